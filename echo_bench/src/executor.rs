@@ -0,0 +1,285 @@
+//! Where the servers and clients run.
+
+use std::fs::File;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use echo_common::Endpoint;
+
+use crate::experiment::{ExecutorKind, Hosts};
+
+/// How often to check whether a command exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Set on SIGINT or SIGTERM, which stop the experiment.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Stop the experiment on SIGINT or SIGTERM, rather than terminate: waits fail from then on,
+/// and the commands still running are killed as they are dropped.
+pub fn handle_signals() -> io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the action only stores to an atomic, which is async-signal-safe
+        unsafe {
+            signal_hook_registry::register(signal, || INTERRUPTED.store(true, Ordering::SeqCst))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Starts the commands of an experiment on their hosts.
+pub trait Executor {
+    /// Start `command` on `host`, with its output (stdout and stderr) in the local file `log`
+    /// once it exits.
+    fn spawn(&self, host: &str, command: &[String], log: &Path)
+        -> anyhow::Result<Box<dyn Process>>;
+}
+
+/// A command started by an executor.
+pub trait Process {
+    /// Ask the command to stop, as SIGINT does.
+    fn interrupt(&mut self) -> anyhow::Result<()>;
+
+    /// Stop the command right away.
+    fn kill(&mut self) -> anyhow::Result<()>;
+
+    /// How the command exited, if it did.
+    fn try_wait(&mut self) -> anyhow::Result<Option<ExitStatus>>;
+}
+
+impl dyn Process + '_ {
+    /// Wait for the command to exit, for up to `timeout` (forever without one), unless the
+    /// experiment is interrupted.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> anyhow::Result<Option<ExitStatus>> {
+        let start = Instant::now();
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(Some(status));
+            }
+            if interrupted() {
+                return Err(anyhow::anyhow!("interrupted"));
+            }
+            if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                return Ok(None);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+pub fn new(kind: ExecutorKind, hosts: &Hosts) -> anyhow::Result<Box<dyn Executor>> {
+    match kind {
+        ExecutorKind::Local => Ok(Box::new(Local::new(hosts)?)),
+    }
+}
+
+/// Runs every command as a child process, whatever its host.
+///
+/// Every child leads a process group of its own, which signals go to: the Python servers fork
+/// workers, which hold on to the listener.
+pub struct Local;
+
+impl Local {
+    /// Fails unless the server host is an address of this machine, which the server could
+    /// listen on; client hosts only name the logs, but those that name another machine are
+    /// warned about.
+    pub fn new(hosts: &Hosts) -> anyhow::Result<Self> {
+        if !is_local(&hosts.server) {
+            return Err(anyhow::anyhow!(
+                "server host {} is not an address of this machine, where the local executor runs \
+                 the server",
+                hosts.server
+            ));
+        }
+        for client in &hosts.clients {
+            if resolves(client) && !is_local(client) {
+                tracing::warn!(
+                    "client host {} is another machine, but the local executor runs its client \
+                     on this one",
+                    client
+                );
+            }
+        }
+        Ok(Local)
+    }
+}
+
+fn resolves(host: &str) -> bool {
+    match Endpoint::new(host, 0) {
+        Endpoint::Unix(_) => true,
+        Endpoint::Tcp(addr) => addr
+            .to_socket_addrs()
+            .is_ok_and(|mut addrs| addrs.next().is_some()),
+    }
+}
+
+/// Whether `host` is a Unix domain socket, or resolves to an address this machine can bind.
+fn is_local(host: &str) -> bool {
+    match Endpoint::new(host, 0) {
+        Endpoint::Unix(_) => true,
+        Endpoint::Tcp(addr) => addr.to_socket_addrs().is_ok_and(|mut addrs| {
+            addrs.any(|addr| addr.ip().is_loopback() || UdpSocket::bind(addr).is_ok())
+        }),
+    }
+}
+
+impl Executor for Local {
+    fn spawn(
+        &self,
+        _host: &str,
+        command: &[String],
+        log: &Path,
+    ) -> anyhow::Result<Box<dyn Process>> {
+        let (program, args) = command.split_first().context("empty command")?;
+        let stdout =
+            File::create(log).with_context(|| format!("failed to create {}", log.display()))?;
+        let stderr = stdout.try_clone()?;
+        let child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(stdout)
+            .stderr(stderr)
+            .process_group(0)
+            .spawn()
+            .with_context(|| format!("failed to start {}", program))?;
+        Ok(Box::new(LocalProcess(child)))
+    }
+}
+
+struct LocalProcess(Child);
+
+impl LocalProcess {
+    /// Send `signal` to the process group of the child.
+    fn signal(&mut self, signal: libc::c_int) -> io::Result<()> {
+        // once reaped, its PID may name another process group
+        if self.0.try_wait()?.is_some() {
+            return Ok(());
+        }
+        // SAFETY: a plain syscall
+        if unsafe { libc::kill(-(self.0.id() as libc::pid_t), signal) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Process for LocalProcess {
+    fn interrupt(&mut self) -> anyhow::Result<()> {
+        self.signal(libc::SIGINT)
+            .context("failed to interrupt child")
+    }
+
+    fn kill(&mut self) -> anyhow::Result<()> {
+        self.signal(libc::SIGKILL).context("failed to kill child")
+    }
+
+    fn try_wait(&mut self) -> anyhow::Result<Option<ExitStatus>> {
+        self.0.try_wait().context("failed to wait for child")
+    }
+}
+
+impl Drop for LocalProcess {
+    fn drop(&mut self) {
+        // a run that failed half-way leaves nothing behind
+        if self.signal(libc::SIGKILL).is_ok() {
+            let _ = self.0.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(server: &str, clients: &[&str]) -> Hosts {
+        Hosts {
+            server: server.to_string(),
+            clients: clients.iter().map(|client| client.to_string()).collect(),
+        }
+    }
+
+    fn log(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("echo_bench-{}-{}.log", name, std::process::id()))
+    }
+
+    #[test]
+    fn local_hosts() {
+        for host in [
+            "127.0.0.1",
+            "[::1]",
+            "localhost",
+            "0.0.0.0",
+            "unix:/tmp/echo.sock",
+        ] {
+            assert!(is_local(host), "{}", host);
+        }
+        // TEST-NET-1, which no machine is assigned
+        assert!(!is_local("192.0.2.1"));
+        assert!(!is_local("no-such-host.invalid"));
+        assert!(!resolves("client0"));
+    }
+
+    #[test]
+    fn rejects_remote_servers() {
+        assert!(Local::new(&hosts("127.0.0.1", &["client0", "localhost"])).is_ok());
+        // warned about only
+        assert!(Local::new(&hosts("127.0.0.1", &["192.0.2.1"])).is_ok());
+        let e = Local::new(&hosts("192.0.2.1", &["client0"])).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "server host 192.0.2.1 is not an address of this machine, where the local executor \
+             runs the server"
+        );
+    }
+
+    #[test]
+    fn logs_the_output() {
+        let log = log("output");
+        let command = ["sh", "-c", "echo out; echo err >&2"].map(String::from);
+        let mut process = Local.spawn("client0", &command, &log).unwrap();
+        let status = process.wait(None).unwrap().unwrap();
+        assert!(status.success());
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "out\nerr\n");
+        std::fs::remove_file(&log).unwrap();
+
+        assert!(Local.spawn("client0", &[], &log).is_err());
+    }
+
+    #[test]
+    fn signals_the_process_group() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let log = log("group");
+        // the trap tells an interrupt from other signals
+        let command = [
+            "sh",
+            "-c",
+            "trap 'exit 3' INT; while :; do sleep 0.01; done",
+        ];
+        let command = command.map(String::from);
+        let mut process = Local.spawn("client0", &command, &log).unwrap();
+        let timeout = Some(Duration::from_millis(100));
+        assert_eq!(process.wait(timeout).unwrap(), None);
+        process.interrupt().unwrap();
+        let status = process.wait(Some(Duration::from_secs(5))).unwrap().unwrap();
+        assert_eq!(status.code(), Some(3));
+
+        let command = ["sleep", "10"].map(String::from);
+        let mut process = Local.spawn("client0", &command, &log).unwrap();
+        process.kill().unwrap();
+        let status = process.wait(Some(Duration::from_secs(5))).unwrap().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        // once reaped, signals are left out
+        process.kill().unwrap();
+        std::fs::remove_file(&log).unwrap();
+    }
+}
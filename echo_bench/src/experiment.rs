@@ -0,0 +1,583 @@
+//! Experiment files: the implementations to compare, and the sizes, client types and hosts to
+//! run them with, in TOML.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{Deserialize, Deserializer};
+
+/// Names the log of the server of a run, in place of a client host.
+pub const SERVER_LOG: &str = "server";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Experiment {
+    /// Results go to `<results>/<name>/<size>/`.
+    pub name: String,
+
+    #[serde(default = "default_results")]
+    pub results: PathBuf,
+
+    #[serde(default)]
+    pub executor: ExecutorKind,
+
+    /// Message sizes, as given to `--message-size` (e.g., `1`, `4KiB` or `1MiB`).
+    pub sizes: Vec<String>,
+
+    /// How many times every run is repeated.
+    #[serde(default = "default_repetitions")]
+    pub repetitions: usize,
+
+    /// The `echo-stats` binary, run over the client logs of every run.
+    #[serde(default = "default_stats")]
+    pub stats: PathBuf,
+
+    /// How long a server has to pass its health probe.
+    #[serde(default = "default_ready_timeout", deserialize_with = "period")]
+    pub ready_timeout: Duration,
+
+    /// How long a server has to exit once interrupted, before it is killed.
+    #[serde(default = "default_stop_timeout", deserialize_with = "period")]
+    pub stop_timeout: Duration,
+
+    /// Port of the statistics of the servers probed with `admin`.
+    #[serde(default = "default_admin_port")]
+    pub admin_port: u16,
+
+    /// Arguments of every server.
+    #[serde(default)]
+    pub server_args: Vec<String>,
+
+    /// Arguments of every client (e.g., `["-d", "30s"]`).
+    #[serde(default)]
+    pub client_args: Vec<String>,
+
+    pub hosts: Hosts,
+
+    #[serde(rename = "implementation")]
+    pub implementations: Vec<Implementation>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExecutorKind {
+    /// Every server and client is a child process, on this machine.
+    #[default]
+    Local,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hosts {
+    /// Where the server runs: the host it listens on, and the clients connect to.
+    #[serde(default = "default_server")]
+    pub server: String,
+
+    /// Where the clients run, one client per entry; the names label their logs.
+    pub clients: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Implementation {
+    pub name: String,
+
+    /// The server command, to which the host, the port and the arguments are appended.
+    pub server: Vec<String>,
+
+    /// The client command, to which the host, the port, `--message-size` and the arguments are
+    /// appended.
+    pub client: Vec<String>,
+
+    pub port: u16,
+
+    #[serde(default)]
+    pub probe: Probe,
+
+    /// The `--client-type` of every run; without any, the flag is left out.
+    #[serde(default)]
+    pub client_types: Vec<String>,
+
+    #[serde(default)]
+    pub server_args: Vec<String>,
+
+    #[serde(default)]
+    pub client_args: Vec<String>,
+
+    /// Variants of the implementation, each run as if it were an implementation of its own.
+    #[serde(default, rename = "variant")]
+    pub variants: Vec<Variant>,
+}
+
+/// How to tell that a server is ready for clients.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Probe {
+    /// Start the server with `--admin-port`, and wait for its statistics to say that it is
+    /// listening (`echo_listening 1`; the Rust servers).
+    #[default]
+    Admin,
+    /// Wait for the port to accept TCP connections.
+    Tcp,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Variant {
+    pub name: String,
+
+    #[serde(default)]
+    pub server_args: Vec<String>,
+
+    #[serde(default)]
+    pub client_args: Vec<String>,
+}
+
+/// A server, with the clients to run against it.
+pub struct Run {
+    /// Names the logs and the statistics: the implementation, variant and client type.
+    pub label: String,
+    pub size: String,
+    pub repetition: usize,
+    pub port: u16,
+    pub probe: Probe,
+    pub server: Vec<String>,
+    pub client: Vec<String>,
+}
+
+impl Experiment {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let experiment: Experiment =
+            toml::from_str(&text).with_context(|| format!("failed to parse {}", path.display()))?;
+        let clients = &experiment.hosts.clients;
+        if clients.is_empty() {
+            return Err(anyhow::anyhow!("{}: no client hosts", path.display()));
+        }
+        // the names of the logs
+        for (i, client) in clients.iter().enumerate() {
+            if client == SERVER_LOG || clients[..i].contains(client) {
+                return Err(anyhow::anyhow!(
+                    "{}: client host {} is listed twice, or named after the server log",
+                    path.display(),
+                    client
+                ));
+            }
+        }
+        for implementation in &experiment.implementations {
+            if implementation.server.is_empty() || implementation.client.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "{}: implementation {} needs a server and a client command",
+                    path.display(),
+                    implementation.name
+                ));
+            }
+        }
+        Ok(experiment)
+    }
+
+    /// Where the logs and statistics of `size` go.
+    pub fn dir(&self, size: &str) -> PathBuf {
+        self.results.join(&self.name).join(size)
+    }
+
+    /// The log of `host` (or of the server, `SERVER_LOG`) in `run`:
+    /// `<label>_<host>_<repetition>.log`.
+    pub fn log(&self, run: &Run, host: &str) -> PathBuf {
+        self.dir(&run.size)
+            .join(format!("{}_{}_{}.log", run.label, host, run.repetition))
+    }
+
+    /// The statistics of `run`: `<label>_<repetition>.stats`.
+    pub fn stats_file(&self, run: &Run) -> PathBuf {
+        self.dir(&run.size)
+            .join(format!("{}_{}.stats", run.label, run.repetition))
+    }
+
+    /// Every run, by implementation, variant, client type, size and repetition.
+    pub fn runs(&self) -> Vec<Run> {
+        let mut runs = Vec::new();
+        for implementation in &self.implementations {
+            let variants: Vec<_> = if implementation.variants.is_empty() {
+                vec![None]
+            } else {
+                implementation.variants.iter().map(Some).collect()
+            };
+            let client_types: Vec<_> = if implementation.client_types.is_empty() {
+                vec![None]
+            } else {
+                implementation.client_types.iter().map(Some).collect()
+            };
+            for variant in &variants {
+                for client_type in &client_types {
+                    for size in &self.sizes {
+                        for repetition in 1..=self.repetitions {
+                            runs.push(self.run(
+                                implementation,
+                                *variant,
+                                *client_type,
+                                size,
+                                repetition,
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        runs
+    }
+
+    fn run(
+        &self,
+        implementation: &Implementation,
+        variant: Option<&Variant>,
+        client_type: Option<&String>,
+        size: &str,
+        repetition: usize,
+    ) -> Run {
+        let mut label = implementation.name.clone();
+        for part in [variant.map(|v| &v.name), client_type]
+            .into_iter()
+            .flatten()
+        {
+            label.push('_');
+            label.push_str(part);
+        }
+
+        let host = &self.hosts.server;
+        let port = implementation.port.to_string();
+
+        let mut server = implementation.server.clone();
+        server.extend([host.clone(), port.clone()]);
+        if implementation.probe == Probe::Admin {
+            server.extend(["--admin-port".to_string(), self.admin_port.to_string()]);
+        }
+        server.extend(self.server_args.iter().cloned());
+        server.extend(implementation.server_args.iter().cloned());
+        server.extend(
+            variant
+                .into_iter()
+                .flat_map(|v| v.server_args.iter().cloned()),
+        );
+
+        let mut client = implementation.client.clone();
+        client.extend([host.clone(), port]);
+        client.extend(["--message-size".to_string(), size.to_string()]);
+        if let Some(client_type) = client_type {
+            client.extend(["--client-type".to_string(), client_type.clone()]);
+        }
+        client.extend(self.client_args.iter().cloned());
+        client.extend(implementation.client_args.iter().cloned());
+        client.extend(
+            variant
+                .into_iter()
+                .flat_map(|v| v.client_args.iter().cloned()),
+        );
+
+        Run {
+            label,
+            size: size.to_string(),
+            repetition,
+            port: implementation.port,
+            probe: implementation.probe,
+            server,
+            client,
+        }
+    }
+}
+
+fn period<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    echo_common::cli::period_parser(&s).map_err(serde::de::Error::custom)
+}
+
+fn default_results() -> PathBuf {
+    PathBuf::from("results")
+}
+
+fn default_repetitions() -> usize {
+    1
+}
+
+fn default_stats() -> PathBuf {
+    PathBuf::from("target/release/echo-stats")
+}
+
+fn default_ready_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_stop_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_admin_port() -> u16 {
+    9199
+}
+
+fn default_server() -> String {
+    // the tonic client takes no bracketed IPv6 addresses
+    "127.0.0.1".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load `text` from a file of its own.
+    fn load(name: &str, text: &str) -> anyhow::Result<Experiment> {
+        let path =
+            std::env::temp_dir().join(format!("echo_bench-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let experiment = Experiment::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        experiment
+    }
+
+    const MINIMAL: &str = r#"
+        name = "test"
+        sizes = ["1"]
+
+        [hosts]
+        clients = ["client0"]
+
+        [[implementation]]
+        name = "rust_sync"
+        server = ["rust_sync_server"]
+        client = ["rust_sync_client"]
+        port = 9094
+    "#;
+
+    #[test]
+    fn defaults() {
+        let experiment = load("defaults", MINIMAL).unwrap();
+        assert_eq!(experiment.executor, ExecutorKind::Local);
+        assert_eq!(experiment.repetitions, 1);
+        assert_eq!(experiment.ready_timeout, Duration::from_secs(10));
+        assert_eq!(experiment.hosts.server, "127.0.0.1");
+        assert_eq!(experiment.dir("1"), Path::new("results/test/1"));
+
+        let runs = experiment.runs();
+        assert_eq!(runs.len(), 1);
+        let run = &runs[0];
+        assert_eq!(run.label, "rust_sync");
+        assert_eq!(run.probe, Probe::Admin);
+        assert_eq!(
+            run.server,
+            [
+                "rust_sync_server",
+                "127.0.0.1",
+                "9094",
+                "--admin-port",
+                "9199"
+            ]
+        );
+        assert_eq!(
+            run.client,
+            [
+                "rust_sync_client",
+                "127.0.0.1",
+                "9094",
+                "--message-size",
+                "1"
+            ]
+        );
+        assert_eq!(
+            experiment.log(run, SERVER_LOG),
+            Path::new("results/test/1/rust_sync_server_1.log")
+        );
+        assert_eq!(
+            experiment.stats_file(run),
+            Path::new("results/test/1/rust_sync_1.stats")
+        );
+    }
+
+    #[test]
+    fn runs() {
+        let experiment = load(
+            "runs",
+            r#"
+            name = "test"
+            sizes = ["1", "4KiB"]
+            repetitions = 2
+            ready_timeout = "500ms"
+            server_args = ["-j", "1"]
+            client_args = ["-d", "1s"]
+
+            [hosts]
+            server = "::1"
+            clients = ["a", "b"]
+
+            [[implementation]]
+            name = "python"
+            server = ["python3", "server.py"]
+            client = ["python3", "client.py"]
+            port = 9090
+            probe = "tcp"
+
+            [[implementation]]
+            name = "rust_async"
+            server = ["server"]
+            client = ["client"]
+            port = 9095
+            client_types = ["closed", "bursty"]
+            client_args = ["-p", "4"]
+
+            [[implementation.variant]]
+            name = "tls"
+            server_args = ["--tls"]
+            client_args = ["--tls"]
+
+            [[implementation.variant]]
+            name = "plain"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(experiment.ready_timeout, Duration::from_millis(500));
+
+        let runs = experiment.runs();
+        // 2 sizes and 2 repetitions of python, and of 2 variants and 2 client types of
+        // rust_async
+        assert_eq!(runs.len(), 4 + 16);
+        let labels = runs
+            .iter()
+            .map(|run| format!("{} {} #{}", run.label, run.size, run.repetition))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels[..6],
+            [
+                "python 1 #1",
+                "python 1 #2",
+                "python 4KiB #1",
+                "python 4KiB #2",
+                "rust_async_tls_closed 1 #1",
+                "rust_async_tls_closed 1 #2",
+            ]
+        );
+        assert_eq!(labels[19], "rust_async_plain_bursty 4KiB #2");
+
+        // no admin port to probe
+        assert_eq!(
+            runs[0].server,
+            ["python3", "server.py", "::1", "9090", "-j", "1"]
+        );
+        // the experiment's arguments, then the implementation's, then the variant's
+        assert_eq!(
+            runs[4].server,
+            [
+                "server",
+                "::1",
+                "9095",
+                "--admin-port",
+                "9199",
+                "-j",
+                "1",
+                "--tls"
+            ]
+        );
+        assert_eq!(
+            runs[4].client,
+            [
+                "client",
+                "::1",
+                "9095",
+                "--message-size",
+                "1",
+                "--client-type",
+                "closed",
+                "-d",
+                "1s",
+                "-p",
+                "4",
+                "--tls"
+            ]
+        );
+        assert_eq!(
+            experiment.log(&runs[4], "b"),
+            Path::new("results/test/1/rust_async_tls_closed_b_1.log")
+        );
+    }
+
+    #[test]
+    fn invalid_experiments() {
+        let invalid = [
+            (MINIMAL.replace(r#"["client0"]"#, "[]"), "no client hosts"),
+            (
+                MINIMAL.replace(r#"["client0"]"#, r#"["a", "a"]"#),
+                "client host a is listed twice",
+            ),
+            (
+                MINIMAL.replace(r#"["client0"]"#, r#"["server"]"#),
+                "client host server is listed twice, or named after the server log",
+            ),
+            (
+                MINIMAL.replace(r#"["rust_sync_client"]"#, "[]"),
+                "implementation rust_sync needs a server and a client command",
+            ),
+        ];
+        for (text, error) in invalid {
+            let e = load("invalid", &text).err().unwrap();
+            assert!(e.to_string().contains(error), "{}", e);
+        }
+
+        for text in [
+            // unknown fields, which would be left out silently
+            MINIMAL.replace("port = 9094", "port = 9094\nclient_type = [\"closed\"]"),
+            MINIMAL.replace("sizes", "size"),
+            MINIMAL.replace("name = \"test\"", "name = \"test\"\nexecutor = \"ssh\""),
+            MINIMAL.replace(
+                "sizes = [\"1\"]",
+                "sizes = [\"1\"]\nstop_timeout = \"soon\"",
+            ),
+            MINIMAL.replace("port = 9094", "port = 9094\nprobe = \"http\""),
+        ] {
+            let e = load("invalid", &text).err().unwrap();
+            assert!(format!("{:#}", e).contains("failed to parse"), "{:#}", e);
+        }
+    }
+
+    /// The experiment of the README, which also covers the QUIC and io_uring implementations
+    /// and their modes.
+    #[test]
+    fn single() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../scripts/single.toml");
+        let experiment = Experiment::load(&path).unwrap();
+        let runs = experiment
+            .runs()
+            .into_iter()
+            .filter(|run| run.size == "1")
+            .collect::<Vec<_>>();
+        let labels = runs
+            .iter()
+            .map(|run| run.label.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            [
+                "python",
+                "python_grpc",
+                "python_async_grpc",
+                "rust_sync",
+                "rust_async_closed",
+                "rust_async_bursty",
+                "rust_tonic_closed",
+                "rust_tonic_bursty",
+                "rust_quic_per-request",
+                "rust_quic_long-lived",
+                "rust_uring_plain",
+                "rust_uring_registered-buffers",
+                "rust_uring_multishot",
+            ]
+        );
+
+        let quic = &runs[9];
+        assert!(quic
+            .client
+            .ends_with(&["--stream-mode".into(), "long-lived".into()]));
+        let uring = &runs[12];
+        assert!(uring.server.ends_with(&["--multishot".into()]));
+        assert!(uring.client.ends_with(&["--multishot".into()]));
+    }
+}
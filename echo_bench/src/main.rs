@@ -0,0 +1,202 @@
+//! `echo-bench`: runs the experiment of a TOML file, starting every server, waiting for it to be
+//! ready, running the clients against it, and computing the statistics of their logs.
+
+use std::fs::File;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use clap::Parser;
+
+mod executor;
+mod experiment;
+mod probe;
+
+use executor::{Executor, Process};
+use experiment::{Experiment, Run, SERVER_LOG};
+
+/// How often to probe a server that is not ready yet.
+const PROBE_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Parser)]
+#[command(author, version, about, long_about=None)]
+struct Args {
+    /// The experiment file (TOML).
+    experiment: PathBuf,
+
+    /// Print the commands of every run, without running them.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    // not from RUST_LOG, which the servers and clients inherit: the clients log to stdout,
+    // along with the output the statistics are computed from
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_writer(std::io::stderr)
+        .init();
+    let args = Args::parse();
+    let experiment = Experiment::load(&args.experiment)?;
+    let runs = experiment.runs();
+
+    if args.dry_run {
+        for run in &runs {
+            println!("{} {} #{}", run.label, run.size, run.repetition);
+            println!("  server: {}", run.server.join(" "));
+            println!("  client: {}", run.client.join(" "));
+        }
+        return Ok(());
+    }
+
+    executor::handle_signals().context("failed to handle signals")?;
+    let executor = executor::new(experiment.executor, &experiment.hosts)?;
+    let mut failed = 0;
+    for (i, run) in runs.iter().enumerate() {
+        tracing::info!(
+            "[{}/{}] {} {} #{}",
+            i + 1,
+            runs.len(),
+            run.label,
+            run.size,
+            run.repetition
+        );
+        if let Err(e) = bench(&experiment, executor.as_ref(), run) {
+            if executor::interrupted() {
+                return Err(anyhow::anyhow!(
+                    "interrupted during {} {} #{}",
+                    run.label,
+                    run.size,
+                    run.repetition
+                ));
+            }
+            tracing::error!(
+                "{} {} #{} failed: {:#}",
+                run.label,
+                run.size,
+                run.repetition,
+                e
+            );
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        return Err(anyhow::anyhow!("{} of {} runs failed", failed, runs.len()));
+    }
+    Ok(())
+}
+
+/// Start the server of `run`, run its clients once it is ready, stop it, and compute the
+/// statistics of the client logs.
+fn bench(experiment: &Experiment, executor: &dyn Executor, run: &Run) -> anyhow::Result<()> {
+    let dir = experiment.dir(&run.size);
+    std::fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+
+    let host = &experiment.hosts.server;
+    // or the probe would pass before the server is up
+    if probe::ready(run.probe, host, run.port, experiment.admin_port) {
+        return Err(anyhow::anyhow!(
+            "a server is already running on {} (port {}, admin port {})",
+            host,
+            run.port,
+            experiment.admin_port
+        ));
+    }
+    let mut server = executor.spawn(host, &run.server, &experiment.log(run, SERVER_LOG))?;
+    wait_ready(experiment, run, server.as_mut())?;
+
+    let mut clients = Vec::new();
+    for client in &experiment.hosts.clients {
+        let log = experiment.log(run, client);
+        clients.push((
+            client,
+            log.clone(),
+            executor.spawn(client, &run.client, &log)?,
+        ));
+    }
+    let mut logs = Vec::new();
+    let mut errors = Vec::new();
+    for (client, log, process) in &mut clients {
+        let status = process.wait(None)?.expect("waits without a timeout");
+        if status.success() {
+            logs.push(log.clone());
+        } else {
+            errors.push(format!("client on {} exited with {}", client, status));
+        }
+    }
+
+    stop(experiment, server.as_mut())?;
+    if !errors.is_empty() {
+        return Err(anyhow::anyhow!("{}", errors.join(", ")));
+    }
+    stats(experiment, run, &logs)
+}
+
+/// Wait for the server to pass its health probe.
+fn wait_ready(experiment: &Experiment, run: &Run, server: &mut dyn Process) -> anyhow::Result<()> {
+    let host = &experiment.hosts.server;
+    let start = Instant::now();
+    while !probe::ready(run.probe, host, run.port, experiment.admin_port) {
+        if let Some(status) = server.try_wait()? {
+            return Err(anyhow::anyhow!(
+                "server exited with {} before it was ready",
+                status
+            ));
+        }
+        if executor::interrupted() {
+            return Err(anyhow::anyhow!("interrupted"));
+        }
+        if start.elapsed() >= experiment.ready_timeout {
+            return Err(anyhow::anyhow!(
+                "server not ready after {:?}",
+                experiment.ready_timeout
+            ));
+        }
+        std::thread::sleep(PROBE_INTERVAL);
+    }
+    tracing::info!("server ready after {:?}", start.elapsed());
+    Ok(())
+}
+
+/// Interrupt the server, and wait for it to exit (or kill it after the stop timeout), so that
+/// the next one can take the port.
+fn stop(experiment: &Experiment, server: &mut dyn Process) -> anyhow::Result<()> {
+    server.interrupt()?;
+    match server.wait(Some(experiment.stop_timeout))? {
+        // a server that could not drain every connection fails, which spoils no statistics
+        Some(status) if !status.success() => tracing::warn!("server exited with {}", status),
+        Some(_) => {}
+        None => {
+            tracing::warn!(
+                "server still running after {:?}, killing it",
+                experiment.stop_timeout
+            );
+            server.kill()?;
+            server.wait(None)?;
+        }
+    }
+    Ok(())
+}
+
+/// Run `echo-stats` over the client logs of `run`.
+fn stats(experiment: &Experiment, run: &Run, logs: &[PathBuf]) -> anyhow::Result<()> {
+    let path = experiment.stats_file(run);
+    let file =
+        File::create(&path).with_context(|| format!("failed to create {}", path.display()))?;
+    let status = Command::new(&experiment.stats)
+        .args(logs)
+        .stdout(file)
+        .status()
+        .with_context(|| format!("failed to run {}", experiment.stats.display()))?;
+    if !status.success() {
+        return Err(anyhow::anyhow!(
+            "{} exited with {}",
+            experiment.stats.display(),
+            status
+        ));
+    }
+    tracing::info!("statistics in {}", path.display());
+    Ok(())
+}
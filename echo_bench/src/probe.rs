@@ -0,0 +1,122 @@
+//! Health probes, to start the clients once the server is ready rather than after a fixed
+//! delay.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use echo_common::Endpoint;
+
+use crate::experiment::Probe;
+
+/// How long a single attempt may take.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Whether the server on `host` is ready: its statistics on `admin_port` say it is listening,
+/// or it accepts connections on `port`.
+pub fn ready(probe: Probe, host: &str, port: u16, admin_port: u16) -> bool {
+    match probe {
+        Probe::Admin => {
+            // as `ServerArgs::admin_addr`
+            let host = match Endpoint::new(host, port) {
+                Endpoint::Tcp(_) => host,
+                Endpoint::Unix(_) => "localhost",
+            };
+            scrape(&format!("{}:{}", host, admin_port)).unwrap_or(false)
+        }
+        Probe::Tcp => match Endpoint::new(host, port) {
+            Endpoint::Tcp(addr) => connect(&addr).is_ok(),
+            Endpoint::Unix(path) => UnixStream::connect(path).is_ok(),
+        },
+    }
+}
+
+/// Whether `GET /metrics` on `addr` says the server is listening: its statistics are served
+/// before it binds.
+fn scrape(addr: &str) -> io::Result<bool> {
+    let mut stream = connect(addr)?;
+    stream.set_read_timeout(Some(ATTEMPT_TIMEOUT))?;
+    stream.set_write_timeout(Some(ATTEMPT_TIMEOUT))?;
+    write!(
+        stream,
+        "GET /metrics HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        addr
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response.starts_with("HTTP/1.1 200")
+        && response.lines().any(|line| line == "echo_listening 1"))
+}
+
+fn connect(addr: &str) -> io::Result<TcpStream> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no address to connect to"))?;
+    TcpStream::connect_timeout(&addr, ATTEMPT_TIMEOUT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    /// Answer a single scrape with `metrics`.
+    fn serve_metrics(listener: TcpListener, metrics: &'static str) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let n = stream.read(&mut request).unwrap();
+            assert!(request[..n].starts_with(b"GET /metrics HTTP/1.1\r\n"));
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                metrics.len(),
+                metrics
+            )
+            .unwrap();
+        })
+    }
+
+    #[test]
+    fn admin_probes() {
+        for (metrics, listening) in [
+            ("echo_connections 0\necho_listening 1\n", true),
+            ("echo_listening 0\n", false),
+        ] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let admin_port = listener.local_addr().unwrap().port();
+            let server = serve_metrics(listener, metrics);
+            assert_eq!(ready(Probe::Admin, "127.0.0.1", 1, admin_port), listening);
+            server.join().unwrap();
+        }
+
+        // nothing to scrape
+        let admin_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        assert!(!ready(Probe::Admin, "127.0.0.1", 1, admin_port));
+    }
+
+    #[test]
+    fn tcp_probes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(ready(Probe::Tcp, "127.0.0.1", port, 1));
+        drop(listener);
+        assert!(!ready(Probe::Tcp, "127.0.0.1", port, 1));
+
+        let path = std::env::temp_dir().join(format!("echo_bench-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let host = format!("unix:{}", path.display());
+        assert!(!ready(Probe::Tcp, &host, 0, 1));
+        let _listener = UnixListener::bind(&path).unwrap();
+        assert!(ready(Probe::Tcp, &host, 0, 1));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,416 @@
+//! The CLI contract from the README, shared by every client and server.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{Local, NaiveDateTime, NaiveTime};
+use clap::Parser;
+
+use crate::endpoint::Endpoint;
+use crate::protocol::{Hello, PayloadMode, MAX_MESSAGE_SIZE};
+use crate::sizes::{size_dist_parser, SizeDist};
+use crate::sockopt::SocketArgs;
+
+pub fn size_parser(s: &str) -> anyhow::Result<usize> {
+    parse_size::Config::new()
+        .with_binary()
+        .parse_size(s)
+        .map(|x| x as usize)
+        .map_err(|e| anyhow::anyhow!("failed to parse {}: {:?}", s, e))
+}
+
+/// Parse a message size, up to the largest the servers accept.
+pub fn message_size_parser(s: &str) -> anyhow::Result<usize> {
+    let size = size_parser(s)?;
+    if size == 0 || size > MAX_MESSAGE_SIZE {
+        return Err(anyhow::anyhow!(
+            "message size must be between 1 and {} bytes: {}",
+            MAX_MESSAGE_SIZE,
+            s
+        ));
+    }
+    Ok(size)
+}
+
+pub fn time_parser(s: &str) -> anyhow::Result<NaiveDateTime> {
+    let today = Local::now().date_naive();
+    let time =
+        NaiveTime::parse_from_str(s, "%H:%M:%S").context("failed to parse start timestamp")?;
+    Ok(NaiveDateTime::new(today, time))
+}
+
+pub fn duration_parser(s: &str) -> anyhow::Result<u64> {
+    humantime::parse_duration(s)
+        .map(|s| s.as_secs())
+        .context("failed to parse duration")
+}
+
+/// Parse a duration with sub-second precision (e.g., `10ms` or `250us`).
+pub fn period_parser(s: &str) -> anyhow::Result<Duration> {
+    let period = humantime::parse_duration(s).context("failed to parse period")?;
+    if period.is_zero() {
+        return Err(anyhow::anyhow!("period must be positive: {}", s));
+    }
+    Ok(period)
+}
+
+/// Parse a request rate, in requests per second (e.g., `50k/s`, `1M/s` or `2500`).
+pub fn rate_parser(s: &str) -> anyhow::Result<f64> {
+    let number = s.strip_suffix("/s").unwrap_or(s);
+    let (number, multiplier) = match number.char_indices().last() {
+        Some((idx, 'k')) | Some((idx, 'K')) => (&number[..idx], 1e3),
+        Some((idx, 'M')) => (&number[..idx], 1e6),
+        Some((idx, 'G')) => (&number[..idx], 1e9),
+        _ => (number, 1.0),
+    };
+    let rate = number
+        .parse::<f64>()
+        .map(|x| x * multiplier)
+        .map_err(|e| anyhow::anyhow!("failed to parse rate {}: {:?}", s, e))?;
+    if !rate.is_finite() || rate <= 0.0 {
+        return Err(anyhow::anyhow!("rate must be positive: {}", s));
+    }
+    Ok(rate)
+}
+
+/// Parse the command line into `P`, using `default_port` for the positional `port`.
+///
+/// Every implementation listens on its own port, so the default cannot live in the shared
+/// argument structs.
+pub fn parse<P: Parser>(default_port: &'static str) -> P {
+    let matches = P::command()
+        .mut_arg("port", |arg| {
+            arg.required(false).default_value(default_port)
+        })
+        .get_matches();
+    P::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct ServerArgs {
+    #[arg(default_value = "[::1]")]
+    pub host: String,
+
+    #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+    pub port: u16,
+
+    #[arg(short = 'j', long)]
+    pub n_cores: Option<usize>,
+
+    /// Backlog of the listening socket (TCP or Unix domain socket), which the kernel caps to
+    /// `net.core.somaxconn`.
+    #[arg(short, long, default_value_t = 1024, value_parser = clap::value_parser!(i32).range(1..))]
+    pub backlog: i32,
+
+    /// Serve the server statistics (Prometheus text format) on this port.
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub admin_port: Option<u16>,
+
+    /// On SIGINT/SIGTERM, how long to wait for in-flight echoes before giving up.
+    #[arg(long, default_value = "5s", value_parser = period_parser)]
+    pub drain_timeout: Duration,
+
+    #[command(flatten)]
+    pub tls: TlsArgs,
+
+    #[command(flatten)]
+    pub socket: SocketArgs,
+}
+
+impl ServerArgs {
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// Where to listen: `addr()`, or a Unix domain socket for a `unix:/path` host.
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint::new(&self.host, self.port)
+    }
+
+    /// The admin port is on localhost when the server listens on a Unix domain socket.
+    pub fn admin_addr(&self) -> Option<String> {
+        let host = match self.endpoint() {
+            Endpoint::Tcp(_) => self.host.as_str(),
+            Endpoint::Unix(_) => "localhost",
+        };
+        self.admin_port.map(|port| format!("{}:{}", host, port))
+    }
+    pub fn parallelism(&self) -> usize {
+        self.n_cores.unwrap_or_else(num_cpus::get)
+    }
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct ClientArgs {
+    #[arg(default_value = "[::1]")]
+    pub host: String,
+
+    #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+    pub port: u16,
+
+    #[arg(short = 'j', long)]
+    pub n_cores: Option<usize>,
+
+    #[arg(short, long, default_value = "60s", value_parser = duration_parser)]
+    pub duration: u64,
+
+    #[arg(short, long, default_value = "10s", value_parser = duration_parser)]
+    pub warmup: u64,
+
+    #[arg(short, long, default_value_t = 1, value_parser = message_size_parser)]
+    pub message_size: usize,
+
+    /// Draw the size of every message from a distribution: `fixed:SIZE`, `uniform:MIN,MAX`,
+    /// `normal:MEAN,STDDEV`, `lognormal:MU,SIGMA`, `zipf:N,S` or `file:PATH`.
+    #[arg(long, value_parser = size_dist_parser, conflicts_with = "message_size")]
+    pub size_dist: Option<SizeDist>,
+
+    /// Contents of the messages, which determine how the replies are verified.
+    #[arg(long, value_enum, default_value_t = PayloadMode::Constant)]
+    pub payload: PayloadMode,
+
+    /// Seed of the `random` payload.
+    #[arg(long, default_value_t = 0)]
+    pub payload_seed: u64,
+
+    #[arg(short, long, value_parser = time_parser)]
+    pub start: Option<NaiveDateTime>,
+
+    /// Correct for coordinated omission, assuming requests are meant to go out at this interval
+    /// (not with the open-loop client, which does not omit any).
+    #[arg(long, value_parser = period_parser)]
+    pub expected_interval: Option<Duration>,
+
+    /// Print every latency sample, besides the histogram summary (for `awk/cdf.awk`).
+    #[arg(long)]
+    pub print_samples: bool,
+
+    /// Also print the throughput and latency percentiles of every interval of this length.
+    #[arg(long, value_parser = period_parser)]
+    pub report_interval: Option<Duration>,
+
+    #[arg(long, default_value = "text")]
+    pub output_format: OutputFormat,
+
+    #[command(flatten)]
+    pub tls: TlsArgs,
+
+    #[command(flatten)]
+    pub socket: SocketArgs,
+}
+
+impl ClientArgs {
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// Where to connect: `addr()`, or a Unix domain socket for a `unix:/path` host.
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint::new(&self.host, self.port)
+    }
+
+    pub fn parallelism(&self) -> usize {
+        self.n_cores.unwrap_or_else(num_cpus::get)
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(self.duration)
+    }
+
+    pub fn warmup(&self) -> Duration {
+        Duration::from_secs(self.warmup)
+    }
+
+    /// Sizes of the messages: `--size-dist`, or always `--message-size`.
+    pub fn size_dist(&self) -> SizeDist {
+        self.size_dist
+            .clone()
+            .unwrap_or(SizeDist::Fixed(self.message_size))
+    }
+
+    /// Handshake of the raw-TCP clients: messages are framed with `--size-dist`.
+    pub fn hello(&self) -> Hello {
+        let hello = match &self.size_dist {
+            Some(dist) => Hello::framed(dist.max()),
+            None => Hello::new(self.message_size),
+        };
+        hello.with_payload(self.payload)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// The line-oriented format from the README.
+    Text,
+    /// One JSON object per line, with one record per sample.
+    Jsonl,
+}
+
+/// TLS options of the raw-TCP and gRPC implementations.
+#[derive(clap::Args, Clone, Debug)]
+pub struct TlsArgs {
+    /// Encrypt connections with TLS, with certificates issued by the CA in `--tls-dir`.
+    #[arg(long)]
+    pub tls: bool,
+
+    /// Also authenticate clients with certificates issued by the same CA (implies `--tls`).
+    #[arg(long)]
+    pub mtls: bool,
+
+    /// Where the CA is kept: it is generated on first use, and other machines need a copy.
+    #[arg(long, default_value = "/tmp/echo_tls")]
+    pub tls_dir: PathBuf,
+}
+
+impl TlsArgs {
+    pub fn enabled(&self) -> bool {
+        self.tls || self.mtls
+    }
+
+    /// For the transports without TLS: `--tls and --mtls are not supported <why>`.
+    pub fn reject(&self, why: &str) -> anyhow::Result<()> {
+        if self.enabled() {
+            return Err(anyhow::anyhow!(
+                "--tls and --mtls are not supported {}",
+                why
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Options of the UDP clients.
+#[derive(clap::Args, Clone, Debug)]
+pub struct DatagramArgs {
+    /// Declare a message lost when its reply does not come back within this long.
+    #[arg(long, default_value = "1s", value_parser = period_parser)]
+    pub loss_timeout: Duration,
+}
+
+/// Load patterns offered by the async clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ClientType {
+    /// All requests are pushed out at the same time, over a single connection.
+    Bursty,
+    /// Bursts of `--burst-size` requests are pushed out every `--burst-period`, over a single
+    /// connection.
+    ControlledBursty,
+    /// Each worker awaits on its own request before issuing the next one.
+    Closed,
+    /// Requests are issued at `--rate`, with Poisson arrivals, regardless of the replies.
+    Open,
+    /// Each worker keeps `--pipeline-depth` requests outstanding on its connection.
+    Pipelined,
+}
+
+/// Load generation options of the async clients.
+#[derive(clap::Args, Clone, Debug)]
+pub struct LoadArgs {
+    #[arg(short, long)]
+    pub client_type: ClientType,
+
+    /// Target request rate of the open-loop client, across all workers (e.g., `50k/s`).
+    ///
+    /// Its latencies are measured from the scheduled send times, which leaves nothing for
+    /// `--expected-interval` to correct.
+    #[arg(short, long, value_parser = rate_parser, required_if_eq("client_type", "open"))]
+    pub rate: Option<f64>,
+
+    /// Requests per burst of the controlled bursty client (default: one per core).
+    #[arg(long)]
+    pub burst_size: Option<usize>,
+
+    /// Interval between the start of consecutive bursts of the controlled bursty client.
+    #[arg(long, value_parser = period_parser, required_if_eq("client_type", "controlled-bursty"))]
+    pub burst_period: Option<Duration>,
+
+    /// Outstanding requests per connection of the pipelined client.
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        required_if_eq("client_type", "pipelined")
+    )]
+    pub pipeline_depth: Option<u32>,
+}
+
+impl LoadArgs {
+    /// Reject the options that do not apply to the client type: `--expected-interval` with the
+    /// open-loop client (clap cannot scope a conflict to a value of `--client-type`).
+    pub fn check(&self, common: &ClientArgs) -> anyhow::Result<()> {
+        if self.client_type == ClientType::Open && common.expected_interval.is_some() {
+            return Err(anyhow::anyhow!(
+                "--expected-interval is not supported by open-loop clients, which do not omit \
+                 any request"
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    #[derive(Parser)]
+    struct Args {
+        #[command(flatten)]
+        common: ClientArgs,
+
+        #[command(flatten)]
+        load: LoadArgs,
+    }
+
+    /// As `parse`, but with the port given.
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        let matches = Args::command()
+            .mut_arg("port", |arg| arg.required(false))
+            .try_get_matches_from(["client", "127.0.0.1", "9095"].iter().chain(args))?;
+        Args::from_arg_matches(&matches)
+    }
+
+    #[test]
+    fn rates() {
+        for (s, rate) in [
+            ("2500", 2500.0),
+            ("50k/s", 50e3),
+            ("50K/s", 50e3),
+            ("1.5M/s", 1.5e6),
+            ("2G", 2e9),
+            ("0.5/s", 0.5),
+        ] {
+            assert_eq!(rate_parser(s).unwrap(), rate, "{}", s);
+        }
+        for s in [
+            "", "/s", "k/s", "fast", "10m/s", "0", "-1k/s", "inf", "NaN/s",
+        ] {
+            assert!(rate_parser(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn expected_interval_conflicts_with_open_loop_only() {
+        let args = parse(&["-c", "open", "-r", "1k/s", "--expected-interval", "1ms"]).unwrap();
+        let e = args.load.check(&args.common).unwrap_err().to_string();
+        assert!(
+            e.starts_with("--expected-interval is not supported"),
+            "{}",
+            e
+        );
+
+        // a leftover `--rate` does not matter to the other client types
+        for client_type in ["closed", "bursty"] {
+            let args = parse(&[
+                "-c",
+                client_type,
+                "-r",
+                "1k/s",
+                "--expected-interval",
+                "1ms",
+            ])
+            .unwrap();
+            assert!(args.load.check(&args.common).is_ok());
+        }
+    }
+}
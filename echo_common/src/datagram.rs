@@ -0,0 +1,350 @@
+//! The UDP echo protocol, and the accounting of lost, late, reordered and duplicated replies.
+//!
+//! There is no handshake: every datagram carries the sequence number of the message on its
+//! socket (8 bytes, big-endian), followed by the payload (see [`crate::payload`]), and the
+//! server echoes datagrams back as they are.
+//!
+//! A message whose reply does not come back within the loss timeout is declared lost; a reply
+//! that comes back after that is counted as late, and is not part of the latency samples.
+
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::endpoint::Endpoint;
+use crate::payload::Message;
+
+/// Length of the sequence number at the start of every datagram.
+pub const SEQUENCE_LEN: usize = 8;
+
+/// Largest UDP payload over IPv4.
+pub const MAX_DATAGRAM_LEN: usize = 65_507;
+
+/// Largest message, once the sequence number is in.
+pub const MAX_MESSAGE_SIZE: usize = MAX_DATAGRAM_LEN - SEQUENCE_LEN;
+
+/// Payload bytes of a datagram of `len` bytes, once the sequence number is out.
+pub fn payload_len(len: usize) -> usize {
+    len.saturating_sub(SEQUENCE_LEN)
+}
+
+/// The datagram carrying `message`.
+pub fn encode(mut message: Message) -> Vec<u8> {
+    let mut datagram = vec![0; SEQUENCE_LEN + message.size()];
+    datagram[..SEQUENCE_LEN].copy_from_slice(&message.sequence().to_be_bytes());
+    message.fill(&mut datagram[SEQUENCE_LEN..]);
+    datagram
+}
+
+/// Address of a UDP server; there is no UDP over Unix domain sockets here.
+pub fn resolve(endpoint: &Endpoint) -> anyhow::Result<SocketAddr> {
+    match endpoint {
+        Endpoint::Tcp(addr) => addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("failed to resolve {}", addr)),
+        Endpoint::Unix(_) => Err(anyhow::anyhow!(
+            "UDP does not run over Unix domain sockets: {}",
+            endpoint
+        )),
+    }
+}
+
+/// Address to bind a client socket to in order to reach `server`.
+pub fn local_addr(server: SocketAddr) -> SocketAddr {
+    match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+/// Fail unless every message fits in a datagram.
+pub fn check_size(max_size: usize) -> anyhow::Result<()> {
+    if max_size > MAX_MESSAGE_SIZE {
+        return Err(anyhow::anyhow!(
+            "messages of {} bytes do not fit in a datagram (at most {})",
+            max_size,
+            MAX_MESSAGE_SIZE
+        ));
+    }
+    Ok(())
+}
+
+/// Delivery counters of a client.
+#[derive(Clone, Debug, Default)]
+pub struct DatagramStats {
+    pub sent: u64,
+    /// Replies received in time.
+    pub received: u64,
+    /// Messages without a reply within the loss timeout.
+    pub lost: u64,
+    /// Replies received after their message was declared lost.
+    pub late: u64,
+    /// Replies received after the reply to a later message.
+    pub reordered: u64,
+    /// Replies received more than once.
+    pub duplicates: u64,
+}
+
+impl DatagramStats {
+    pub fn merge(&mut self, other: &DatagramStats) {
+        self.sent += other.sent;
+        self.received += other.received;
+        self.lost += other.lost;
+        self.late += other.late;
+        self.reordered += other.reordered;
+        self.duplicates += other.duplicates;
+    }
+
+    /// Fraction of the messages sent that were lost.
+    pub fn loss_rate(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        self.lost as f64 / self.sent as f64
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Outstanding,
+    Received,
+    Lost,
+    /// Lost, then received.
+    Late,
+}
+
+/// Tracks the messages sent on one socket until they are answered or lost. `T` is kept along
+/// with each outstanding message, and dropped once it is answered or lost.
+pub struct Tracker<T> {
+    timeout: Duration,
+    /// State of every message sent, by sequence number.
+    states: Vec<State>,
+    /// Outstanding messages, with their send instant, by sequence number. Messages are sent
+    /// in order, so the first one is the first to be lost.
+    outstanding: BTreeMap<u64, (Instant, Message, T)>,
+    highest_received: Option<u64>,
+    stats: DatagramStats,
+}
+
+impl<T> Tracker<T> {
+    pub fn new(timeout: Duration) -> Self {
+        Tracker {
+            timeout,
+            states: Vec::new(),
+            outstanding: BTreeMap::new(),
+            highest_received: None,
+            stats: DatagramStats::default(),
+        }
+    }
+
+    /// Track `message`, sent (or scheduled) at `sent`; messages are tracked in order.
+    pub fn track(&mut self, sent: Instant, message: Message, slot: T) {
+        debug_assert_eq!(message.sequence(), self.states.len() as u64);
+        self.states.push(State::Outstanding);
+        self.outstanding
+            .insert(message.sequence(), (sent, message, slot));
+        self.stats.sent += 1;
+    }
+
+    /// Account for a reply received at `now`; returns its latency and size if it is the first
+    /// reply to an outstanding message, the only ones to record.
+    pub fn receive(
+        &mut self,
+        datagram: &[u8],
+        now: Instant,
+    ) -> anyhow::Result<Option<(Duration, usize)>> {
+        let Some(sequence) = datagram
+            .get(..SEQUENCE_LEN)
+            .and_then(|header| header.try_into().ok())
+            .map(u64::from_be_bytes)
+        else {
+            return Err(anyhow::anyhow!("truncated reply: {} bytes", datagram.len()));
+        };
+        let Some(state) = self.states.get_mut(sequence as usize) else {
+            return Err(anyhow::anyhow!(
+                "reply to message {}, which was never sent",
+                sequence
+            ));
+        };
+
+        match *state {
+            State::Received | State::Late => {
+                self.stats.duplicates += 1;
+                Ok(None)
+            }
+            State::Lost => {
+                *state = State::Late;
+                self.stats.late += 1;
+                Ok(None)
+            }
+            State::Outstanding => {
+                *state = State::Received;
+                let (sent, mut reply, _) = self
+                    .outstanding
+                    .remove(&sequence)
+                    .expect("outstanding messages are tracked");
+                let payload = &datagram[SEQUENCE_LEN..];
+                if payload.len() != reply.size() {
+                    return Err(anyhow::anyhow!(
+                        "mismatched reply to message {}: {} bytes, sent {}",
+                        sequence,
+                        payload.len(),
+                        reply.size()
+                    ));
+                }
+                reply.check(payload)?;
+
+                self.stats.received += 1;
+                if self
+                    .highest_received
+                    .is_some_and(|highest| sequence < highest)
+                {
+                    self.stats.reordered += 1;
+                }
+                self.highest_received = self.highest_received.max(Some(sequence));
+                Ok(Some((now.saturating_duration_since(sent), reply.size())))
+            }
+        }
+    }
+
+    /// When the next outstanding message is lost, if there is one.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.outstanding
+            .first_key_value()
+            .map(|(_, (sent, _, _))| *sent + self.timeout)
+    }
+
+    /// Declare the messages that are past their deadline at `now` lost.
+    pub fn expire(&mut self, now: Instant) {
+        while let Some(entry) = self.outstanding.first_entry() {
+            let (sent, _, _) = entry.get();
+            if now < *sent + self.timeout {
+                break;
+            }
+            self.states[*entry.key() as usize] = State::Lost;
+            self.stats.lost += 1;
+            entry.remove();
+        }
+    }
+
+    /// Whether every message sent has been answered or lost.
+    pub fn idle(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    pub fn stats(&self) -> &DatagramStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::Payload;
+    use crate::protocol::PayloadMode;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// A tracker of `n` messages of 8 bytes sent at `start`, with their datagrams.
+    fn sent(n: usize, start: Instant) -> (Tracker<()>, Vec<Vec<u8>>) {
+        let mut tracker = Tracker::new(TIMEOUT);
+        let mut payload = Payload::new(PayloadMode::Sequence, 0, 0);
+        let datagrams = (0..n)
+            .map(|_| {
+                let message = payload.next(8);
+                tracker.track(start, message, ());
+                encode(message)
+            })
+            .collect();
+        (tracker, datagrams)
+    }
+
+    #[test]
+    fn in_order_replies() {
+        let start = Instant::now();
+        let (mut tracker, datagrams) = sent(2, start);
+        assert_eq!(tracker.next_deadline(), Some(start + TIMEOUT));
+        let later = start + Duration::from_millis(5);
+        for datagram in &datagrams {
+            assert_eq!(
+                tracker.receive(datagram, later).unwrap(),
+                Some((Duration::from_millis(5), 8))
+            );
+        }
+        assert!(tracker.idle());
+        assert_eq!(tracker.next_deadline(), None);
+        let stats = tracker.stats();
+        assert_eq!((stats.sent, stats.received), (2, 2));
+        assert_eq!(
+            stats.lost + stats.late + stats.reordered + stats.duplicates,
+            0
+        );
+    }
+
+    #[test]
+    fn reordered_and_duplicate_replies() {
+        let start = Instant::now();
+        let (mut tracker, datagrams) = sent(3, start);
+        assert!(tracker.receive(&datagrams[2], start).unwrap().is_some());
+        assert!(tracker.receive(&datagrams[0], start).unwrap().is_some());
+        assert!(tracker.receive(&datagrams[1], start).unwrap().is_some());
+        assert!(tracker.receive(&datagrams[1], start).unwrap().is_none());
+        let stats = tracker.stats();
+        assert_eq!(stats.received, 3);
+        assert_eq!(stats.reordered, 2);
+        assert_eq!(stats.duplicates, 1);
+    }
+
+    #[test]
+    fn lost_and_late_replies() {
+        let start = Instant::now();
+        let (mut tracker, datagrams) = sent(2, start);
+        tracker.expire(start + TIMEOUT - Duration::from_nanos(1));
+        assert_eq!(tracker.stats().lost, 0);
+        tracker.expire(start + TIMEOUT);
+        assert!(tracker.idle());
+        assert_eq!(tracker.stats().lost, 2);
+        assert_eq!(tracker.stats().loss_rate(), 1.0);
+
+        // late, and not a sample; then a duplicate of a late reply
+        assert!(tracker.receive(&datagrams[0], start).unwrap().is_none());
+        assert!(tracker.receive(&datagrams[0], start).unwrap().is_none());
+        let stats = tracker.stats();
+        assert_eq!((stats.received, stats.late, stats.duplicates), (0, 1, 1));
+    }
+
+    #[test]
+    fn invalid_replies() {
+        let start = Instant::now();
+        let (mut tracker, mut datagrams) = sent(2, start);
+        assert_eq!(
+            tracker.receive(&[0; 7], start).unwrap_err().to_string(),
+            "truncated reply: 7 bytes"
+        );
+        assert_eq!(
+            tracker
+                .receive(&7u64.to_be_bytes(), start)
+                .unwrap_err()
+                .to_string(),
+            "reply to message 7, which was never sent"
+        );
+        assert_eq!(
+            tracker
+                .receive(&datagrams[0][..12], start)
+                .unwrap_err()
+                .to_string(),
+            "mismatched reply to message 0: 4 bytes, sent 8"
+        );
+        datagrams[1][SEQUENCE_LEN] ^= 1;
+        assert!(tracker.receive(&datagrams[1], start).is_err());
+    }
+
+    #[test]
+    fn payload_lengths() {
+        assert_eq!(payload_len(SEQUENCE_LEN + 100), 100);
+        assert_eq!(payload_len(3), 0);
+        assert!(check_size(MAX_MESSAGE_SIZE).is_ok());
+        assert!(check_size(MAX_MESSAGE_SIZE + 1).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+//! Where a server listens and a client connects: a TCP address, or a Unix domain socket for a
+//! `unix:/path/to/sock` host (raw-TCP implementations only; the port is then ignored).
+
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::{Shutdown, TcpListener, ToSocketAddrs};
+use std::os::fd::AsFd;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use socket2::{Domain, SockAddr, SockRef, Socket, Type};
+
+const UNIX_PREFIX: &str = "unix:";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    /// `host:port`
+    Tcp(String),
+    /// Path of a Unix domain socket.
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    pub fn new(host: &str, port: u16) -> Self {
+        match host.strip_prefix(UNIX_PREFIX) {
+            Some(path) => Endpoint::Unix(PathBuf::from(path)),
+            None => Endpoint::Tcp(format!("{}:{}", host, port)),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => f.write_str(addr),
+            Endpoint::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// A TCP listener on `addr`, with room for `backlog` connections in its listen queue; with
+/// `reuse_port`, other listeners can share the port (`SO_REUSEPORT`).
+pub fn tcp_listener(
+    addr: impl ToSocketAddrs,
+    backlog: i32,
+    reuse_port: bool,
+) -> io::Result<TcpListener> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no address to bind"))?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    // as `TcpListener::bind` does
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(reuse_port)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    Ok(socket.into())
+}
+
+/// A Unix domain socket listener at `path`, with room for `backlog` connections in its listen
+/// queue.
+pub fn unix_listener(path: &Path, backlog: i32) -> io::Result<UnixListener> {
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.bind(&SockAddr::unix(path)?)?;
+    socket.listen(backlog)?;
+    Ok(socket.into())
+}
+
+/// Async version of [`tcp_listener`], to call within a runtime.
+#[cfg(feature = "tokio")]
+pub fn tcp_listener_async(
+    addr: impl ToSocketAddrs,
+    backlog: i32,
+    reuse_port: bool,
+) -> io::Result<tokio::net::TcpListener> {
+    let listener = tcp_listener(addr, backlog, reuse_port)?;
+    listener.set_nonblocking(true)?;
+    tokio::net::TcpListener::from_std(listener)
+}
+
+/// Async version of [`unix_listener`], to call within a runtime.
+#[cfg(feature = "tokio")]
+pub fn unix_listener_async(path: &Path, backlog: i32) -> io::Result<tokio::net::UnixListener> {
+    let listener = unix_listener(path, backlog)?;
+    listener.set_nonblocking(true)?;
+    tokio::net::UnixListener::from_std(listener)
+}
+
+/// Wake up every thread blocked on `accept` on `listener` (or a clone of it), which then fails.
+pub fn wake(listener: &impl AsFd) {
+    let _ = SockRef::from(listener).shutdown(Shutdown::Both);
+}
+
+/// Remove the socket file left at `path` by a server that is gone (e.g., killed), so that it
+/// can be bound again; a socket that a server still listens on is left alone.
+pub fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match UnixStream::connect(path) {
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+            _ => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("endpoint-{}-{}.sock", name, std::process::id()))
+    }
+
+    #[test]
+    fn parses_hosts() {
+        assert_eq!(
+            Endpoint::new("[::1]", 9094),
+            Endpoint::Tcp("[::1]:9094".to_string())
+        );
+        assert_eq!(
+            Endpoint::new("unix:/tmp/echo.sock", 9094),
+            Endpoint::Unix(PathBuf::from("/tmp/echo.sock"))
+        );
+        // only the prefix makes a Unix domain socket
+        assert_eq!(
+            Endpoint::new("unixhost", 1),
+            Endpoint::Tcp("unixhost:1".to_string())
+        );
+        assert_eq!(Endpoint::new("127.0.0.1", 80).to_string(), "127.0.0.1:80");
+        assert_eq!(
+            Endpoint::new("unix:/tmp/echo.sock", 80).to_string(),
+            "unix:/tmp/echo.sock"
+        );
+    }
+
+    #[test]
+    fn unix_round_trip() {
+        let path = temp_path("round-trip");
+        let _ = std::fs::remove_file(&path);
+        let listener = unix_listener(&path, 16).unwrap();
+        let Endpoint::Unix(connect_to) = Endpoint::new(&format!("unix:{}", path.display()), 0)
+        else {
+            panic!("not a Unix domain socket");
+        };
+
+        let mut client = UnixStream::connect(connect_to).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        drop((client, server, listener));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tcp_listeners_share_ports_when_asked() {
+        let first = tcp_listener("127.0.0.1:0", 16, true).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = tcp_listener(addr, 16, true).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+        assert!(tcp_listener(addr, 16, false).is_err());
+    }
+
+    #[test]
+    fn wakes_blocked_accepts() {
+        let listener = tcp_listener("127.0.0.1:0", 16, false).unwrap();
+        let accepting = listener.try_clone().unwrap();
+        let accept = std::thread::spawn(move || accepting.accept().map(|_| ()));
+        // racing the accept is fine: a shut down listener fails it all the same
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        wake(&listener);
+        assert!(accept.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn removes_stale_sockets_only() {
+        let path = temp_path("stale");
+        let _ = std::fs::remove_file(&path);
+
+        // a server is still listening
+        let listener = unix_listener(&path, 16).unwrap();
+        remove_stale_socket(&path).unwrap();
+        assert!(path.exists());
+
+        // it is gone
+        drop(listener);
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+        // nothing to remove
+        remove_stale_socket(&path).unwrap();
+
+        // not a socket
+        std::fs::write(&path, "").unwrap();
+        remove_stale_socket(&path).unwrap();
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
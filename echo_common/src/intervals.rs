@@ -0,0 +1,293 @@
+//! Throughput and latency time series, reported at a fixed interval while the run goes on.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use hdrhistogram::Histogram;
+use serde_json::json;
+
+use crate::cli::OutputFormat;
+
+/// Samples of one worker since the last report.
+struct Window {
+    histogram: Histogram<u64>,
+    ops: u64,
+    bytes: u64,
+}
+
+impl Window {
+    fn new() -> Self {
+        Window {
+            histogram: Histogram::new(3).expect("3 significant digits are supported"),
+            ops: 0,
+            bytes: 0,
+        }
+    }
+}
+
+/// Handle held by a worker to record into the current interval.
+///
+/// Each worker has its own lock, which is only contended when the reporter drains it.
+#[derive(Clone)]
+pub struct IntervalRecorder(Arc<Mutex<Window>>);
+
+impl IntervalRecorder {
+    pub fn record(&self, elapsed: Duration, size: usize) {
+        if let Ok(mut window) = self.0.lock() {
+            window.ops += 1;
+            window.bytes += size as u64;
+            window
+                .histogram
+                .record(elapsed.as_nanos() as u64)
+                .expect("auto-resizing histograms accept any value");
+        }
+    }
+
+    /// Requests recorded since the last report.
+    #[cfg(test)]
+    pub(crate) fn ops(&self) -> u64 {
+        self.0.lock().map(|window| window.ops).unwrap_or(0)
+    }
+}
+
+struct Shared {
+    workers: Mutex<Vec<IntervalRecorder>>,
+    stopped: Mutex<bool>,
+    wakeup: Condvar,
+}
+
+/// Background thread that aggregates every worker's window and prints it once per interval.
+pub struct Intervals {
+    shared: Arc<Shared>,
+    reporter: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Intervals {
+    pub fn start(id: String, period: Duration, format: OutputFormat) -> Self {
+        let shared = Arc::new(Shared {
+            workers: Mutex::new(Vec::new()),
+            stopped: Mutex::new(false),
+            wakeup: Condvar::new(),
+        });
+
+        let reporter = {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                let start = Instant::now();
+                let mut from = Duration::ZERO;
+                loop {
+                    let to = from + period;
+                    let stopped = shared.wait_until(start + to);
+                    let to = if stopped { start.elapsed() } else { to };
+
+                    let window = shared.drain();
+                    print_interval(&id, format, from, to, &window);
+
+                    if stopped {
+                        break;
+                    }
+                    from = to;
+                }
+            })
+        };
+
+        Intervals {
+            shared,
+            reporter: Mutex::new(Some(reporter)),
+        }
+    }
+
+    pub fn recorder(&self) -> IntervalRecorder {
+        let recorder = IntervalRecorder(Arc::new(Mutex::new(Window::new())));
+        if let Ok(mut workers) = self.shared.workers.lock() {
+            workers.push(recorder.clone());
+        }
+        recorder
+    }
+
+    /// Report the last (partial) interval and stop the reporter.
+    pub fn stop(&self) {
+        if let Ok(mut stopped) = self.shared.stopped.lock() {
+            *stopped = true;
+            self.shared.wakeup.notify_all();
+        }
+        if let Some(reporter) = self.reporter.lock().ok().and_then(|mut r| r.take()) {
+            // a panic was already reported on stderr, the summary can still be printed
+            let _ = reporter.join();
+        }
+    }
+}
+
+impl Shared {
+    /// Sleep until `deadline`, returning early (with `true`) when stopped.
+    fn wait_until(&self, deadline: Instant) -> bool {
+        let Ok(mut stopped) = self.stopped.lock() else {
+            return true;
+        };
+        while !*stopped {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            stopped = match self.wakeup.wait_timeout(stopped, deadline - now) {
+                Ok((stopped, _)) => stopped,
+                Err(_) => return true,
+            };
+        }
+        *stopped
+    }
+
+    /// Merge and reset every worker's window.
+    fn drain(&self) -> Window {
+        let mut total = Window::new();
+        if let Ok(workers) = self.workers.lock() {
+            for worker in workers.iter() {
+                if let Ok(mut window) = worker.0.lock() {
+                    total.ops += window.ops;
+                    total.bytes += window.bytes;
+                    total
+                        .histogram
+                        .add(&window.histogram)
+                        .expect("auto-resizing histograms can be merged");
+                    *window = Window::new();
+                }
+            }
+        }
+        total
+    }
+}
+
+fn print_interval(id: &str, format: OutputFormat, from: Duration, to: Duration, window: &Window) {
+    println!("{}", interval_line(id, format, from, to, window));
+}
+
+fn interval_line(
+    id: &str,
+    format: OutputFormat,
+    from: Duration,
+    to: Duration,
+    window: &Window,
+) -> String {
+    let seconds = (to - from).as_secs_f64();
+    let per_second = |n: u64| {
+        if seconds > 0.0 {
+            n as f64 / seconds
+        } else {
+            0.0
+        }
+    };
+    let ops = per_second(window.ops);
+    let bytes = per_second(window.bytes);
+    let micros =
+        |percentile: f64| window.histogram.value_at_percentile(percentile) as f64 / 1_000f64;
+
+    match format {
+        OutputFormat::Text => format!(
+            "Interval: {} {:.3} {:.3} {:.3} {:.3} {:.3} {:.3} {:.3} {:.3}",
+            id,
+            from.as_secs_f64(),
+            to.as_secs_f64(),
+            ops,
+            bytes,
+            micros(50.0),
+            micros(90.0),
+            micros(99.0),
+            window.histogram.max() as f64 / 1_000f64,
+        ),
+        OutputFormat::Jsonl => json!({
+                "type": "interval",
+                "id": id,
+                "from": from.as_secs_f64(),
+                "to": to.as_secs_f64(),
+                "ops_per_s": ops,
+                "bytes_per_s": bytes,
+                "p50_us": micros(50.0),
+                "p90_us": micros(90.0),
+                "p99_us": micros(99.0),
+                "max_us": window.histogram.max() as f64 / 1_000f64,
+        })
+        .to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shared() -> Shared {
+        Shared {
+            workers: Mutex::new(Vec::new()),
+            stopped: Mutex::new(false),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    #[test]
+    fn drains_every_worker() {
+        let shared = shared();
+        let workers = (0..2)
+            .map(|_| IntervalRecorder(Arc::new(Mutex::new(Window::new()))))
+            .collect::<Vec<_>>();
+        *shared.workers.lock().unwrap() = workers.clone();
+        workers[0].record(Duration::from_micros(10), 100);
+        workers[0].record(Duration::from_micros(30), 100);
+        workers[1].record(Duration::from_micros(20), 50);
+
+        let window = shared.drain();
+        assert_eq!(window.ops, 3);
+        assert_eq!(window.bytes, 250);
+        assert_eq!(window.histogram.max(), 30_015);
+        // and starts over
+        assert!(workers.iter().all(|worker| worker.ops() == 0));
+        assert_eq!(shared.drain().ops, 0);
+    }
+
+    #[test]
+    fn lines() {
+        let mut window = Window::new();
+        window.ops = 4;
+        window.bytes = 4096;
+        window.histogram.record(1_000).unwrap();
+        window.histogram.record(2_000).unwrap();
+        let (from, to) = (Duration::from_secs(1), Duration::from_millis(1500));
+        assert_eq!(
+            interval_line("host:1", OutputFormat::Text, from, to, &window),
+            "Interval: host:1 1.000 1.500 8.000 8192.000 1.000 2.000 2.000 2.000"
+        );
+        let json: serde_json::Value = serde_json::from_str(&interval_line(
+            "host:1",
+            OutputFormat::Jsonl,
+            from,
+            to,
+            &window,
+        ))
+        .unwrap();
+        assert_eq!(json["type"], "interval");
+        assert_eq!(json["ops_per_s"], 8.0);
+        assert_eq!(json["p50_us"], 1.0);
+
+        // a run that stops as an interval starts
+        let empty = interval_line("host:1", OutputFormat::Text, to, to, &Window::new());
+        assert_eq!(
+            empty,
+            "Interval: host:1 1.500 1.500 0.000 0.000 0.000 0.000 0.000 0.000"
+        );
+    }
+
+    #[test]
+    fn stopping_wakes_the_reporter() {
+        let intervals = Intervals::start(
+            "test".to_string(),
+            Duration::from_secs(3600),
+            OutputFormat::Text,
+        );
+        intervals.recorder().record(Duration::from_micros(1), 1);
+        let start = Instant::now();
+        intervals.stop();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(intervals
+            .shared
+            .wait_until(Instant::now() + Duration::from_secs(3600)));
+    }
+}
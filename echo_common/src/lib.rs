@@ -0,0 +1,105 @@
+//! Code shared by the Rust echo implementations.
+//!
+//! The library owns the CLI contract, the output format and the measurement loop. An
+//! implementation only has to supply the transport: a way to perform one echo and time it.
+
+use std::future::Future;
+use std::time::Duration;
+
+use chrono::{Local, NaiveDateTime};
+
+pub mod cli;
+pub mod datagram;
+pub mod endpoint;
+pub mod intervals;
+pub mod metrics;
+pub mod output;
+pub mod payload;
+pub mod per_core;
+pub mod protocol;
+pub mod schedule;
+pub mod shutdown;
+pub mod sizes;
+pub mod sockopt;
+#[cfg(feature = "tls")]
+pub mod tls;
+
+pub use cli::{ClientArgs, ClientType, DatagramArgs, LoadArgs, OutputFormat, ServerArgs, TlsArgs};
+pub use endpoint::Endpoint;
+pub use metrics::ServerStats;
+pub use output::{Recorder, Report};
+pub use payload::{Message, Payload};
+pub use per_core::ServerMode;
+pub use schedule::Arrivals;
+#[cfg(feature = "tokio")]
+pub use schedule::Bursts;
+pub use sockopt::SocketArgs;
+
+/// Unique identifier of a client process, as printed in the `Start:` and `End:` lines.
+pub fn client_id() -> anyhow::Result<String> {
+    Ok(format!(
+        "{}:{:x}",
+        gethostname::gethostname()
+            .into_string()
+            .map_err(|os_str| anyhow::anyhow!("failed to convert hostname: {:?}", os_str))?,
+        uuid::Uuid::new_v4().simple()
+    ))
+}
+
+/// Sleep until the (local) start instant, if it is in the future.
+pub fn wait_for_start(start: Option<NaiveDateTime>) {
+    if let Some(start) = start {
+        if let Ok(delay) = (start - Local::now().naive_local()).to_std() {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+/// Closed-loop measurement: issue one request at a time until the run is over.
+pub fn closed_loop<F>(mut recorder: Recorder<'_>, mut request: F) -> anyhow::Result<()>
+where
+    F: FnMut(Message) -> anyhow::Result<Duration>,
+{
+    let mut sizes = recorder.sizes()?;
+    let mut payload = recorder.payload();
+    while recorder.running() {
+        let size = sizes.next().expect("sizes never run out");
+        let elapsed = request(payload.next(size))?;
+        recorder.record(elapsed, size);
+    }
+    recorder.finish();
+
+    Ok(())
+}
+
+/// Async version of [`closed_loop`].
+pub async fn closed_loop_async<F, Fut>(
+    mut recorder: Recorder<'_>,
+    mut request: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(Message) -> Fut,
+    Fut: Future<Output = anyhow::Result<Duration>>,
+{
+    let mut sizes = recorder.sizes()?;
+    let mut payload = recorder.payload();
+    while recorder.running() {
+        let size = sizes.next().expect("sizes never run out");
+        let elapsed = request(payload.next(size)).await?;
+        recorder.record(elapsed, size);
+    }
+    recorder.finish();
+
+    Ok(())
+}
+
+/// Multi-threaded tokio runtime with `n_cores` workers (default: one per core).
+#[cfg(feature = "tokio")]
+pub fn runtime(n_cores: Option<usize>) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(n_cores) = n_cores {
+        builder.worker_threads(n_cores);
+    }
+    builder.build()
+}
@@ -0,0 +1,291 @@
+//! Server-side counters and gauges, in the Prometheus text exposition format.
+//!
+//! Every server keeps one [`ServerStats`]. With `--admin-port`, it is served over plain HTTP
+//! (any request gets the metrics); it is also dumped on stdout when the server shuts down.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long a scrape may take to send its request, or to take the metrics in: scrapes are
+/// served one at a time, so a stalled one must not hold the others up for long.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Longest scrape request (request line and headers) read.
+const MAX_REQUEST: u64 = 8 << 10;
+
+#[derive(Default)]
+pub struct ServerStats {
+    /// Whether the server is bound yet: its statistics are served before.
+    listening: AtomicBool,
+    accepted: AtomicU64,
+    active: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    messages: AtomicU64,
+    /// Errors, by kind (the `io::ErrorKind` in snake case, when there is one).
+    errors: Mutex<BTreeMap<String, u64>>,
+}
+
+/// An active connection, counted until dropped.
+pub struct Connection(Arc<ServerStats>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ServerStats {
+    pub fn new() -> Arc<Self> {
+        Arc::new(ServerStats::default())
+    }
+
+    /// Mark the server as bound, to accept connections (or datagrams) from then on.
+    pub fn listening(&self) {
+        self.listening.store(true, Ordering::Relaxed);
+    }
+
+    /// Count an accepted connection, which stays active as long as the returned guard lives.
+    pub fn connection(self: &Arc<Self>) -> Connection {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Connection(self.clone())
+    }
+
+    /// Connections currently open.
+    pub fn active(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Count a message echoed in full.
+    pub fn message(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an error, classified by the first I/O error in its chain of sources.
+    pub fn error(&self, error: &(dyn std::error::Error + 'static)) {
+        let kind = std::iter::successors(Some(error), |e| e.source())
+            .find_map(|e| e.downcast_ref::<io::Error>())
+            .map(|e| snake_case(&format!("{:?}", e.kind())))
+            .unwrap_or_else(|| "other".to_string());
+        self.error_kind(&kind);
+    }
+
+    /// Count an error of the given kind.
+    pub fn error_kind(&self, kind: &str) {
+        if let Ok(mut errors) = self.errors.lock() {
+            *errors.entry(kind.to_string()).or_default() += 1;
+        }
+    }
+
+    /// The metrics, in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        metric(
+            "echo_listening",
+            "gauge",
+            "Whether the server accepts connections yet (1) or not (0).",
+            self.listening.load(Ordering::Relaxed) as u64,
+        );
+        metric(
+            "echo_connections_accepted_total",
+            "counter",
+            "Connections accepted.",
+            load(&self.accepted),
+        );
+        metric(
+            "echo_connections_active",
+            "gauge",
+            "Connections currently open.",
+            load(&self.active),
+        );
+        metric(
+            "echo_bytes_in_total",
+            "counter",
+            "Payload bytes received (without frame headers, or the sequence numbers of datagrams).",
+            load(&self.bytes_in),
+        );
+        metric(
+            "echo_bytes_out_total",
+            "counter",
+            "Payload bytes echoed back (without frame headers, or the sequence numbers of datagrams).",
+            load(&self.bytes_out),
+        );
+        metric(
+            "echo_messages_total",
+            "counter",
+            "Messages echoed in full.",
+            load(&self.messages),
+        );
+
+        out.push_str("# HELP echo_errors_total Errors, by kind.\n");
+        out.push_str("# TYPE echo_errors_total counter\n");
+        if let Ok(errors) = self.errors.lock() {
+            for (kind, count) in errors.iter() {
+                let _ = writeln!(out, "echo_errors_total{{kind=\"{}\"}} {}", kind, count);
+            }
+        }
+        out
+    }
+
+    /// Print the metrics on stdout, on shutdown.
+    pub fn dump(&self) {
+        print!("{}", self.render());
+    }
+
+    /// Serve the metrics on `addr`, from a background thread.
+    pub fn serve_admin(self: &Arc<Self>, addr: &str) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| anyhow::anyhow!("failed to bind admin port {}: {}", addr, e))?;
+        let stats = self.clone();
+        std::thread::spawn(move || stats.serve(listener));
+        Ok(())
+    }
+
+    fn serve(&self, listener: TcpListener) {
+        for stream in listener.incoming().flatten() {
+            // a scrape is tiny: serve it inline, and ignore clients that go away or stall
+            let _ = self.scrape(stream);
+        }
+    }
+
+    fn scrape(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+        stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+        let mut reader = BufReader::new((&stream).take(MAX_REQUEST));
+        let mut line = String::new();
+        // skip the request line and headers
+        while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+            line.clear();
+        }
+
+        let body = self.render();
+        let mut stream = &stream;
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+}
+
+fn snake_case(s: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in s.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                out.push('_');
+            }
+            out.push(c.to_ascii_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn scrape(addr: std::net::SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn renders_every_metric() {
+        let stats = ServerStats::new();
+        stats.listening();
+        let connection = stats.connection();
+        stats.connection();
+        stats.bytes_in(10);
+        stats.bytes_out(8);
+        stats.message();
+        stats.error(&io::Error::from(io::ErrorKind::ConnectionReset));
+        stats.error_kind("handshake");
+        drop(connection);
+
+        let rendered = stats.render();
+        let samples = rendered
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            samples,
+            [
+                "echo_listening 1",
+                "echo_connections_accepted_total 2",
+                "echo_connections_active 0",
+                "echo_bytes_in_total 10",
+                "echo_bytes_out_total 8",
+                "echo_messages_total 1",
+                "echo_errors_total{kind=\"connection_reset\"} 1",
+                "echo_errors_total{kind=\"handshake\"} 1",
+            ]
+        );
+        assert!(rendered.contains("# TYPE echo_messages_total counter\n"));
+    }
+
+    #[test]
+    fn stalled_scrapes_time_out() {
+        let stats = ServerStats::new();
+        stats.message();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = stats.clone();
+        std::thread::spawn(move || server.serve(listener));
+
+        // never sends its request
+        let _stalled = TcpStream::connect(addr).unwrap();
+        let start = Instant::now();
+        let response = scrape(addr);
+        assert!(
+            start.elapsed() < SCRAPE_TIMEOUT * 3,
+            "{:?}",
+            start.elapsed()
+        );
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert_eq!(body, stats.render());
+    }
+
+    #[test]
+    fn snake_case_error_kinds() {
+        assert_eq!(snake_case("ConnectionReset"), "connection_reset");
+        assert_eq!(snake_case("other"), "other");
+    }
+}
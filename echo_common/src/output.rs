@@ -0,0 +1,711 @@
+//! The output format from the README.
+//!
+//! Every client prints a header (`Message Size:`, `Corrected:`, ...), then each worker prints
+//! a `Start:` line once the warmup is over and an `End:` line. Latencies are recorded into a
+//! per-worker HDR histogram; the histograms are merged and summarized once every worker is
+//! done. With `--print-samples`, each latency (in microseconds) is also printed as it happens.
+//!
+//! With `--output-format jsonl`, the same information is printed as one JSON object per line,
+//! tagged by `type`, and every sample is printed with its worker, connection and sequence
+//! number.
+//!
+//! With `--size-dist`, the summary is followed by one per size bucket (powers of two).
+//!
+//! With `--report-interval`, the throughput and latency percentiles of every interval are
+//! also printed during the run, aggregated across workers (see [`crate::intervals`]).
+//!
+//! Socket options (`--nodelay`, ...) are printed in the header when they are set.
+//!
+//! With `--tls`, connection setup (TCP and TLS handshakes) is timed apart from the echoes, and
+//! summarized after them.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use hdrhistogram::serialization::{Serializer, V2DeflateSerializer};
+use hdrhistogram::Histogram;
+use serde_json::json;
+
+use crate::cli::{ClientArgs, OutputFormat};
+use crate::datagram::DatagramStats;
+use crate::intervals::{IntervalRecorder, Intervals};
+use crate::payload::Payload;
+use crate::protocol::PayloadMode;
+use crate::sizes::{self, SizeDist, Sizes};
+use crate::sockopt::SocketArgs;
+
+/// Percentiles printed in the summary.
+const PERCENTILES: [f64; 6] = [50.0, 90.0, 95.0, 99.0, 99.9, 99.99];
+
+fn new_histogram() -> Histogram<u64> {
+    // latencies in nanoseconds, auto-resizing, with 3 significant digits
+    Histogram::new(3).expect("3 significant digits are supported")
+}
+
+/// Per-process output state: the header, the merged histogram and the summary.
+pub struct Report {
+    id: String,
+    size_dist: SizeDist,
+    payload: PayloadMode,
+    payload_seed: u64,
+    /// Whether results are broken down by size bucket (with `--size-dist`).
+    bucketed: bool,
+    warmup: Duration,
+    deadline: Duration,
+    expected_interval: Option<Duration>,
+    print_samples: bool,
+    format: OutputFormat,
+    /// `tls` or `mtls`, when connections are encrypted.
+    tls: Option<&'static str>,
+    socket: SocketArgs,
+    merged: Mutex<Histogram<u64>>,
+    /// Requests measured by every (finished) worker: the merged histogram also holds the
+    /// samples added by the coordinated-omission correction.
+    requests: AtomicU64,
+    /// Connection setup times, kept apart from the echo latencies.
+    handshakes: Mutex<Histogram<u64>>,
+    /// Merged histograms per size bucket, keyed by the largest size in the bucket.
+    buckets: Mutex<BTreeMap<usize, Histogram<u64>>>,
+    report_interval: Option<Duration>,
+    /// Started along with the first worker.
+    intervals: OnceLock<Intervals>,
+    /// The same instant on both clocks, to tell the wall-clock time of an `Instant`.
+    epoch: (Instant, SystemTime),
+}
+
+impl Report {
+    pub fn new(args: &ClientArgs) -> anyhow::Result<Self> {
+        Ok(Report {
+            id: crate::client_id()?,
+            size_dist: args.size_dist(),
+            payload: args.payload,
+            payload_seed: args.payload_seed,
+            bucketed: args.size_dist.is_some(),
+            warmup: args.warmup(),
+            deadline: args.warmup() + args.duration(),
+            expected_interval: args.expected_interval,
+            print_samples: args.print_samples,
+            format: args.output_format,
+            tls: match (args.tls.tls, args.tls.mtls) {
+                (_, true) => Some("mtls"),
+                (true, false) => Some("tls"),
+                (false, false) => None,
+            },
+            socket: args.socket.clone(),
+            merged: Mutex::new(new_histogram()),
+            requests: AtomicU64::new(0),
+            handshakes: Mutex::new(new_histogram()),
+            buckets: Mutex::new(BTreeMap::new()),
+            report_interval: args.report_interval,
+            intervals: OnceLock::new(),
+            epoch: (Instant::now(), SystemTime::now()),
+        })
+    }
+
+    /// Header shared by every client.
+    pub fn header(&self) {
+        match self.format {
+            OutputFormat::Text => {
+                // the mean size, so that throughputs in bytes stay right
+                println!("Message Size: {}", self.mean_size());
+                if self.bucketed {
+                    println!("Size Distribution: {}", self.size_dist);
+                }
+                println!("Payload: {}", self.payload);
+                match self.expected_interval {
+                    Some(interval) => {
+                        println!("Corrected: {}", humantime::format_duration(interval))
+                    }
+                    None => println!("Corrected: none"),
+                }
+                if let Some(tls) = self.tls {
+                    println!("TLS: {}", tls);
+                }
+                let socket = &self.socket;
+                if let Some(nodelay) = socket.nodelay {
+                    println!("Nodelay: {}", nodelay);
+                }
+                if let Some(size) = socket.sndbuf {
+                    println!("Send Buffer: {}", size);
+                }
+                if let Some(size) = socket.rcvbuf {
+                    println!("Receive Buffer: {}", size);
+                }
+                if let Some(busy_poll) = socket.busy_poll_us {
+                    println!("Busy Poll: {}us", busy_poll);
+                }
+                if socket.quickack {
+                    println!("Quick Ack: true");
+                }
+                if socket.cork {
+                    println!("Cork: true");
+                }
+            }
+            OutputFormat::Jsonl => println!(
+                "{}",
+                json!({
+                    "type": "header",
+                    "id": self.id,
+                    "message_size": self.mean_size(),
+                    "size_dist": self.bucketed.then(|| self.size_dist.to_string()),
+                    "payload": self.payload.to_string(),
+                    "expected_interval_us": self.expected_interval.map(micros),
+                    "tls": self.tls,
+                    "nodelay": self.socket.nodelay,
+                    "sndbuf": self.socket.sndbuf,
+                    "rcvbuf": self.socket.rcvbuf,
+                    "busy_poll_us": self.socket.busy_poll_us,
+                    "quickack": self.socket.quickack,
+                    "cork": self.socket.cork,
+                })
+            ),
+        }
+    }
+
+    /// Target request rate of an open-loop run, in requests per second.
+    pub fn rate(&self, rate: f64) {
+        match self.format {
+            OutputFormat::Text => println!("Rate: {:.3}", rate),
+            OutputFormat::Jsonl => println!("{}", json!({ "type": "rate", "rate": rate })),
+        }
+    }
+
+    /// Shape of the bursts of a controlled bursty run.
+    pub fn burst(&self, size: usize, period: Duration) {
+        match self.format {
+            OutputFormat::Text => {
+                println!("Burst Size: {}", size);
+                println!("Burst Period: {}", humantime::format_duration(period));
+            }
+            OutputFormat::Jsonl => println!(
+                "{}",
+                json!({ "type": "burst", "size": size, "period_us": micros(period) })
+            ),
+        }
+    }
+
+    /// Outstanding requests per connection of a pipelined run.
+    pub fn pipeline(&self, depth: usize) {
+        match self.format {
+            OutputFormat::Text => println!("Pipeline Depth: {}", depth),
+            OutputFormat::Jsonl => {
+                println!("{}", json!({ "type": "pipeline", "depth": depth }))
+            }
+        }
+    }
+
+    /// Delivery counters of a UDP run, across workers.
+    pub fn datagrams(&self, stats: &DatagramStats) {
+        match self.format {
+            OutputFormat::Text => {
+                println!("Sent: {}", stats.sent);
+                println!("Received: {}", stats.received);
+                println!("Lost: {}", stats.lost);
+                println!("Loss Rate: {:.6}", stats.loss_rate());
+                println!("Late: {}", stats.late);
+                println!("Reordered: {}", stats.reordered);
+                println!("Duplicates: {}", stats.duplicates);
+            }
+            OutputFormat::Jsonl => println!(
+                "{}",
+                json!({
+                    "type": "datagrams",
+                    "id": self.id,
+                    "sent": stats.sent,
+                    "received": stats.received,
+                    "lost": stats.lost,
+                    "loss_rate": stats.loss_rate(),
+                    "late": stats.late,
+                    "reordered": stats.reordered,
+                    "duplicates": stats.duplicates,
+                })
+            ),
+        }
+    }
+
+    /// Start measuring a new worker, issuing requests over `connection`.
+    pub fn recorder(&self, worker: usize, connection: impl Into<String>) -> Recorder<'_> {
+        let interval = self.report_interval.map(|period| {
+            self.intervals
+                .get_or_init(|| Intervals::start(self.id.clone(), period, self.format))
+                .recorder()
+        });
+        Recorder {
+            report: self,
+            worker,
+            connection: connection.into(),
+            sequence: 0,
+            requests: 0,
+            histogram: new_histogram(),
+            buckets: BTreeMap::new(),
+            start: Instant::now(),
+            reporting: false,
+            interval,
+        }
+    }
+
+    /// Record how long it took to set up a connection, TLS handshake included.
+    pub fn handshake(&self, elapsed: Duration) {
+        if let Ok(mut handshakes) = self.handshakes.lock() {
+            handshakes
+                .record(elapsed.as_nanos() as u64)
+                .expect("auto-resizing histograms accept any value");
+        }
+    }
+
+    /// Print the summary of the merged histogram of every (finished) worker.
+    ///
+    /// Also stops the interval reports, after the last (partial) interval.
+    pub fn summary(&self) -> anyhow::Result<()> {
+        if let Some(intervals) = self.intervals.get() {
+            intervals.stop();
+        }
+
+        let merged = self
+            .merged
+            .lock()
+            .map_err(|_| anyhow::anyhow!("a worker panicked while merging its histogram"))?;
+
+        let mut serialized = Vec::new();
+        V2DeflateSerializer::new()
+            .serialize(&merged, &mut serialized)
+            .map_err(|e| anyhow::anyhow!("failed to serialize histogram: {:?}", e))?;
+        let serialized = base64::engine::general_purpose::STANDARD.encode(serialized);
+        let requests = self.requests.load(Ordering::Relaxed);
+
+        match self.format {
+            OutputFormat::Text => {
+                println!("Samples: {}", merged.len());
+                println!("Requests: {}", requests);
+                if !merged.is_empty() {
+                    println!("Min: {:.3} us", nanos_to_micros(merged.min()));
+                    println!("Mean: {:.3} us", merged.mean() / 1_000f64);
+                    println!("Stddev: {:.3} us", merged.stdev() / 1_000f64);
+                    for percentile in PERCENTILES {
+                        println!(
+                            "P{}: {:.3} us",
+                            percentile,
+                            nanos_to_micros(merged.value_at_percentile(percentile))
+                        );
+                    }
+                    println!("Max: {:.3} us", nanos_to_micros(merged.max()));
+                }
+                println!("Histogram: {}", serialized);
+            }
+            OutputFormat::Jsonl => {
+                let percentiles = PERCENTILES
+                    .iter()
+                    .map(|percentile| {
+                        (
+                            format!("p{}", percentile),
+                            json!(nanos_to_micros(merged.value_at_percentile(*percentile))),
+                        )
+                    })
+                    .collect::<serde_json::Map<_, _>>();
+                println!(
+                    "{}",
+                    json!({
+                        "type": "summary",
+                        "id": self.id,
+                        "samples": merged.len(),
+                        "requests": requests,
+                        "min_us": nanos_to_micros(merged.min()),
+                        "mean_us": merged.mean() / 1_000f64,
+                        "stddev_us": merged.stdev() / 1_000f64,
+                        "percentiles_us": percentiles,
+                        "max_us": nanos_to_micros(merged.max()),
+                        "histogram": serialized,
+                    })
+                );
+            }
+        }
+        drop(merged);
+
+        if self.bucketed {
+            self.print_buckets()?;
+        }
+        self.print_handshakes()?;
+
+        Ok(())
+    }
+
+    /// Print the summary of the connection setup times, if any were recorded.
+    fn print_handshakes(&self) -> anyhow::Result<()> {
+        let handshakes = self
+            .handshakes
+            .lock()
+            .map_err(|_| anyhow::anyhow!("a worker panicked while recording a handshake"))?;
+        if handshakes.is_empty() {
+            return Ok(());
+        }
+        let percentile = |p: f64| nanos_to_micros(handshakes.value_at_percentile(p));
+        match self.format {
+            OutputFormat::Text => println!(
+                "Handshake: {} {:.3} {:.3} {:.3} {:.3}",
+                handshakes.len(),
+                handshakes.mean() / 1_000f64,
+                percentile(50.0),
+                percentile(99.0),
+                nanos_to_micros(handshakes.max()),
+            ),
+            OutputFormat::Jsonl => println!(
+                "{}",
+                json!({
+                    "type": "handshake",
+                    "id": self.id,
+                    "samples": handshakes.len(),
+                    "mean_us": handshakes.mean() / 1_000f64,
+                    "p50_us": percentile(50.0),
+                    "p99_us": percentile(99.0),
+                    "max_us": nanos_to_micros(handshakes.max()),
+                })
+            ),
+        }
+        Ok(())
+    }
+
+    /// Print the summary of every size bucket.
+    fn print_buckets(&self) -> anyhow::Result<()> {
+        let buckets = self
+            .buckets
+            .lock()
+            .map_err(|_| anyhow::anyhow!("a worker panicked while merging its histogram"))?;
+        for (max_size, histogram) in buckets.iter() {
+            let percentile = |p: f64| nanos_to_micros(histogram.value_at_percentile(p));
+            match self.format {
+                OutputFormat::Text => println!(
+                    "Bucket: {} {} {:.3} {:.3} {:.3} {:.3}",
+                    max_size,
+                    histogram.len(),
+                    histogram.mean() / 1_000f64,
+                    percentile(50.0),
+                    percentile(99.0),
+                    nanos_to_micros(histogram.max()),
+                ),
+                OutputFormat::Jsonl => println!(
+                    "{}",
+                    json!({
+                        "type": "bucket",
+                        "id": self.id,
+                        "max_size": max_size,
+                        "samples": histogram.len(),
+                        "mean_us": histogram.mean() / 1_000f64,
+                        "p50_us": percentile(50.0),
+                        "p99_us": percentile(99.0),
+                        "max_us": nanos_to_micros(histogram.max()),
+                    })
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    fn mean_size(&self) -> usize {
+        self.size_dist.mean().round() as usize
+    }
+}
+
+/// Tracks the warmup and measurement phases of a single worker and records its samples.
+pub struct Recorder<'a> {
+    report: &'a Report,
+    worker: usize,
+    connection: String,
+    sequence: u64,
+    /// Requests measured, without the samples added by the coordinated-omission correction.
+    requests: u64,
+    histogram: Histogram<u64>,
+    buckets: BTreeMap<usize, Histogram<u64>>,
+    start: Instant,
+    reporting: bool,
+    interval: Option<IntervalRecorder>,
+}
+
+impl<'a> Recorder<'a> {
+    /// Whether the worker should keep issuing requests.
+    pub fn running(&self) -> bool {
+        self.start.elapsed() < self.report.deadline
+    }
+
+    /// Instant at which the worker should stop issuing requests.
+    pub fn deadline(&self) -> Instant {
+        self.start + self.report.deadline
+    }
+
+    /// A new stream of message sizes for this worker.
+    pub fn sizes(&self) -> anyhow::Result<Sizes> {
+        self.report.size_dist.sampler()
+    }
+
+    /// The messages of this worker's connection.
+    pub fn payload(&self) -> Payload {
+        Payload::new(
+            self.report.payload,
+            self.report.payload_seed,
+            self.worker as u64,
+        )
+    }
+
+    /// Record the latency of one request of `size` bytes, printing `Start:` when the warmup
+    /// ends.
+    ///
+    /// With an expected interval, a request that took longer than that interval also records
+    /// the latencies that the requests it held back would have seen (as in HdrHistogram's
+    /// `recordValueWithExpectedInterval`).
+    ///
+    /// Interval reports cover the same requests as the histogram, but are not corrected.
+    pub fn record(&mut self, elapsed: Duration, size: usize) {
+        // before anything is printed, which may block
+        let received = Instant::now();
+        let sequence = self.sequence;
+        self.sequence += 1;
+
+        let now = self.start.elapsed();
+        if !self.reporting && now > self.report.warmup {
+            self.reporting = true;
+            self.phase("Start", now);
+        }
+
+        if !self.reporting || now >= self.report.deadline {
+            return;
+        }
+
+        self.requests += 1;
+        if let Some(interval) = &self.interval {
+            interval.record(elapsed, size);
+        }
+        let value = elapsed.as_nanos() as u64;
+        match self.report.expected_interval {
+            Some(interval) => self
+                .histogram
+                .record_correct(value, interval.as_nanos() as u64),
+            None => self.histogram.record(value),
+        }
+        .expect("auto-resizing histograms accept any value");
+        if self.report.bucketed {
+            let histogram = self
+                .buckets
+                .entry(sizes::bucket(size))
+                .or_insert_with(new_histogram);
+            match self.report.expected_interval {
+                Some(interval) => histogram.record_correct(value, interval.as_nanos() as u64),
+                None => histogram.record(value),
+            }
+            .expect("auto-resizing histograms accept any value");
+        }
+
+        let print_samples = match self.report.format {
+            OutputFormat::Text => self.report.print_samples,
+            OutputFormat::Jsonl => true,
+        };
+        if print_samples {
+            self.sample(sequence, received, elapsed, false, size);
+            if let Some(interval) = self.report.expected_interval {
+                let mut missing = elapsed.saturating_sub(interval);
+                while missing >= interval {
+                    self.sample(sequence, received, missing, true, size);
+                    missing -= interval;
+                }
+            }
+        }
+    }
+
+    /// Print `End:` and merge this worker's samples into the report.
+    pub fn finish(self) {
+        self.phase("End", self.start.elapsed());
+        self.report
+            .requests
+            .fetch_add(self.requests, Ordering::Relaxed);
+        if let Ok(mut merged) = self.report.merged.lock() {
+            merged
+                .add(&self.histogram)
+                .expect("auto-resizing histograms can be merged");
+        }
+        if let Ok(mut buckets) = self.report.buckets.lock() {
+            for (bucket, histogram) in &self.buckets {
+                buckets
+                    .entry(*bucket)
+                    .or_insert_with(new_histogram)
+                    .add(histogram)
+                    .expect("auto-resizing histograms can be merged");
+            }
+        }
+    }
+
+    fn phase(&self, phase: &str, now: Duration) {
+        match self.report.format {
+            OutputFormat::Text => {
+                println!("{}: {} {:.9}", phase, self.report.id, now.as_secs_f64())
+            }
+            OutputFormat::Jsonl => println!(
+                "{}",
+                json!({
+                    "type": phase.to_lowercase(),
+                    "id": self.report.id,
+                    "worker": self.worker,
+                    "connection": self.connection,
+                    "ts": now.as_secs_f64(),
+                })
+            ),
+        }
+    }
+
+    /// Print one sample, of a reply `received` after `elapsed`; `synthetic` samples are the ones
+    /// added by the coordinated-omission correction, on behalf of the request with the same
+    /// sequence number, and were due to be sent when the requests they stand for would have
+    /// been.
+    fn sample(
+        &self,
+        sequence: u64,
+        received: Instant,
+        elapsed: Duration,
+        synthetic: bool,
+        size: usize,
+    ) {
+        match self.report.format {
+            OutputFormat::Text => println!("{:.3}", micros(elapsed)),
+            OutputFormat::Jsonl => {
+                let sent = received.checked_sub(elapsed).unwrap_or(received);
+                let (epoch, wall_clock) = self.report.epoch;
+                // requests are not sent before the report is created
+                let sent = (wall_clock + sent.saturating_duration_since(epoch))
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                println!(
+                    "{}",
+                    json!({
+                        "type": "sample",
+                        "id": self.report.id,
+                        "worker": self.worker,
+                        "connection": self.connection,
+                        "seq": sequence,
+                        "sent_unix_ns": sent.as_nanos() as u64,
+                        "latency_us": micros(elapsed),
+                        "message_size": size,
+                        "synthetic": synthetic,
+                    })
+                );
+            }
+        }
+    }
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1_000_000f64
+}
+
+fn nanos_to_micros(nanos: u64) -> f64 {
+    nanos as f64 / 1_000f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches, Parser};
+
+    #[derive(Parser)]
+    struct Args {
+        #[command(flatten)]
+        common: ClientArgs,
+    }
+
+    /// A report without warmup, with the client options `args`.
+    fn report(args: &[&str]) -> Report {
+        let args = ["client", "127.0.0.1", "9094", "--warmup", "0s"]
+            .iter()
+            .chain(args);
+        // as `cli::parse`
+        let matches = Args::command()
+            .mut_arg("port", |arg| arg.required(false).default_value("9094"))
+            .try_get_matches_from(args)
+            .unwrap();
+        Report::new(&Args::from_arg_matches(&matches).unwrap().common).unwrap()
+    }
+
+    fn values(histogram: &Histogram<u64>) -> Vec<(u64, u64)> {
+        histogram
+            .iter_recorded()
+            .map(|value| (value.value_iterated_to(), value.count_at_value()))
+            .collect()
+    }
+
+    #[test]
+    fn records_latencies() {
+        let report = report(&[]);
+        let mut recorder = report.recorder(0, "test");
+        recorder.record(Duration::from_nanos(1_000), 1);
+        recorder.record(Duration::from_nanos(1_000), 1);
+        recorder.record(Duration::from_nanos(2_000), 1);
+        assert_eq!(values(&recorder.histogram), [(1_000, 2), (2_000, 1)]);
+        assert!(recorder.buckets.is_empty());
+
+        recorder.finish();
+        let merged = report.merged.lock().unwrap();
+        assert_eq!(values(&merged), [(1_000, 2), (2_000, 1)]);
+    }
+
+    #[test]
+    fn corrects_for_coordinated_omission() {
+        let report = report(&["--expected-interval", "500ns"]);
+        let mut recorder = report.recorder(0, "test");
+        // held back three requests, which would have waited 1500, 1000 and 500ns
+        recorder.record(Duration::from_nanos(2_000), 1);
+        // on time
+        recorder.record(Duration::from_nanos(400), 1);
+        assert_eq!(
+            values(&recorder.histogram),
+            [(400, 1), (500, 1), (1_000, 1), (1_500, 1), (2_000, 1)]
+        );
+
+        // the synthetic samples are not requests
+        recorder.finish();
+        assert_eq!(report.merged.lock().unwrap().len(), 5);
+        assert_eq!(report.requests.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn records_by_size_bucket() {
+        let report = report(&["--size-dist", "uniform:1,100", "--expected-interval", "1us"]);
+        let mut recorder = report.recorder(0, "test");
+        recorder.record(Duration::from_nanos(2_000), 3);
+        recorder.record(Duration::from_nanos(1_000), 4);
+        recorder.record(Duration::from_nanos(1_500), 100);
+        let buckets = recorder
+            .buckets
+            .iter()
+            .map(|(bucket, histogram)| (*bucket, values(histogram)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            buckets,
+            [(4, vec![(1_000, 2), (2_000, 1)]), (128, vec![(1_500, 1)])]
+        );
+
+        recorder.finish();
+        let merged = report.buckets.lock().unwrap();
+        assert_eq!(merged.keys().collect::<Vec<_>>(), [&4, &128]);
+    }
+
+    #[test]
+    fn stops_recording_at_the_deadline() {
+        let report = report(&["--duration", "0s", "--report-interval", "1h"]);
+        let mut recorder = report.recorder(0, "test");
+        recorder.record(Duration::from_nanos(1_000), 1);
+        assert_eq!(recorder.histogram.len(), 0);
+        // nor are the intervals
+        assert_eq!(recorder.interval.as_ref().unwrap().ops(), 0);
+        recorder.finish();
+        report.summary().unwrap();
+    }
+
+    #[test]
+    fn intervals_cover_the_requests_recorded() {
+        let report = report(&["--report-interval", "1h"]);
+        let mut recorder = report.recorder(0, "test");
+        recorder.record(Duration::from_nanos(1_000), 1);
+        assert_eq!(recorder.interval.as_ref().unwrap().ops(), 1);
+        recorder.finish();
+        report.summary().unwrap();
+    }
+}
@@ -0,0 +1,363 @@
+//! Message payloads (`--payload`), and the verification of the replies.
+//!
+//! The contents of a message only depend on the payload mode, the seed, the connection, the
+//! sequence number of the message on that connection and the offset in the message, so that
+//! replies can be checked chunk by chunk, however they are split, without keeping what was
+//! sent:
+//! - `constant` messages are all 42s, which only catches truncation;
+//! - `sequence` messages repeat a 16-byte stamp (the connection, then the sequence number,
+//!   big-endian), which also catches replies that are reordered, duplicated or from another
+//!   connection;
+//! - `random` messages are pseudo-random, which also catches corruption within a message;
+//! - `crc32c` messages are sequence-stamped, and end with the CRC32C of the rest of the
+//!   message (big-endian), which is all that is verified, as an application would.
+
+use crate::protocol::PayloadMode;
+
+/// Length of the stamp of sequence-stamped messages.
+const STAMP_LEN: usize = 16;
+
+/// Length of the checksum at the end of `crc32c` messages.
+const CHECKSUM_LEN: usize = 4;
+
+/// Expected bytes are generated, and compared with the reply, this many at a time.
+const CHUNK_LEN: usize = 256;
+
+/// The messages of one connection.
+pub struct Payload {
+    mode: PayloadMode,
+    seed: u64,
+    connection: u64,
+    sequence: u64,
+}
+
+impl Payload {
+    pub fn new(mode: PayloadMode, seed: u64, connection: u64) -> Self {
+        Payload {
+            mode,
+            seed,
+            connection,
+            sequence: 0,
+        }
+    }
+
+    /// The next message, of `size` bytes.
+    pub fn next(&mut self, size: usize) -> Message {
+        let sequence = self.sequence;
+        self.sequence += 1;
+
+        let mut stamp = [0; STAMP_LEN];
+        stamp[..8].copy_from_slice(&self.connection.to_be_bytes());
+        stamp[8..].copy_from_slice(&sequence.to_be_bytes());
+        Message {
+            mode: self.mode,
+            sequence,
+            stamp,
+            key: splitmix64(splitmix64(self.seed ^ splitmix64(self.connection)) ^ sequence),
+            size,
+            offset: 0,
+            crc: !0,
+        }
+    }
+}
+
+/// One message, generated or checked from start to end, in chunks.
+///
+/// Messages are cheap to copy: keep a copy of a fresh message to check its reply.
+#[derive(Clone, Copy, Debug)]
+pub struct Message {
+    mode: PayloadMode,
+    sequence: u64,
+    stamp: [u8; STAMP_LEN],
+    /// Seed of the random bytes.
+    key: u64,
+    size: usize,
+    /// Bytes generated or checked so far.
+    offset: usize,
+    /// Running CRC32C of the bytes before the checksum.
+    crc: u32,
+}
+
+impl Message {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Sequence number of the message on its connection.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Generate the next `buf.len()` bytes of the message.
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        if self.mode == PayloadMode::Constant {
+            buf.fill(42);
+            self.offset += buf.len();
+            return;
+        }
+        let (content, checksum) = buf.split_at_mut(self.content_len(buf.len()));
+        self.generate(content);
+        if self.mode == PayloadMode::Crc32c {
+            self.crc = crc32c(self.crc, content);
+        }
+        self.offset += content.len();
+        for byte in checksum {
+            *byte = self.checksum(self.offset);
+            self.offset += 1;
+        }
+    }
+
+    /// Check the next `buf.len()` bytes of the reply.
+    pub fn check(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        if self.mode == PayloadMode::Constant {
+            if !buf.iter().all(|x| *x == 42) {
+                return Err(anyhow::anyhow!("mismatched reply"));
+            }
+            self.offset += buf.len();
+            return Ok(());
+        }
+        let (content, checksum) = buf.split_at(self.content_len(buf.len()));
+        if self.mode == PayloadMode::Crc32c {
+            self.crc = crc32c(self.crc, content);
+            self.offset += content.len();
+        } else {
+            // compared a chunk at a time, as the reply is read within the latency of the message
+            let mut expected = [0; CHUNK_LEN];
+            for chunk in content.chunks(CHUNK_LEN) {
+                let expected = &mut expected[..chunk.len()];
+                self.generate(expected);
+                if let Some(i) = chunk.iter().zip(&*expected).position(|(x, y)| x != y) {
+                    self.offset += i;
+                    return Err(self.mismatch(chunk[i], expected[i]));
+                }
+                self.offset += chunk.len();
+            }
+        }
+        for &byte in checksum {
+            let expected = self.checksum(self.offset);
+            if byte != expected {
+                return Err(self.mismatch(byte, expected));
+            }
+            self.offset += 1;
+        }
+        Ok(())
+    }
+
+    /// How many of the next `len` bytes come before the checksum.
+    fn content_len(&self, len: usize) -> usize {
+        len.min(self.checksum_offset().saturating_sub(self.offset))
+    }
+
+    /// Generate the bytes from the current offset on, outside of the checksum, a block (a stamp,
+    /// or a random word) at a time.
+    fn generate(&self, out: &mut [u8]) {
+        match self.mode {
+            PayloadMode::Constant => out.fill(42),
+            PayloadMode::Sequence | PayloadMode::Crc32c => {
+                blocks(out, self.offset, |_| self.stamp);
+            }
+            PayloadMode::Random => blocks(out, self.offset, |block| {
+                splitmix64(self.key.wrapping_add(block as u64)).to_le_bytes()
+            }),
+        }
+    }
+
+    /// Offset of the checksum, which is cut short in messages shorter than a checksum.
+    fn checksum_offset(&self) -> usize {
+        match self.mode {
+            PayloadMode::Crc32c => self.size.saturating_sub(CHECKSUM_LEN),
+            _ => self.size,
+        }
+    }
+
+    /// Byte of the checksum at `offset`, once every byte before it is in.
+    fn checksum(&self, offset: usize) -> u8 {
+        (!self.crc).to_be_bytes()[CHECKSUM_LEN - (self.size - offset)]
+    }
+
+    fn mismatch(&self, byte: u8, expected: u8) -> anyhow::Error {
+        if self.mode == PayloadMode::Crc32c {
+            return anyhow::anyhow!(
+                "mismatched reply to message {}: bad checksum",
+                self.sequence
+            );
+        }
+        anyhow::anyhow!(
+            "mismatched reply to message {}: byte {} is {:#04x}, expected {:#04x}",
+            self.sequence,
+            self.offset,
+            byte,
+            expected
+        )
+    }
+}
+
+/// Fill `out` with the bytes from `offset` on of a sequence of `N`-byte blocks, the block at
+/// every index being `block(index)`.
+fn blocks<const N: usize>(out: &mut [u8], offset: usize, block: impl Fn(usize) -> [u8; N]) {
+    let mut i = 0;
+    while i < out.len() {
+        let offset = offset + i;
+        let phase = offset % N;
+        let len = (out.len() - i).min(N - phase);
+        out[i..i + len].copy_from_slice(&block(offset / N)[phase..phase + len]);
+        i += len;
+    }
+}
+
+/// SplitMix64, a counter-based generator: random bytes can be generated at any offset.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Lookup tables of the (reflected) CRC32C, or Castagnoli, polynomial, to add 8 bytes at a time
+/// ("slicing-by-8"): `CRC32C_TABLES[k][i]` is the CRC of byte `i` followed by `k` zeros.
+const CRC32C_TABLES: [[u32; 256]; 8] = crc32c_tables();
+
+const fn crc32c_tables() -> [[u32; 256]; 8] {
+    let mut tables = [[0; 256]; 8];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        tables[0][i] = crc;
+        i += 1;
+    }
+    let mut k = 1;
+    while k < 8 {
+        let mut i = 0;
+        while i < 256 {
+            let crc = tables[k - 1][i];
+            tables[k][i] = (crc >> 8) ^ tables[0][(crc & 0xff) as usize];
+            i += 1;
+        }
+        k += 1;
+    }
+    tables
+}
+
+/// Add `bytes` to a running CRC32C, which starts at `!0` and is complemented at the end.
+fn crc32c(mut crc: u32, bytes: &[u8]) -> u32 {
+    let t = &CRC32C_TABLES;
+    let mut words = bytes.chunks_exact(8);
+    for word in &mut words {
+        let lo = crc ^ u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        let hi = u32::from_le_bytes([word[4], word[5], word[6], word[7]]);
+        crc = t[7][(lo & 0xff) as usize]
+            ^ t[6][((lo >> 8) & 0xff) as usize]
+            ^ t[5][((lo >> 16) & 0xff) as usize]
+            ^ t[4][(lo >> 24) as usize]
+            ^ t[3][(hi & 0xff) as usize]
+            ^ t[2][((hi >> 8) & 0xff) as usize]
+            ^ t[1][((hi >> 16) & 0xff) as usize]
+            ^ t[0][(hi >> 24) as usize];
+    }
+    for &byte in words.remainder() {
+        crc = t[0][((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [PayloadMode; 4] = [
+        PayloadMode::Constant,
+        PayloadMode::Sequence,
+        PayloadMode::Random,
+        PayloadMode::Crc32c,
+    ];
+
+    /// Fill a message of `size` bytes in chunks of `chunk` bytes.
+    fn message(payload: &mut Payload, size: usize, chunk: usize) -> (Message, Vec<u8>) {
+        let fresh = payload.next(size);
+        let mut message = fresh;
+        let mut buf = vec![0; size];
+        buf.chunks_mut(chunk).for_each(|chunk| message.fill(chunk));
+        (fresh, buf)
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(!crc32c(!0, b"123456789"), 0xe306_9283);
+        // bytewise and by the word
+        let bytes: Vec<u8> = (0..100u8).collect();
+        let bytewise = bytes.iter().fold(!0, |crc, byte| crc32c(crc, &[*byte]));
+        assert_eq!(crc32c(!0, &bytes), bytewise);
+    }
+
+    #[test]
+    fn fill_is_independent_of_chunking() {
+        for mode in MODES {
+            for size in [0, 1, 3, 4, 5, 16, 17, 255, 256, 257, 1000] {
+                let (_, whole) = message(&mut Payload::new(mode, 7, 3), size, size.max(1));
+                for chunk in [1, 3, 8, 13] {
+                    let (_, chunked) = message(&mut Payload::new(mode, 7, 3), size, chunk);
+                    assert_eq!(whole, chunked, "{:?} {} by {}", mode, size, chunk);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn replies_check_in_any_chunks() {
+        for mode in MODES {
+            for size in [0, 1, 3, 4, 5, 16, 17, 255, 256, 257, 1000] {
+                let mut payload = Payload::new(mode, 7, 3);
+                let (fresh, buf) = message(&mut payload, size, 100);
+                for chunk in [1, 7, 300] {
+                    let mut reply = fresh;
+                    for part in buf.chunks(chunk) {
+                        reply.check(part).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn corruption_is_caught() {
+        for mode in [
+            PayloadMode::Sequence,
+            PayloadMode::Random,
+            PayloadMode::Crc32c,
+        ] {
+            let mut payload = Payload::new(mode, 7, 3);
+            let (fresh, mut buf) = message(&mut payload, 1000, 1000);
+            buf[600] ^= 1;
+            let e = {
+                let mut reply = fresh;
+                reply.check(&buf)
+            }
+            .unwrap_err()
+            .to_string();
+            if mode != PayloadMode::Crc32c {
+                assert!(e.contains("byte 600"), "{}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn random_depends_on_message() {
+        let mut payload = Payload::new(PayloadMode::Random, 7, 3);
+        let (_, first) = message(&mut payload, 64, 64);
+        let (second, _) = message(&mut payload, 64, 64);
+        assert!({
+            let mut reply = second;
+            reply.check(&first)
+        }
+        .is_err());
+    }
+}
@@ -0,0 +1,46 @@
+//! Thread-per-core servers (`--mode thread-per-core`): a thread pinned to every core, each
+//! accepting on its own `SO_REUSEPORT` listener, so that the kernel spreads connections over
+//! the cores and the threads share nothing but the statistics.
+
+use std::io;
+use std::net::TcpListener;
+
+use core_affinity::CoreId;
+
+use crate::endpoint;
+
+/// How a server spreads its connections over the cores.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ServerMode {
+    /// A single listener, with connections scheduled on any core (by the OS, or by the
+    /// work-stealing runtime).
+    Shared,
+    /// A pinned thread per core (`-j`), with its own listener, running its own connections.
+    ThreadPerCore,
+}
+
+/// The cores to pin `n` threads to, in turn (the cores this process may run on).
+pub fn cores(n: usize) -> anyhow::Result<Vec<CoreId>> {
+    match core_affinity::get_core_ids() {
+        Some(ids) if !ids.is_empty() => Ok(ids.into_iter().cycle().take(n).collect()),
+        _ => Err(anyhow::anyhow!(
+            "failed to list the cores to pin threads to"
+        )),
+    }
+}
+
+/// Pin the current thread to `core`. Threads it spawns afterwards inherit the pinning.
+pub fn pin(core: CoreId) -> anyhow::Result<()> {
+    if !core_affinity::set_for_current(core) {
+        return Err(anyhow::anyhow!("failed to pin thread to core {}", core.id));
+    }
+    Ok(())
+}
+
+/// `n` listeners on `addr`, which share its port through `SO_REUSEPORT`, each with room for
+/// `backlog` connections.
+pub fn listeners(addr: &str, n: usize, backlog: i32) -> io::Result<Vec<TcpListener>> {
+    (0..n)
+        .map(|_| endpoint::tcp_listener(addr, backlog, true))
+        .collect()
+}
@@ -0,0 +1,664 @@
+//! The handshake of the raw-TCP echo protocol.
+//!
+//! A client opens every connection with a fixed-size hello; all integers are big-endian:
+//!
+//! | bytes | field                                 |
+//! |-------|---------------------------------------|
+//! | 4     | magic, `ECHO`                         |
+//! | 2     | protocol version                      |
+//! | 1     | payload mode (see [`PayloadMode`])    |
+//! | 1     | reserved (zero)                       |
+//! | 4     | requested features (bit set)          |
+//! | 8     | message size, in bytes                |
+//!
+//! The message size is at most [`MAX_MESSAGE_SIZE`]. The server answers with an ack, which may reject the connection with a reason:
+//!
+//! | bytes | field                                 |
+//! |-------|---------------------------------------|
+//! | 4     | magic, `ECHO`                         |
+//! | 2     | protocol version of the server        |
+//! | 1     | status: 0 accepted, 1 rejected        |
+//! | 1     | reserved (zero)                       |
+//! | 4     | granted features (bit set)            |
+//! | 2     | length of the reason                  |
+//! | n     | reason (UTF-8), when rejected         |
+//!
+//! Once accepted, the client sends messages of the agreed size, and the server echoes them.
+//! With the [`FEATURE_FRAMED`] feature, every message is instead preceded by its length (4
+//! bytes), up to the agreed size, and the server echoes the whole frame.
+
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::payload::Message;
+
+pub const MAGIC: [u8; 4] = *b"ECHO";
+pub const VERSION: u16 = 1;
+
+pub const HELLO_LEN: usize = 20;
+pub const ACK_LEN: usize = 14;
+
+/// Per-message length framing, for messages of different sizes.
+pub const FEATURE_FRAMED: u32 = 1 << 0;
+
+/// Features a server supports; requested features outside this set are not granted.
+pub const SUPPORTED_FEATURES: u32 = FEATURE_FRAMED;
+
+/// Length of the header of a frame.
+pub const FRAME_HEADER_LEN: usize = 4;
+
+/// How much of a message the raw-TCP servers take in, at least, before they wait for the client
+/// to read the echo: clients may write messages up to that long before they read the reply,
+/// but must read while they write longer ones.
+pub const READ_AHEAD: usize = 1 << 18;
+
+/// Largest message size a server accepts, framed or not: the size is the client's to pick, and
+/// the server's buffers must not grow with it unbounded.
+pub const MAX_MESSAGE_SIZE: usize = 1 << 30;
+
+/// How long a client waits for the ack before giving up on the server.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Contents of the messages, and how the client verifies the replies (see
+/// [`crate::payload`]). Servers echo every mode the same way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PayloadMode {
+    /// Every byte is 42.
+    Constant,
+    /// The connection and message sequence number, repeated.
+    Sequence,
+    /// Pseudo-random bytes, seeded by `--payload-seed`, the connection and the message.
+    Random,
+    /// Sequence-stamped bytes, followed by their CRC32C; only the checksum is verified.
+    Crc32c,
+}
+
+impl PayloadMode {
+    fn to_u8(self) -> u8 {
+        match self {
+            PayloadMode::Constant => 0,
+            PayloadMode::Sequence => 1,
+            PayloadMode::Random => 2,
+            PayloadMode::Crc32c => 3,
+        }
+    }
+
+    fn from_u8(mode: u8) -> Option<Self> {
+        match mode {
+            0 => Some(PayloadMode::Constant),
+            1 => Some(PayloadMode::Sequence),
+            2 => Some(PayloadMode::Random),
+            3 => Some(PayloadMode::Crc32c),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PayloadMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use clap::ValueEnum;
+
+        let value = self
+            .to_possible_value()
+            .expect("payload modes are never skipped");
+        f.write_str(value.get_name())
+    }
+}
+
+/// What the client asks for.
+#[derive(Clone, Debug)]
+pub struct Hello {
+    pub version: u16,
+    pub payload: PayloadMode,
+    pub features: u32,
+    pub message_size: usize,
+}
+
+impl Hello {
+    pub fn new(message_size: usize) -> Self {
+        Hello {
+            version: VERSION,
+            payload: PayloadMode::Constant,
+            features: 0,
+            message_size,
+        }
+    }
+
+    pub fn with_payload(self, payload: PayloadMode) -> Self {
+        Hello { payload, ..self }
+    }
+
+    /// Hello for framed messages of up to `max_size` bytes.
+    pub fn framed(max_size: usize) -> Self {
+        Hello {
+            features: FEATURE_FRAMED,
+            ..Hello::new(max_size)
+        }
+    }
+
+    pub fn framed_messages(&self) -> bool {
+        self.features & FEATURE_FRAMED != 0
+    }
+
+    pub fn encode(&self) -> [u8; HELLO_LEN] {
+        let mut buf = [0; HELLO_LEN];
+        buf[0..4].copy_from_slice(&MAGIC);
+        buf[4..6].copy_from_slice(&self.version.to_be_bytes());
+        buf[6] = self.payload.to_u8();
+        buf[8..12].copy_from_slice(&self.features.to_be_bytes());
+        buf[12..20].copy_from_slice(&(self.message_size as u64).to_be_bytes());
+        buf
+    }
+
+    /// Decode and validate a hello; the error is the reason to reject it with.
+    pub fn decode(buf: &[u8; HELLO_LEN]) -> Result<Self, String> {
+        if buf[0..4] != MAGIC {
+            return Err("bad magic: not an echo client".to_string());
+        }
+        let version = u16::from_be_bytes([buf[4], buf[5]]);
+        if version != VERSION {
+            return Err(format!(
+                "unsupported protocol version {} (server speaks {})",
+                version, VERSION
+            ));
+        }
+        let payload = PayloadMode::from_u8(buf[6])
+            .ok_or_else(|| format!("unsupported payload mode {}", buf[6]))?;
+        let features = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        let message_size = u64::from_be_bytes([
+            buf[12], buf[13], buf[14], buf[15], buf[16], buf[17], buf[18], buf[19],
+        ]);
+        let message_size = match usize::try_from(message_size) {
+            Ok(0) => return Err("invalid message size: 0".to_string()),
+            Ok(size) if size <= MAX_MESSAGE_SIZE => size,
+            _ => {
+                return Err(format!(
+                    "message size too large: {} (at most {})",
+                    message_size, MAX_MESSAGE_SIZE
+                ))
+            }
+        };
+        Ok(Hello {
+            version,
+            payload,
+            features,
+            message_size,
+        })
+    }
+}
+
+/// What the server answers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Ack {
+    Accepted { features: u32 },
+    Rejected { reason: String },
+}
+
+impl Ack {
+    pub fn encode(&self) -> Vec<u8> {
+        let (status, features, reason) = match self {
+            Ack::Accepted { features } => (0, *features, ""),
+            Ack::Rejected { reason } => (1, 0, reason.as_str()),
+        };
+        let reason = &reason.as_bytes()[..reason.len().min(u16::MAX as usize)];
+
+        let mut buf = Vec::with_capacity(ACK_LEN + reason.len());
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&VERSION.to_be_bytes());
+        buf.extend_from_slice(&[status, 0]);
+        buf.extend_from_slice(&features.to_be_bytes());
+        buf.extend_from_slice(&(reason.len() as u16).to_be_bytes());
+        buf.extend_from_slice(reason);
+        buf
+    }
+
+    /// Decode the fixed-size part of an ack; returns the status (with an empty reason) and
+    /// the length of the reason that follows.
+    fn decode_header(buf: &[u8; ACK_LEN]) -> anyhow::Result<(Ack, usize)> {
+        if buf[0..4] != MAGIC {
+            return Err(anyhow::anyhow!(
+                "bad magic in the handshake reply: not an echo server, or one that predates \
+                 the handshake"
+            ));
+        }
+        let features = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        let reason_len = u16::from_be_bytes([buf[12], buf[13]]) as usize;
+        let ack = match buf[6] {
+            0 => Ack::Accepted { features },
+            1 => Ack::Rejected {
+                reason: String::new(),
+            },
+            status => return Err(anyhow::anyhow!("invalid handshake status {}", status)),
+        };
+        Ok((ack, reason_len))
+    }
+}
+
+/// Answer a decoded (or undecodable) hello.
+pub fn negotiate(hello: &Result<Hello, String>) -> Ack {
+    match hello {
+        Ok(hello) => Ack::Accepted {
+            features: hello.features & SUPPORTED_FEATURES,
+        },
+        Err(reason) => Ack::Rejected {
+            reason: reason.clone(),
+        },
+    }
+}
+
+/// Turn an ack into the features granted, or an error with the server's reason.
+fn accepted(ack: Ack) -> anyhow::Result<u32> {
+    match ack {
+        Ack::Accepted { features } => Ok(features),
+        Ack::Rejected { reason } => Err(anyhow::anyhow!("server rejected handshake: {}", reason)),
+    }
+}
+
+/// Check that the server granted every requested feature.
+pub fn require(hello: &Hello, granted: u32) -> anyhow::Result<()> {
+    let missing = hello.features & !granted;
+    if missing != 0 {
+        return Err(anyhow::anyhow!(
+            "server does not support the requested features {:#x}",
+            missing
+        ));
+    }
+    Ok(())
+}
+
+/// Length of a frame, from its header.
+pub fn frame_len(header: [u8; FRAME_HEADER_LEN]) -> usize {
+    u32::from_be_bytes(header) as usize
+}
+
+/// Length of a frame received by a server, which must be within the size agreed in the
+/// handshake.
+pub fn frame_size(header: [u8; FRAME_HEADER_LEN], max_size: usize) -> anyhow::Result<usize> {
+    let size = frame_len(header);
+    if size == 0 || size > max_size {
+        return Err(anyhow::anyhow!(
+            "invalid frame of {} bytes (expected 1 to {})",
+            size,
+            max_size
+        ));
+    }
+    Ok(size)
+}
+
+/// Header of a frame of `len` bytes.
+pub fn frame_header(len: usize) -> [u8; FRAME_HEADER_LEN] {
+    (len as u32).to_be_bytes()
+}
+
+/// Check the header of a frame echoed back to a client, which sent `size` bytes.
+pub fn check_frame(header: [u8; FRAME_HEADER_LEN], size: usize) -> anyhow::Result<()> {
+    let len = frame_len(header);
+    if len != size {
+        return Err(anyhow::anyhow!(
+            "mismatched reply: frame of {} bytes, sent {}",
+            len,
+            size
+        ));
+    }
+    Ok(())
+}
+
+/// Everything a client sends for `message`, preceded by its header if `framed`.
+pub fn encode(mut message: Message, framed: bool) -> Vec<u8> {
+    let header_len = if framed { FRAME_HEADER_LEN } else { 0 };
+    let mut buf = vec![0; header_len + message.size()];
+    if framed {
+        buf[..FRAME_HEADER_LEN].copy_from_slice(&frame_header(message.size()));
+    }
+    message.fill(&mut buf[header_len..]);
+    buf
+}
+
+/// Client side of the handshake: returns the features granted by the server.
+pub fn connect<S: Read + Write>(stream: &mut S, hello: &Hello) -> anyhow::Result<u32> {
+    stream
+        .write_all(&hello.encode())
+        .context("failed to send handshake")?;
+
+    let mut header = [0; ACK_LEN];
+    stream
+        .read_exact(&mut header)
+        .context("no handshake reply from the server")?;
+    let (mut ack, reason_len) = Ack::decode_header(&header)?;
+    if let Ack::Rejected { reason } = &mut ack {
+        let mut buf = vec![0; reason_len];
+        stream
+            .read_exact(&mut buf)
+            .context("truncated handshake reply")?;
+        *reason = String::from_utf8_lossy(&buf).into_owned();
+    }
+    accepted(ack)
+}
+
+/// Server side of the handshake: returns the accepted hello (with the features granted), or
+/// an error once the client has been told why it was rejected.
+pub fn accept<S: Read + Write>(stream: &mut S) -> anyhow::Result<Hello> {
+    let mut buf = [0; HELLO_LEN];
+    stream
+        .read_exact(&mut buf)
+        .context("failed to read handshake")?;
+    let hello = Hello::decode(&buf);
+    stream
+        .write_all(&negotiate(&hello).encode())
+        .context("failed to answer handshake")?;
+    hello
+        .map(|hello| Hello {
+            features: hello.features & SUPPORTED_FEATURES,
+            ..hello
+        })
+        .map_err(|reason| anyhow::anyhow!("rejected handshake: {}", reason))
+}
+
+/// Async version of [`connect`].
+#[cfg(feature = "tokio")]
+pub async fn connect_async<S>(stream: &mut S, hello: &Hello) -> anyhow::Result<u32>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    stream
+        .write_all(&hello.encode())
+        .await
+        .context("failed to send handshake")?;
+
+    let mut header = [0; ACK_LEN];
+    stream
+        .read_exact(&mut header)
+        .await
+        .context("no handshake reply from the server")?;
+    let (mut ack, reason_len) = Ack::decode_header(&header)?;
+    if let Ack::Rejected { reason } = &mut ack {
+        let mut buf = vec![0; reason_len];
+        stream
+            .read_exact(&mut buf)
+            .await
+            .context("truncated handshake reply")?;
+        *reason = String::from_utf8_lossy(&buf).into_owned();
+    }
+    accepted(ack)
+}
+
+/// Async version of [`accept`].
+#[cfg(feature = "tokio")]
+pub async fn accept_async<S>(stream: &mut S) -> anyhow::Result<Hello>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut buf = [0; HELLO_LEN];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .context("failed to read handshake")?;
+    let hello = Hello::decode(&buf);
+    stream
+        .write_all(&negotiate(&hello).encode())
+        .await
+        .context("failed to answer handshake")?;
+    hello
+        .map(|hello| Hello {
+            features: hello.features & SUPPORTED_FEATURES,
+            ..hello
+        })
+        .map_err(|reason| anyhow::anyhow!("rejected handshake: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::Payload;
+    use std::io;
+
+    /// A stream that reads `input`, and keeps what is written.
+    struct Duplex {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Duplex {
+        fn new(input: Vec<u8>) -> Self {
+            Duplex {
+                input: io::Cursor::new(input),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const MODES: [PayloadMode; 4] = [
+        PayloadMode::Constant,
+        PayloadMode::Sequence,
+        PayloadMode::Random,
+        PayloadMode::Crc32c,
+    ];
+
+    #[test]
+    fn hello_layout() {
+        let hello = Hello::framed(0x0102_0304).with_payload(PayloadMode::Random);
+        assert_eq!(
+            hello.encode(),
+            [b'E', b'C', b'H', b'O', 0, 1, 2, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        for payload in MODES {
+            for hello in [Hello::new(1), Hello::framed(MAX_MESSAGE_SIZE)] {
+                let hello = hello.with_payload(payload);
+                let decoded = Hello::decode(&hello.encode()).unwrap();
+                assert_eq!(decoded.version, VERSION);
+                assert_eq!(decoded.payload, payload);
+                assert_eq!(decoded.features, hello.features);
+                assert_eq!(decoded.message_size, hello.message_size);
+            }
+        }
+    }
+
+    #[test]
+    fn hello_rejections() {
+        let reject = |edit: fn(&mut [u8; HELLO_LEN])| {
+            let mut buf = Hello::new(16).encode();
+            edit(&mut buf);
+            Hello::decode(&buf).unwrap_err()
+        };
+        assert_eq!(reject(|buf| buf[0] = b'X'), "bad magic: not an echo client");
+        assert_eq!(
+            reject(|buf| buf[5] = 2),
+            "unsupported protocol version 2 (server speaks 1)"
+        );
+        assert_eq!(reject(|buf| buf[6] = 4), "unsupported payload mode 4");
+        assert_eq!(
+            reject(|buf| buf[12..].copy_from_slice(&0u64.to_be_bytes())),
+            "invalid message size: 0"
+        );
+        // framed or not
+        for hello in [
+            Hello::new(MAX_MESSAGE_SIZE + 1),
+            Hello::framed(u64::MAX as usize),
+        ] {
+            assert_eq!(
+                Hello::decode(&hello.encode()).unwrap_err(),
+                format!(
+                    "message size too large: {} (at most 1073741824)",
+                    hello.message_size
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn ack_round_trip() {
+        for ack in [
+            Ack::Accepted {
+                features: FEATURE_FRAMED,
+            },
+            Ack::Rejected {
+                reason: "no thanks".to_string(),
+            },
+        ] {
+            let buf = ack.encode();
+            let (decoded, reason_len) =
+                Ack::decode_header(buf[..ACK_LEN].try_into().unwrap()).unwrap();
+            assert_eq!(reason_len, buf.len() - ACK_LEN);
+            match (decoded, &ack) {
+                (Ack::Accepted { features }, Ack::Accepted { features: expected }) => {
+                    assert_eq!(features, *expected)
+                }
+                (Ack::Rejected { .. }, Ack::Rejected { reason }) => {
+                    assert_eq!(&buf[ACK_LEN..], reason.as_bytes())
+                }
+                (decoded, _) => panic!("decoded {:?} from {:?}", decoded, ack),
+            }
+        }
+    }
+
+    #[test]
+    fn ack_rejections() {
+        let mut buf: [u8; ACK_LEN] = Ack::Accepted { features: 0 }.encode()[..]
+            .try_into()
+            .unwrap();
+        buf[6] = 2;
+        assert_eq!(
+            Ack::decode_header(&buf).unwrap_err().to_string(),
+            "invalid handshake status 2"
+        );
+        buf[0] = b'X';
+        assert!(Ack::decode_header(&buf)
+            .unwrap_err()
+            .to_string()
+            .starts_with("bad magic"));
+    }
+
+    #[test]
+    fn negotiate_grants_supported_features() {
+        let hello = Hello {
+            features: FEATURE_FRAMED | 1 << 7,
+            ..Hello::new(16)
+        };
+        assert_eq!(
+            negotiate(&Ok(hello.clone())),
+            Ack::Accepted {
+                features: FEATURE_FRAMED
+            }
+        );
+        assert_eq!(
+            require(&hello, FEATURE_FRAMED).unwrap_err().to_string(),
+            "server does not support the requested features 0x80"
+        );
+        assert!(require(&hello, FEATURE_FRAMED | 1 << 7).is_ok());
+    }
+
+    #[test]
+    fn handshake() {
+        let hello = Hello::framed(64).with_payload(PayloadMode::Crc32c);
+        let mut server = Duplex::new(hello.encode().to_vec());
+        let accepted = accept(&mut server).unwrap();
+        assert_eq!(accepted.message_size, 64);
+        assert_eq!(accepted.payload, PayloadMode::Crc32c);
+        assert!(accepted.framed_messages());
+
+        let mut client = Duplex::new(server.output);
+        assert_eq!(connect(&mut client, &hello).unwrap(), FEATURE_FRAMED);
+        assert_eq!(client.output, hello.encode());
+    }
+
+    #[test]
+    fn rejected_handshake() {
+        let mut hello = Hello::new(16).encode();
+        hello[4] = 9;
+        let mut server = Duplex::new(hello.to_vec());
+        assert_eq!(
+            accept(&mut server).unwrap_err().to_string(),
+            "rejected handshake: unsupported protocol version 2305 (server speaks 1)"
+        );
+
+        // the client is told why
+        let mut client = Duplex::new(server.output);
+        assert_eq!(
+            connect(&mut client, &Hello::new(16))
+                .unwrap_err()
+                .to_string(),
+            "server rejected handshake: unsupported protocol version 2305 (server speaks 1)"
+        );
+    }
+
+    #[test]
+    fn oversized_messages() {
+        let hello = Hello::new(MAX_MESSAGE_SIZE + 1);
+        let mut server = Duplex::new(hello.encode().to_vec());
+        assert!(accept(&mut server).is_err());
+        let mut client = Duplex::new(server.output);
+        assert_eq!(
+            connect(&mut client, &hello).unwrap_err().to_string(),
+            "server rejected handshake: message size too large: 1073741825 (at most 1073741824)"
+        );
+    }
+
+    #[test]
+    fn truncated_handshakes() {
+        assert!(accept(&mut Duplex::new(vec![0; HELLO_LEN - 1])).is_err());
+        let ack = Ack::Rejected {
+            reason: "why".to_string(),
+        }
+        .encode();
+        let mut client = Duplex::new(ack[..ack.len() - 1].to_vec());
+        assert_eq!(
+            connect(&mut client, &Hello::new(16))
+                .unwrap_err()
+                .to_string(),
+            "truncated handshake reply"
+        );
+    }
+
+    #[test]
+    fn frame_bounds() {
+        assert_eq!(frame_len(frame_header(0x0102_0304)), 0x0102_0304);
+        assert_eq!(frame_size(frame_header(1), 16).unwrap(), 1);
+        assert_eq!(frame_size(frame_header(16), 16).unwrap(), 16);
+        assert_eq!(
+            frame_size(frame_header(0), 16).unwrap_err().to_string(),
+            "invalid frame of 0 bytes (expected 1 to 16)"
+        );
+        assert_eq!(
+            frame_size(frame_header(17), 16).unwrap_err().to_string(),
+            "invalid frame of 17 bytes (expected 1 to 16)"
+        );
+        assert!(check_frame(frame_header(16), 16).is_ok());
+        assert_eq!(
+            check_frame(frame_header(15), 16).unwrap_err().to_string(),
+            "mismatched reply: frame of 15 bytes, sent 16"
+        );
+    }
+
+    #[test]
+    fn encoded_messages() {
+        let mut payload = Payload::new(PayloadMode::Constant, 0, 0);
+        assert_eq!(encode(payload.next(3), false), [42, 42, 42]);
+        assert_eq!(encode(payload.next(3), true), [0, 0, 0, 3, 42, 42, 42]);
+    }
+}
@@ -0,0 +1,136 @@
+//! Send schedules of the open-loop and controlled bursty clients.
+
+use std::time::{Duration, Instant};
+
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+use rand_distr::{Distribution, Exp};
+
+/// Poisson arrival process: an infinite sequence of send instants with exponentially
+/// distributed gaps, averaging `rate` requests per second.
+pub struct Arrivals {
+    next: Instant,
+    gap: Exp<f64>,
+    rng: SmallRng,
+}
+
+impl Arrivals {
+    pub fn new(rate: f64) -> anyhow::Result<Self> {
+        // a zero rate would schedule the second request at infinity
+        if !rate.is_finite() || rate <= 0.0 {
+            return Err(anyhow::anyhow!("invalid rate {}: must be positive", rate));
+        }
+        Ok(Arrivals {
+            next: Instant::now(),
+            gap: Exp::new(rate).map_err(|e| anyhow::anyhow!("invalid rate {}: {:?}", rate, e))?,
+            rng: SmallRng::from_entropy(),
+        })
+    }
+}
+
+impl Iterator for Arrivals {
+    type Item = Instant;
+
+    fn next(&mut self) -> Option<Instant> {
+        let scheduled = self.next;
+        self.next += Duration::from_secs_f64(self.gap.sample(&mut self.rng));
+        Some(scheduled)
+    }
+}
+
+/// Start instants of the bursts of the controlled bursty clients, one every `period`.
+///
+/// A burst that overruns the period delays the next one, which starts as soon as the previous
+/// one is over; the bursts after it are back on the original schedule. The bursts missed in
+/// between are skipped, and counted, rather than sent back to back to catch up (which would
+/// offer a different load than asked for) or shifting every later burst (which would hide the
+/// overrun).
+#[cfg(feature = "tokio")]
+pub struct Bursts {
+    interval: tokio::time::Interval,
+    period: Duration,
+    /// When the next burst is due, if the previous one did not overrun.
+    due: Option<tokio::time::Instant>,
+    skipped: u64,
+}
+
+#[cfg(feature = "tokio")]
+impl Bursts {
+    pub fn new(period: Duration) -> Self {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        Bursts {
+            interval,
+            period,
+            due: None,
+            skipped: 0,
+        }
+    }
+
+    /// Wait for the start of the next burst.
+    pub async fn tick(&mut self) {
+        // the instant it was due, whether late or not
+        let tick = self.interval.tick().await;
+        if let Some(due) = self.due {
+            self.skipped +=
+                (tick.saturating_duration_since(due).as_nanos() / self.period.as_nanos()) as u64;
+        }
+        self.due = Some(tick + self.period);
+    }
+
+    /// Bursts skipped so far, as earlier ones overran the period.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arrivals_average_the_rate() {
+        let arrivals = Arrivals::new(1_000.0).unwrap();
+        let first = arrivals.next;
+        let last = arrivals.take(100_001).last().unwrap();
+        // 100k gaps of 1ms on average, with a relative standard error of 0.3%
+        let elapsed = (last - first).as_secs_f64();
+        assert!((elapsed - 100.0).abs() < 2.0, "{}", elapsed);
+    }
+
+    #[test]
+    fn arrivals_are_ordered() {
+        let arrivals = Arrivals::new(1e6).unwrap().take(1_000).collect::<Vec<_>>();
+        assert!(arrivals.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn invalid_rates() {
+        for rate in [0.0, -1.0, f64::NAN] {
+            assert!(Arrivals::new(rate).is_err(), "{}", rate);
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn bursts_skip_the_periods_they_overrun() {
+        use tokio::time::{sleep, Instant};
+
+        let period = Duration::from_millis(50);
+        let mut bursts = Bursts::new(period);
+        let start = Instant::now();
+        bursts.tick().await;
+        bursts.tick().await;
+        assert!(start.elapsed() >= period);
+        assert_eq!(bursts.skipped(), 0);
+
+        // overruns the burst due at 100ms, which starts right away, and the one at 150ms
+        sleep(Duration::from_millis(120)).await;
+        let late = Instant::now();
+        bursts.tick().await;
+        assert!(late.elapsed() < Duration::from_millis(20));
+        bursts.tick().await;
+        assert!(start.elapsed() >= 4 * period);
+        assert_eq!(bursts.skipped(), 1);
+    }
+}
@@ -0,0 +1,87 @@
+//! Graceful shutdown of the servers on SIGINT/SIGTERM.
+//!
+//! On a signal, a server stops accepting connections, lets every connection finish the
+//! message it is echoing (up to `--drain-timeout`), dumps its statistics and exits: with
+//! success if every connection was drained, with an error otherwise.
+
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use crate::ServerStats;
+
+/// How often the drain checks for the remaining connections.
+const DRAIN_POLL: Duration = Duration::from_millis(10);
+
+/// A SIGINT or SIGTERM, for threads that block (self-pipe trick).
+pub struct Signal(UnixStream);
+
+impl Signal {
+    /// Start listening for SIGINT and SIGTERM, which no longer terminate the process.
+    pub fn new() -> io::Result<Self> {
+        let (reader, writer) = UnixStream::pair()?;
+        writer.set_nonblocking(true)?;
+        let writer = std::sync::Arc::new(writer);
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            let writer = writer.clone();
+            // SAFETY: the action only calls write(2), which is async-signal-safe. A full
+            // pipe (signals nobody waits on) is fine to ignore.
+            unsafe {
+                signal_hook_registry::register(signal, move || {
+                    let _ = (&*writer).write(&[0]);
+                })?;
+            }
+        }
+        Ok(Signal(reader))
+    }
+
+    /// Block until a signal arrives.
+    pub fn wait(mut self) -> io::Result<()> {
+        self.0.read_exact(&mut [0])
+    }
+}
+
+/// Wait for a SIGINT or SIGTERM.
+#[cfg(feature = "tokio")]
+pub async fn signal() -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = interrupt.recv() => {}
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
+
+/// Wait for the active connections to close, up to `timeout`; returns how many are left.
+pub fn drain(stats: &ServerStats, timeout: Duration) -> u64 {
+    let deadline = Instant::now() + timeout;
+    while stats.active() > 0 && Instant::now() < deadline {
+        std::thread::sleep(DRAIN_POLL);
+    }
+    stats.active()
+}
+
+/// Async version of [`drain`].
+#[cfg(feature = "tokio")]
+pub async fn drain_async(stats: &ServerStats, timeout: Duration) -> u64 {
+    let deadline = tokio::time::Instant::now() + timeout;
+    while stats.active() > 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(DRAIN_POLL).await;
+    }
+    stats.active()
+}
+
+/// Print the final statistics, and fail if `left` connections could not be drained.
+pub fn finish(stats: &ServerStats, left: u64) -> anyhow::Result<()> {
+    stats.dump();
+    if left > 0 {
+        return Err(anyhow::anyhow!(
+            "{} connection(s) still active after the drain timeout",
+            left
+        ));
+    }
+    Ok(())
+}
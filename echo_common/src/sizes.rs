@@ -0,0 +1,437 @@
+//! Message size distributions (`--size-dist`).
+//!
+//! Sizes are drawn independently for every message, rounded to whole bytes and clamped to
+//! `[1, max]`, where `max` is the largest size the distribution can produce (the mean plus
+//! six standard deviations for the normal and lognormal distributions).
+
+use std::fmt;
+use std::fs;
+
+use anyhow::Context;
+use rand::distributions::{Uniform, WeightedIndex};
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+use rand_distr::{Distribution, LogNormal, Normal, Zipf};
+
+use crate::cli::size_parser;
+use crate::protocol::MAX_MESSAGE_SIZE;
+
+/// Largest message size the servers accept.
+pub const MAX_SIZE: usize = MAX_MESSAGE_SIZE;
+
+/// Terms of the Zipf normalization summed one by one, before the tail is approximated.
+const EXACT_TERMS: usize = 10_000;
+
+#[derive(Clone, Debug)]
+pub enum SizeDist {
+    /// `fixed:SIZE`
+    Fixed(usize),
+    /// `uniform:MIN,MAX`
+    Uniform { min: usize, max: usize },
+    /// `normal:MEAN,STDDEV`, in bytes.
+    Normal { mean: f64, stddev: f64 },
+    /// `lognormal:MU,SIGMA`: the natural logarithm of the size is normal.
+    LogNormal { mu: f64, sigma: f64 },
+    /// `zipf:N,S`: size `k` in `1..=N` with a probability proportional to `1/k^S`.
+    Zipf { n: usize, s: f64 },
+    /// `file:PATH`: an empirical histogram, with a `SIZE WEIGHT` pair per line.
+    Empirical {
+        path: String,
+        sizes: Vec<usize>,
+        weights: Vec<f64>,
+    },
+}
+
+/// Parse a `--size-dist` specification.
+pub fn size_dist_parser(s: &str) -> anyhow::Result<SizeDist> {
+    let (kind, params) = s.split_once(':').unwrap_or((s, ""));
+    let params = params.split(',').map(str::trim).collect::<Vec<_>>();
+    let float = |s: &str| {
+        s.parse::<f64>()
+            .map_err(|e| anyhow::anyhow!("failed to parse {}: {:?}", s, e))
+    };
+    let expect = |n: usize| {
+        if params.len() == n && params.iter().all(|p| !p.is_empty()) {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "{} takes {} parameter(s), got `{}`",
+                kind,
+                n,
+                s
+            ))
+        }
+    };
+
+    let dist = match kind {
+        "fixed" => {
+            expect(1)?;
+            SizeDist::Fixed(size_parser(params[0])?)
+        }
+        "uniform" => {
+            expect(2)?;
+            SizeDist::Uniform {
+                min: size_parser(params[0])?,
+                max: size_parser(params[1])?,
+            }
+        }
+        "normal" => {
+            expect(2)?;
+            SizeDist::Normal {
+                mean: size_parser(params[0])? as f64,
+                stddev: size_parser(params[1])? as f64,
+            }
+        }
+        "lognormal" => {
+            expect(2)?;
+            SizeDist::LogNormal {
+                mu: float(params[0])?,
+                sigma: float(params[1])?,
+            }
+        }
+        "zipf" => {
+            expect(2)?;
+            SizeDist::Zipf {
+                n: size_parser(params[0])?,
+                s: float(params[1])?,
+            }
+        }
+        "file" => {
+            let path = s["file:".len()..].to_string();
+            let (sizes, weights) = read_histogram(&path)?;
+            SizeDist::Empirical {
+                path,
+                sizes,
+                weights,
+            }
+        }
+        _ => {
+            return Err(anyhow::anyhow!(
+                "unknown size distribution `{}` (expected fixed, uniform, normal, lognormal, \
+                 zipf or file)",
+                kind
+            ))
+        }
+    };
+
+    if dist.max() == 0 || dist.max() > MAX_SIZE {
+        return Err(anyhow::anyhow!(
+            "sizes of {} must be between 1 and {} bytes",
+            s,
+            MAX_SIZE
+        ));
+    }
+    // validate the parameters once, rather than in every worker
+    dist.sampler()?;
+    Ok(dist)
+}
+
+fn read_histogram(path: &str) -> anyhow::Result<(Vec<usize>, Vec<f64>)> {
+    let contents = fs::read_to_string(path).with_context(|| format!("failed to read {}", path))?;
+    let mut sizes = Vec::new();
+    let mut weights = Vec::new();
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let context = || format!("{}:{}: expected `SIZE WEIGHT`: {}", path, lineno + 1, line);
+        let mut fields = line.split_whitespace();
+        let (Some(size), Some(weight), None) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(anyhow::anyhow!(context()));
+        };
+        sizes.push(size_parser(size).with_context(context)?);
+        weights.push(weight.parse::<f64>().with_context(context)?);
+    }
+    if sizes.is_empty() {
+        return Err(anyhow::anyhow!("{}: empty histogram", path));
+    }
+    Ok((sizes, weights))
+}
+
+impl SizeDist {
+    /// Largest size drawn.
+    pub fn max(&self) -> usize {
+        let bound = |x: f64| x.ceil().clamp(1.0, MAX_SIZE as f64) as usize;
+        match self {
+            SizeDist::Fixed(size) => *size,
+            SizeDist::Uniform { max, .. } => *max,
+            SizeDist::Normal { mean, stddev } => bound(mean + 6.0 * stddev),
+            SizeDist::LogNormal { mu, sigma } => bound((mu + 6.0 * sigma).exp()),
+            SizeDist::Zipf { n, .. } => *n,
+            SizeDist::Empirical { sizes, .. } => sizes.iter().copied().max().unwrap_or(0),
+        }
+    }
+
+    /// Expected size of the messages, clamped to `[1, max]` (but not rounded).
+    pub fn mean(&self) -> f64 {
+        let (low, high) = (1.0, self.max() as f64);
+        match self {
+            SizeDist::Fixed(size) => *size as f64,
+            SizeDist::Uniform { min, max } => (*min + *max) as f64 / 2.0,
+            SizeDist::Normal { mean, stddev } => {
+                let (a, b) = ((low - mean) / stddev, (high - mean) / stddev);
+                let pdf = |x: f64| (-x * x / 2.0).exp() / (2.0 * std::f64::consts::PI).sqrt();
+                low * normal_cdf(a)
+                    + high * (1.0 - normal_cdf(b))
+                    + mean * (normal_cdf(b) - normal_cdf(a))
+                    + stddev * (pdf(a) - pdf(b))
+            }
+            SizeDist::LogNormal { mu, sigma } => {
+                // the sizes between the bounds contribute a partial expectation
+                let (a, b) = ((low.ln() - mu) / sigma, (high.ln() - mu) / sigma);
+                low * normal_cdf(a)
+                    + high * (1.0 - normal_cdf(b))
+                    + (mu + sigma * sigma / 2.0).exp()
+                        * (normal_cdf(b - sigma) - normal_cdf(a - sigma))
+            }
+            SizeDist::Zipf { n, s } => harmonic(*n, s - 1.0) / harmonic(*n, *s),
+            SizeDist::Empirical { sizes, weights, .. } => {
+                let total = weights.iter().sum::<f64>();
+                sizes
+                    .iter()
+                    .zip(weights)
+                    .map(|(size, weight)| (*size as f64).clamp(low, high) * weight)
+                    .sum::<f64>()
+                    / total
+            }
+        }
+    }
+
+    /// A new, independently seeded, stream of sizes.
+    pub fn sampler(&self) -> anyhow::Result<Sizes> {
+        let invalid =
+            |e: &dyn fmt::Debug| anyhow::anyhow!("invalid size distribution {}: {:?}", self, e);
+        let sampler = match self {
+            SizeDist::Fixed(size) => Sampler::Fixed(*size),
+            SizeDist::Uniform { min, max } => {
+                if min == &0 || min > max {
+                    return Err(invalid(&"expected 1 <= MIN <= MAX"));
+                }
+                Sampler::Uniform(Uniform::new_inclusive(*min, *max))
+            }
+            SizeDist::Normal { mean, stddev } => {
+                // `fixed:` is the way to send a single size
+                if *stddev <= 0.0 {
+                    return Err(invalid(&"expected STDDEV > 0"));
+                }
+                Sampler::Normal(Normal::new(*mean, *stddev).map_err(|e| invalid(&e))?)
+            }
+            SizeDist::LogNormal { mu, sigma } => {
+                if *sigma <= 0.0 {
+                    return Err(invalid(&"expected SIGMA > 0"));
+                }
+                Sampler::LogNormal(LogNormal::new(*mu, *sigma).map_err(|e| invalid(&e))?)
+            }
+            SizeDist::Zipf { n, s } => {
+                Sampler::Zipf(Zipf::new(*n as u64, *s).map_err(|e| invalid(&e))?)
+            }
+            SizeDist::Empirical { sizes, weights, .. } => Sampler::Empirical(
+                WeightedIndex::new(weights).map_err(|e| invalid(&e))?,
+                sizes.clone(),
+            ),
+        };
+        Ok(Sizes {
+            sampler,
+            max: self.max(),
+            rng: SmallRng::from_entropy(),
+        })
+    }
+}
+
+fn normal_cdf(x: f64) -> f64 {
+    libm::erfc(-x / std::f64::consts::SQRT_2) / 2.0
+}
+
+/// `sum(k^-t for k in 1..=n)`: the first `EXACT_TERMS` terms are summed, and the rest are
+/// approximated (Euler-Maclaurin), so that it takes constant time however large `n` is.
+fn harmonic(n: usize, t: f64) -> f64 {
+    let exact = n.min(EXACT_TERMS);
+    let head = (1..=exact).map(|k| (k as f64).powf(-t)).sum::<f64>();
+    if exact == n {
+        return head;
+    }
+    let (a, b) = ((exact + 1) as f64, n as f64);
+    let f = |x: f64| x.powf(-t);
+    let derivative = |x: f64| -t * x.powf(-t - 1.0);
+    let integral = if t == 1.0 {
+        (b / a).ln()
+    } else {
+        (b.powf(1.0 - t) - a.powf(1.0 - t)) / (1.0 - t)
+    };
+    head + integral + (f(a) + f(b)) / 2.0 + (derivative(b) - derivative(a)) / 12.0
+}
+
+impl fmt::Display for SizeDist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SizeDist::Fixed(size) => write!(f, "fixed:{}", size),
+            SizeDist::Uniform { min, max } => write!(f, "uniform:{},{}", min, max),
+            SizeDist::Normal { mean, stddev } => write!(f, "normal:{},{}", mean, stddev),
+            SizeDist::LogNormal { mu, sigma } => write!(f, "lognormal:{},{}", mu, sigma),
+            SizeDist::Zipf { n, s } => write!(f, "zipf:{},{}", n, s),
+            SizeDist::Empirical { path, .. } => write!(f, "file:{}", path),
+        }
+    }
+}
+
+enum Sampler {
+    Fixed(usize),
+    Uniform(Uniform<usize>),
+    Normal(Normal<f64>),
+    LogNormal(LogNormal<f64>),
+    Zipf(Zipf<f64>),
+    Empirical(WeightedIndex<f64>, Vec<usize>),
+}
+
+/// An infinite stream of message sizes.
+pub struct Sizes {
+    sampler: Sampler,
+    max: usize,
+    rng: SmallRng,
+}
+
+impl Iterator for Sizes {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let rng = &mut self.rng;
+        let size = match &self.sampler {
+            Sampler::Fixed(size) => *size,
+            Sampler::Uniform(uniform) => uniform.sample(rng),
+            Sampler::Normal(normal) => normal.sample(rng).round().max(0.0) as usize,
+            Sampler::LogNormal(lognormal) => lognormal.sample(rng).round().max(0.0) as usize,
+            Sampler::Zipf(zipf) => zipf.sample(rng) as usize,
+            Sampler::Empirical(index, sizes) => sizes[index.sample(rng)],
+        };
+        Some(size.clamp(1, self.max))
+    }
+}
+
+/// Size bucket (the next power of two) that results are broken down by.
+pub fn bucket(size: usize) -> usize {
+    size.next_power_of_two()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= expected.abs() * tolerance,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn parses_every_distribution() {
+        let parse = |s| size_dist_parser(s).unwrap().to_string();
+        assert_eq!(parse("fixed:1KiB"), "fixed:1024");
+        assert_eq!(parse("uniform:1, 4k"), "uniform:1,4096");
+        assert_eq!(parse("normal:1000,100"), "normal:1000,100");
+        assert_eq!(parse("lognormal:7,0.5"), "lognormal:7,0.5");
+        assert_eq!(parse("zipf:1000,1.1"), "zipf:1000,1.1");
+
+        let path = std::env::temp_dir().join(format!("sizes-{}.txt", std::process::id()));
+        fs::write(&path, "# size weight\n64 3\n\n1KiB 1\n").unwrap();
+        let dist = size_dist_parser(&format!("file:{}", path.display())).unwrap();
+        fs::remove_file(&path).unwrap();
+        let SizeDist::Empirical { sizes, weights, .. } = &dist else {
+            panic!("{:?}", dist);
+        };
+        assert_eq!(sizes, &[64, 1024]);
+        assert_eq!(weights, &[3.0, 1.0]);
+        assert_eq!(dist.max(), 1024);
+    }
+
+    #[test]
+    fn rejects_invalid_distributions() {
+        for (spec, error) in [
+            ("gamma:1,2", "unknown size distribution `gamma`"),
+            ("uniform:1", "uniform takes 2 parameter(s)"),
+            ("fixed:", "fixed takes 1 parameter(s)"),
+            ("fixed:0", "sizes of fixed:0 must be between 1 and"),
+            ("fixed:2GiB", "sizes of fixed:2GiB must be between 1 and"),
+            ("uniform:8,4", "invalid size distribution uniform:8,4"),
+            ("normal:1000,0", "invalid size distribution normal:1000,0"),
+            ("lognormal:7,0", "invalid size distribution lognormal:7,0"),
+            ("zipf:100,-1", "invalid size distribution zipf:100,-1"),
+            ("file:/nonexistent", "failed to read /nonexistent"),
+        ] {
+            let e = size_dist_parser(spec).unwrap_err().to_string();
+            assert!(e.starts_with(error), "{}: {}", spec, e);
+        }
+    }
+
+    #[test]
+    fn samples_are_clamped() {
+        let dist = size_dist_parser("normal:10,100").unwrap();
+        assert_eq!(dist.max(), 610);
+        let sizes = dist.sampler().unwrap().take(10_000).collect::<Vec<_>>();
+        assert!(sizes.iter().all(|size| (1..=610).contains(size)));
+        // most of the draws are below 1
+        assert!(sizes.iter().filter(|size| **size == 1).count() > 4_000);
+
+        let dist = SizeDist::Empirical {
+            path: "test".to_string(),
+            sizes: vec![0, 8],
+            weights: vec![1.0, 1.0],
+        };
+        assert!(dist.sampler().unwrap().take(100).all(|size| size >= 1));
+    }
+
+    #[test]
+    fn means() {
+        let mean = |s| size_dist_parser(s).unwrap().mean();
+        assert_eq!(mean("fixed:100"), 100.0);
+        assert_eq!(mean("uniform:1,100"), 50.5);
+        // far from the bounds, the clamping does not matter
+        assert_close(mean("normal:1000,100"), 1000.0, 1e-9);
+        assert_close(mean("lognormal:5,0.5"), (5.0f64 + 0.125).exp(), 1e-6);
+        // of sizes 1 to 4, with probabilities 12/25, 6/25, 4/25 and 3/25
+        assert_close(mean("zipf:4,1"), 48.0 / 25.0, 1e-12);
+    }
+
+    #[test]
+    fn means_account_for_the_clamping() {
+        let dist = size_dist_parser("normal:10,100").unwrap();
+        let sampled = dist.sampler().unwrap().take(1_000_000).sum::<usize>() as f64 / 1e6;
+        // up to the rounding of the draws, and their spread (a standard error under 0.2)
+        assert_close(dist.mean(), sampled, 0.05);
+        assert!(dist.mean() > 40.0, "{}", dist.mean());
+
+        let dist = size_dist_parser("lognormal:0,2").unwrap();
+        let sampled = dist.sampler().unwrap().take(1_000_000).sum::<usize>() as f64 / 1e6;
+        assert_close(dist.mean(), sampled, 0.05);
+
+        let dist = SizeDist::Empirical {
+            path: "test".to_string(),
+            sizes: vec![0, 8],
+            weights: vec![1.0, 1.0],
+        };
+        assert_eq!(dist.mean(), 4.5);
+    }
+
+    #[test]
+    fn zipf_means_of_large_supports() {
+        for s in [0.0, 0.5, 1.0, 1.5, 2.0] {
+            let n = 100_000;
+            let (weighted, total) = (1..=n).fold((0.0, 0.0), |(weighted, total), k| {
+                let p = (k as f64).powf(-s);
+                (weighted + k as f64 * p, total + p)
+            });
+            let dist = SizeDist::Zipf { n, s };
+            assert_close(dist.mean(), weighted / total, 1e-9);
+        }
+        // in constant time
+        let mean = SizeDist::Zipf {
+            n: MAX_SIZE,
+            s: 1.0,
+        }
+        .mean();
+        assert!(mean.is_finite() && mean > 1.0, "{}", mean);
+    }
+}
@@ -0,0 +1,200 @@
+//! Socket options of the servers and clients (`--nodelay`, `--sndbuf`, ...), set on every
+//! connection once it is accepted or connected.
+//!
+//! Options left out are left to the implementation (i.e., to the OS defaults, except that
+//! `rust_tonic` turns `TCP_NODELAY` on). The TCP-level options are skipped on UDP sockets and
+//! Unix domain sockets.
+
+use std::io;
+use std::os::fd::AsFd;
+
+use socket2::{Domain, SockRef, Type};
+
+use crate::cli::size_parser;
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct SocketArgs {
+    /// Set `TCP_NODELAY`, to send small segments without waiting for the ACK of the previous
+    /// one (Nagle's algorithm); `--nodelay=false` clears it.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub nodelay: Option<bool>,
+
+    /// Size of the send buffer (`SO_SNDBUF`, which the kernel doubles), e.g. `256KiB`.
+    #[arg(long, value_parser = size_parser)]
+    pub sndbuf: Option<usize>,
+
+    /// Size of the receive buffer (`SO_RCVBUF`, which the kernel doubles), e.g. `256KiB`.
+    #[arg(long, value_parser = size_parser)]
+    pub rcvbuf: Option<usize>,
+
+    /// Busy-poll the device for up to this many microseconds on blocking receives
+    /// (`SO_BUSY_POLL`; above `net.core.busy_read`, it takes `CAP_NET_ADMIN`).
+    #[arg(long)]
+    pub busy_poll_us: Option<u32>,
+
+    /// Set `TCP_QUICKACK`, to acknowledge segments right away rather than delay the ACKs
+    /// (the kernel may go back to delayed ACKs later in the connection).
+    #[arg(long)]
+    pub quickack: bool,
+
+    /// Set `TCP_CORK`, to only send full segments: the tail of a message waits up to 200 ms.
+    #[arg(long)]
+    pub cork: bool,
+}
+
+impl SocketArgs {
+    /// Set the options on `socket`.
+    pub fn apply(&self, socket: impl AsFd) -> io::Result<()> {
+        let socket = SockRef::from(&socket);
+        if let Some(size) = self.sndbuf {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.rcvbuf {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(busy_poll) = self.busy_poll_us {
+            socket.set_busy_poll(busy_poll)?;
+        }
+        if socket.r#type()? != Type::STREAM || socket.domain()? == Domain::UNIX {
+            return Ok(());
+        }
+        if let Some(nodelay) = self.nodelay {
+            socket.set_tcp_nodelay(nodelay)?;
+        }
+        if self.quickack {
+            socket.set_tcp_quickack(true)?;
+        }
+        if self.cork {
+            socket.set_tcp_cork(true)?;
+        }
+        Ok(())
+    }
+
+    /// What the options are, for the logs.
+    pub fn describe(&self) -> String {
+        let or_default = |value: Option<String>| value.unwrap_or_else(|| "default".to_string());
+        format!(
+            "nodelay: {}, sndbuf: {}, rcvbuf: {}, busy poll: {}, quickack: {}, cork: {}",
+            or_default(self.nodelay.map(|nodelay| nodelay.to_string())),
+            or_default(self.sndbuf.map(|size| size.to_string())),
+            or_default(self.rcvbuf.map(|size| size.to_string())),
+            or_default(self.busy_poll_us.map(|us| format!("{}us", us))),
+            self.quickack,
+            self.cork
+        )
+    }
+
+    /// For the transports that only take `--nodelay`, `--sndbuf` and `--rcvbuf`:
+    /// `--busy-poll-us, --quickack and --cork are not supported <why>`.
+    pub fn reject_tuning(&self, why: &str) -> anyhow::Result<()> {
+        if self.busy_poll_us.is_some() || self.quickack || self.cork {
+            return Err(anyhow::anyhow!(
+                "--busy-poll-us, --quickack and --cork are not supported {}",
+                why
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::net::{TcpListener, TcpStream, UdpSocket};
+    use std::os::unix::net::UnixStream;
+
+    #[derive(Parser)]
+    struct Args {
+        #[command(flatten)]
+        socket: SocketArgs,
+    }
+
+    fn parse(args: &[&str]) -> SocketArgs {
+        Args::try_parse_from(["test"].iter().chain(args))
+            .unwrap()
+            .socket
+    }
+
+    fn tcp_stream() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (stream, listener.accept().unwrap().0)
+    }
+
+    #[test]
+    fn parses_options() {
+        let args = parse(&[]);
+        assert_eq!(args.nodelay, None);
+        assert_eq!(
+            args.describe(),
+            "nodelay: default, sndbuf: default, rcvbuf: default, busy poll: default, \
+             quickack: false, cork: false"
+        );
+
+        assert_eq!(parse(&["--nodelay"]).nodelay, Some(true));
+        assert_eq!(parse(&["--nodelay=false"]).nodelay, Some(false));
+        let args = parse(&[
+            "--sndbuf",
+            "256KiB",
+            "--rcvbuf",
+            "1k",
+            "--busy-poll-us",
+            "50",
+        ]);
+        assert_eq!(args.sndbuf, Some(256 << 10));
+        assert_eq!(args.rcvbuf, Some(1 << 10));
+        assert_eq!(args.busy_poll_us, Some(50));
+    }
+
+    #[test]
+    fn sets_tcp_options() {
+        let (stream, _peer) = tcp_stream();
+        let args = parse(&[
+            "--nodelay",
+            "--sndbuf",
+            "64KiB",
+            "--rcvbuf",
+            "64KiB",
+            "--cork",
+        ]);
+        args.apply(&stream).unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(socket.tcp_nodelay().unwrap());
+        assert!(socket.tcp_cork().unwrap());
+        // doubled by the kernel, for its bookkeeping
+        assert_eq!(socket.send_buffer_size().unwrap(), 128 << 10);
+        assert_eq!(socket.recv_buffer_size().unwrap(), 128 << 10);
+
+        parse(&["--nodelay=false"]).apply(&stream).unwrap();
+        assert!(!socket.tcp_nodelay().unwrap());
+        // options left out are left alone
+        parse(&[]).apply(&stream).unwrap();
+        assert!(socket.tcp_cork().unwrap());
+    }
+
+    #[test]
+    fn skips_tcp_options_elsewhere() {
+        let args = parse(&["--nodelay", "--quickack", "--cork", "--sndbuf", "64KiB"]);
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        args.apply(&udp).unwrap();
+        assert_eq!(SockRef::from(&udp).send_buffer_size().unwrap(), 128 << 10);
+
+        let (unix, _peer) = UnixStream::pair().unwrap();
+        args.apply(&unix).unwrap();
+    }
+
+    #[test]
+    fn rejects_tuning() {
+        assert!(parse(&["--nodelay", "--sndbuf", "1k"])
+            .reject_tuning("here")
+            .is_ok());
+        for args in [&["--quickack"][..], &["--cork"], &["--busy-poll-us", "1"]] {
+            let e = parse(args).reject_tuning("here").unwrap_err().to_string();
+            assert_eq!(
+                e,
+                "--busy-poll-us, --quickack and --cork are not supported here"
+            );
+        }
+    }
+}
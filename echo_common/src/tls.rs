@@ -0,0 +1,319 @@
+//! TLS for the raw-TCP and gRPC implementations (`--tls`, `--mtls`), with rustls.
+//!
+//! Certificates are issued by a CA whose key is generated on first use and kept in
+//! `--tls-dir` (`ca.key`, along with `ca.pem` for other tools), so that the servers and
+//! clients of a machine trust each other without any setup; other machines need a copy of the
+//! directory. Servers get a certificate for `localhost`, their hostname and the address they
+//! listen on, and with `--mtls`, clients get one too, which servers require.
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose,
+};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+use crate::cli::TlsArgs;
+
+const CA_NAME: &str = "echo CA";
+
+/// A certificate issued by the CA, with its key.
+pub struct Credentials {
+    ca: Certificate,
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Credentials {
+    /// A certificate for a server listening on `host`, also valid for `localhost` and the
+    /// hostname of the machine.
+    pub fn server(args: &TlsArgs, host: &str) -> anyhow::Result<Self> {
+        let mut names = vec!["localhost".to_string(), unbracketed(host).to_string()];
+        if let Ok(hostname) = gethostname::gethostname().into_string() {
+            if !names.contains(&hostname) {
+                names.push(hostname);
+            }
+        }
+        let mut params = CertificateParams::new(names)?;
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        Self::issue(args, params)
+    }
+
+    /// A certificate for a client, which only servers with `--mtls` check.
+    pub fn client(args: &TlsArgs) -> anyhow::Result<Self> {
+        let mut params = CertificateParams::new(Vec::new())?;
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "echo client");
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        Self::issue(args, params)
+    }
+
+    fn issue(args: &TlsArgs, params: CertificateParams) -> anyhow::Result<Self> {
+        let (ca, ca_key) = load_ca(&args.tls_dir)?;
+        let key = KeyPair::generate()?;
+        let cert = params.signed_by(&key, &ca, &ca_key)?;
+        Ok(Credentials { ca, cert, key })
+    }
+
+    pub fn ca_pem(&self) -> String {
+        self.ca.pem()
+    }
+
+    pub fn cert_pem(&self) -> String {
+        self.cert.pem()
+    }
+
+    pub fn key_pem(&self) -> String {
+        self.key.serialize_pem()
+    }
+
+    /// Server side, requiring client certificates from the CA with `mtls`.
+    pub fn server_config(&self, mtls: bool) -> anyhow::Result<Arc<ServerConfig>> {
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?;
+        let builder = if mtls {
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(self.roots()?), provider())
+                    .build()?;
+            builder.with_client_cert_verifier(verifier)
+        } else {
+            builder.with_no_client_auth()
+        };
+        let config = builder.with_single_cert(vec![self.cert.der().clone()], self.key_der())?;
+        Ok(Arc::new(config))
+    }
+
+    /// Client side, trusting the CA only, and presenting the certificate with `mtls`.
+    pub fn client_config(&self, mtls: bool) -> anyhow::Result<Arc<ClientConfig>> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(self.roots()?);
+        let config = if mtls {
+            builder.with_client_auth_cert(vec![self.cert.der().clone()], self.key_der())?
+        } else {
+            builder.with_no_client_auth()
+        };
+        Ok(Arc::new(config))
+    }
+
+    fn roots(&self) -> anyhow::Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        roots.add(self.ca.der().clone())?;
+        Ok(roots)
+    }
+
+    fn key_der(&self) -> PrivateKeyDer<'static> {
+        PrivatePkcs8KeyDer::from(self.key.serialize_der()).into()
+    }
+}
+
+/// Name to check the certificate of a server on `host` against.
+pub fn server_name(host: &str) -> anyhow::Result<ServerName<'static>> {
+    ServerName::try_from(unbracketed(host).to_string())
+        .with_context(|| format!("invalid server name: {}", host))
+}
+
+/// `host`, without the brackets of an IPv6 address: the name certificates are issued for.
+pub fn unbracketed(host: &str) -> &str {
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn ca_params() -> anyhow::Result<CertificateParams> {
+    let mut params = CertificateParams::new(Vec::new())?;
+    params.distinguished_name.push(DnType::CommonName, CA_NAME);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    Ok(params)
+}
+
+/// The CA in `dir`, created by whichever process gets there first.
+///
+/// Only the key is shared: the CA certificate is issued again from it, and certificates are
+/// matched to it by name and key, whatever the certificate a peer was issued with.
+fn load_ca(dir: &Path) -> anyhow::Result<(Certificate, KeyPair)> {
+    let key_path = dir.join("ca.key");
+    let key = match fs::read_to_string(&key_path) {
+        Ok(pem) => KeyPair::from_pem(&pem)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => create_ca_key(dir)?,
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", key_path.display())),
+    };
+    let ca = ca_params()?.self_signed(&key)?;
+    Ok((ca, key))
+}
+
+/// Generate the key of the CA; if another process races us to it, use theirs.
+fn create_ca_key(dir: &Path) -> anyhow::Result<KeyPair> {
+    fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let key = KeyPair::generate()?;
+    let key_path = dir.join("ca.key");
+    let tmp_path = dir.join(format!("ca.key.{}", std::process::id()));
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .and_then(|mut file| file.write_all(key.serialize_pem().as_bytes()))
+        .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+    // unlike a rename, a link never replaces the key of another process
+    let linked = fs::hard_link(&tmp_path, &key_path);
+    let _ = fs::remove_file(&tmp_path);
+    match linked {
+        Ok(()) => {
+            let ca = ca_params()?.self_signed(&key)?;
+            let ca_path = dir.join("ca.pem");
+            fs::write(&ca_path, ca.pem())
+                .with_context(|| format!("failed to write {}", ca_path.display()))?;
+            Ok(key)
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            Ok(KeyPair::from_pem(&fs::read_to_string(&key_path)?)?)
+        }
+        Err(e) => Err(e).with_context(|| format!("failed to write {}", key_path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    use rustls::{ClientConnection, ServerConnection};
+
+    fn tls_args(name: &str, mtls: bool) -> TlsArgs {
+        let tls_dir =
+            std::env::temp_dir().join(format!("echo_tls-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&tls_dir);
+        TlsArgs {
+            tls: true,
+            mtls,
+            tls_dir,
+        }
+    }
+
+    fn cleanup(dir: PathBuf) {
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Run the handshake between `client` and `server` in memory.
+    fn handshake(
+        client: &mut ClientConnection,
+        server: &mut ServerConnection,
+    ) -> Result<(), rustls::Error> {
+        for _ in 0..10 {
+            let mut flight = Vec::new();
+            while client.wants_write() {
+                client.write_tls(&mut flight).unwrap();
+            }
+            server.read_tls(&mut flight.as_slice()).unwrap();
+            server.process_new_packets()?;
+
+            let mut flight = Vec::new();
+            while server.wants_write() {
+                server.write_tls(&mut flight).unwrap();
+            }
+            client.read_tls(&mut flight.as_slice()).unwrap();
+            client.process_new_packets()?;
+
+            if !client.is_handshaking() && !server.is_handshaking() {
+                return Ok(());
+            }
+        }
+        panic!("the handshake did not complete");
+    }
+
+    fn connect(
+        client: &Credentials,
+        server: &Credentials,
+        mtls: (bool, bool),
+        host: &str,
+    ) -> Result<(), rustls::Error> {
+        let (client_mtls, server_mtls) = mtls;
+        let mut client = ClientConnection::new(
+            client.client_config(client_mtls).unwrap(),
+            server_name(host).unwrap(),
+        )
+        .unwrap();
+        let mut server = ServerConnection::new(server.server_config(server_mtls).unwrap()).unwrap();
+        handshake(&mut client, &mut server)
+    }
+
+    #[test]
+    fn creates_the_ca_once() {
+        let args = tls_args("ca", false);
+        let first = Credentials::client(&args).unwrap();
+        let key = fs::read_to_string(args.tls_dir.join("ca.key")).unwrap();
+        let mode = fs::metadata(args.tls_dir.join("ca.key"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(fs::read_to_string(args.tls_dir.join("ca.pem"))
+            .unwrap()
+            .starts_with("-----BEGIN CERTIFICATE-----"));
+
+        // issued again from the same key, which is all peers need to agree on
+        let server = Credentials::server(&args, "localhost").unwrap();
+        assert_eq!(
+            fs::read_to_string(args.tls_dir.join("ca.key")).unwrap(),
+            key
+        );
+        connect(&first, &server, (true, true), "localhost").unwrap();
+        cleanup(args.tls_dir);
+    }
+
+    #[test]
+    fn tls_round_trip() {
+        let args = tls_args("tls", false);
+        let server = Credentials::server(&args, "[::1]").unwrap();
+        let client = Credentials::client(&args).unwrap();
+        for host in ["localhost", "[::1]"] {
+            connect(&client, &server, (false, false), host).unwrap();
+        }
+        let e = connect(&client, &server, (false, false), "example.com").unwrap_err();
+        assert!(matches!(e, rustls::Error::InvalidCertificate(_)), "{:?}", e);
+        cleanup(args.tls_dir);
+    }
+
+    #[test]
+    fn mtls_requires_client_certificates() {
+        let args = tls_args("mtls", true);
+        let server = Credentials::server(&args, "localhost").unwrap();
+        let client = Credentials::client(&args).unwrap();
+        connect(&client, &server, (true, true), "localhost").unwrap();
+
+        let e = connect(&client, &server, (false, true), "localhost").unwrap_err();
+        assert_eq!(e, rustls::Error::NoCertificatesPresented);
+
+        // a client of another CA
+        let other = tls_args("mtls-other", true);
+        let stranger = Credentials::client(&other).unwrap();
+        let e = connect(&stranger, &server, (true, true), "localhost").unwrap_err();
+        assert!(matches!(e, rustls::Error::InvalidCertificate(_)), "{:?}", e);
+        cleanup(args.tls_dir);
+        cleanup(other.tls_dir);
+    }
+
+    #[test]
+    fn server_names() {
+        assert_eq!(unbracketed("[::1]"), "::1");
+        assert_eq!(unbracketed("localhost"), "localhost");
+        assert!(server_name("[::1]").is_ok());
+        assert!(server_name("not a name").is_err());
+    }
+}
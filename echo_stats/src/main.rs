@@ -0,0 +1,61 @@
+//! `echo-stats`: statistics over the output of any number of echo clients.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::Parser;
+
+mod parse;
+mod stats;
+
+use parse::Logs;
+use stats::Summary;
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Format {
+    /// The layout of the `.stats` files.
+    Stats,
+    Json,
+}
+
+#[derive(Parser)]
+#[command(author, version, about, long_about=None)]
+struct Args {
+    /// Client logs (default: read from stdin).
+    files: Vec<PathBuf>,
+
+    #[arg(short, long, default_value = "stats")]
+    format: Format,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let mut logs = Logs::default();
+    if args.files.is_empty() {
+        logs.parse("<stdin>", std::io::stdin().lock())?;
+    }
+    for path in &args.files {
+        let name = path.display().to_string();
+        let file = File::open(path).with_context(|| format!("failed to open {}", name))?;
+        logs.parse(&name, BufReader::new(file))?;
+    }
+
+    for name in &logs.uncounted {
+        eprintln!(
+            "warning: {} is corrected for coordinated omission, but has no `Requests:` line: \
+             the throughput is unknown",
+            name
+        );
+    }
+
+    let summary = Summary::new(&logs)?;
+    match args.format {
+        Format::Stats => summary.print_stats(),
+        Format::Json => summary.print_json()?,
+    }
+
+    Ok(())
+}
@@ -0,0 +1,297 @@
+//! Parser for the client output format from the README.
+
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use anyhow::Context;
+use base64::Engine;
+use hdrhistogram::serialization::Deserializer;
+use hdrhistogram::Histogram;
+
+use crate::stats::Samples;
+
+/// Everything gathered from a set of client logs.
+#[derive(Default)]
+pub struct Logs {
+    /// The `Message Size:` shared by every log, with the log it was first seen in.
+    pub message_size: Option<(usize, String)>,
+    /// Earliest `Start:` per client ID.
+    pub start: HashMap<String, f64>,
+    /// Latest `End:` per client ID.
+    pub end: HashMap<String, f64>,
+    pub samples: Samples,
+    /// Requests measured, across the logs that tell (see `uncounted`).
+    pub requests: u64,
+    /// Logs corrected for coordinated omission, but without a `Requests:` line: their
+    /// samples include synthetic ones, so they do not tell how many requests went through.
+    pub uncounted: Vec<String>,
+}
+
+impl Logs {
+    /// Parse one client log.
+    ///
+    /// A log with bare latency lines (`--print-samples`) also carries the histogram of those
+    /// same latencies: only the former are used, as they are exact. Otherwise, the
+    /// `Histogram:` lines are decoded and merged.
+    ///
+    /// The requests are counted from the `Requests:` lines, or from the samples of a log that
+    /// is not corrected for coordinated omission.
+    pub fn parse<R: BufRead>(&mut self, name: &str, reader: R) -> anyhow::Result<()> {
+        let mut has_samples = false;
+        let mut histograms = Vec::new();
+        let mut corrected = false;
+        let mut requests = None;
+        let samples = self.samples.count();
+
+        for (lineno, line) in reader.lines().enumerate() {
+            let line = line.with_context(|| format!("failed to read {}", name))?;
+            let context = || format!("{}:{}: malformed line: {}", name, lineno + 1, line);
+
+            if line.starts_with(|c: char| c.is_ascii_digit()) {
+                let micros = line.trim().parse::<f64>().with_context(context)?;
+                self.samples.record_micros(micros);
+                has_samples = true;
+            } else if let Some(rest) = line.strip_prefix("Message Size:") {
+                let size = rest.trim().parse::<usize>().with_context(context)?;
+                self.message_size(name, size)?;
+            } else if let Some(rest) = line.strip_prefix("Start:") {
+                let (id, ts) = id_and_timestamp(rest).with_context(context)?;
+                let start = self.start.entry(id).or_insert(ts);
+                *start = start.min(ts);
+            } else if let Some(rest) = line.strip_prefix("End:") {
+                let (id, ts) = id_and_timestamp(rest).with_context(context)?;
+                let end = self.end.entry(id).or_insert(ts);
+                *end = end.max(ts);
+            } else if let Some(rest) = line.strip_prefix("Corrected:") {
+                corrected = rest.trim() != "none";
+            } else if let Some(rest) = line.strip_prefix("Requests:") {
+                let n = rest.trim().parse::<u64>().with_context(context)?;
+                *requests.get_or_insert(0) += n;
+            } else if let Some(rest) = line.strip_prefix("Histogram:") {
+                histograms.push(decode_histogram(rest.trim()).with_context(context)?);
+            }
+        }
+
+        if !has_samples {
+            for histogram in histograms {
+                self.samples.record_histogram(&histogram);
+            }
+        }
+
+        match requests {
+            Some(n) => self.requests += n,
+            None if !corrected => self.requests += self.samples.count() - samples,
+            None => self.uncounted.push(name.to_string()),
+        }
+
+        Ok(())
+    }
+
+    fn message_size(&mut self, name: &str, size: usize) -> anyhow::Result<()> {
+        match &self.message_size {
+            None => self.message_size = Some((size, name.to_string())),
+            Some((expected, first)) if *expected != size => {
+                return Err(anyhow::anyhow!(
+                    "inconsistent message sizes: {} in {}, but {} in {}",
+                    expected,
+                    first,
+                    size,
+                    name
+                ))
+            }
+            Some(_) => {}
+        }
+        Ok(())
+    }
+
+    /// IDs with both a `Start:` and an `End:`.
+    pub fn clients(&self) -> impl Iterator<Item = (f64, f64)> + '_ {
+        self.start
+            .iter()
+            .filter_map(|(id, start)| self.end.get(id).map(|end| (*start, *end)))
+    }
+}
+
+fn id_and_timestamp(s: &str) -> anyhow::Result<(String, f64)> {
+    let mut fields = s.split_whitespace();
+    let id = fields.next().context("missing client ID")?;
+    let ts = fields
+        .next()
+        .context("missing timestamp")?
+        .parse::<f64>()
+        .context("invalid timestamp")?;
+    Ok((id.to_string(), ts))
+}
+
+fn decode_histogram(s: &str) -> anyhow::Result<Histogram<u64>> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(s)
+        .context("invalid base64")?;
+    Deserializer::new()
+        .deserialize(&mut bytes.as_slice())
+        .map_err(|e| anyhow::anyhow!("invalid histogram: {:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::Summary;
+    use hdrhistogram::serialization::{Serializer, V2DeflateSerializer};
+
+    /// A `Histogram:` line, as the clients print it (of values under 2048, which it holds
+    /// exactly).
+    fn histogram_line(values: &[u64]) -> String {
+        let mut histogram = Histogram::<u64>::new(3).unwrap();
+        for value in values {
+            histogram.record(*value).unwrap();
+        }
+        let mut serialized = Vec::new();
+        V2DeflateSerializer::new()
+            .serialize(&histogram, &mut serialized)
+            .unwrap();
+        format!(
+            "Histogram: {}",
+            base64::engine::general_purpose::STANDARD.encode(serialized)
+        )
+    }
+
+    fn parse(logs: &[&str]) -> anyhow::Result<Logs> {
+        let mut parsed = Logs::default();
+        for (i, log) in logs.iter().enumerate() {
+            parsed.parse(&format!("client{}.log", i), log.as_bytes())?;
+        }
+        Ok(parsed)
+    }
+
+    #[test]
+    fn bare_samples_win_over_the_histogram() {
+        let log = format!(
+            "Message Size: 16\nStart: a 1.0\n10.000\n30.000\nEnd: a 3.0\n{}\n",
+            histogram_line(&[1_000_000, 2_000_000, 3_000_000])
+        );
+        let summary = Summary::new(&parse(&[&log]).unwrap()).unwrap();
+        assert_eq!(summary.message_size, 16);
+        assert_eq!(summary.transfers, 2);
+        assert_eq!(summary.min_us, 10.0);
+        assert_eq!(summary.mean_us, 20.0);
+        assert_eq!(summary.max_us, 30.0);
+        assert_eq!(summary.clients, 1);
+        assert_eq!(summary.elapsed_avg_s, 2.0);
+        assert_eq!(summary.requests, Some(2));
+        assert_eq!(summary.throughput_ops, Some(1.0));
+        assert_eq!(summary.throughput_bytes, Some(16.0));
+    }
+
+    #[test]
+    fn corrected_logs_count_the_requests_they_print() {
+        // one request of 2ms, and the 3 synthetic samples added for an interval of 500us
+        let log = format!(
+            "Message Size: 8\nCorrected: 500us\nStart: a 0.0\nEnd: a 2.0\nSamples: 4\n\
+             Requests: 1\n{}\n",
+            histogram_line(&[500_000, 1_000_000, 1_500_000, 2_000_000])
+        );
+        let summary = Summary::new(&parse(&[&log]).unwrap()).unwrap();
+        assert_eq!(summary.transfers, 4);
+        assert_eq!(summary.requests, Some(1));
+        assert_eq!(summary.throughput_ops, Some(0.5));
+        assert_eq!(summary.throughput_bytes, Some(4.0));
+    }
+
+    #[test]
+    fn no_throughput_without_a_request_count() {
+        let logs = parse(&[
+            "Message Size: 8\nCorrected: none\nStart: a 0.0\n1.0\n2.0\nEnd: a 1.0\n",
+            // synthetic samples, but no `Requests:` line (e.g., `--print-samples` alone)
+            "Message Size: 8\nCorrected: 1ms\nStart: b 0.0\n3.0\n2.0\n1.0\nEnd: b 1.0\n",
+        ])
+        .unwrap();
+        assert_eq!(logs.requests, 2);
+        assert_eq!(logs.uncounted, ["client1.log"]);
+        let summary = Summary::new(&logs).unwrap();
+        assert_eq!(summary.transfers, 5);
+        assert_eq!(summary.requests, None);
+        assert_eq!(summary.throughput_ops, None);
+        assert_eq!(summary.throughput_bytes, None);
+    }
+
+    #[test]
+    fn histograms_without_bare_samples() {
+        let logs = [
+            format!(
+                "Message Size: 1\nStart: a 0.5\nEnd: a 1.5\n{}\n",
+                histogram_line(&[1_000, 2_000])
+            ),
+            // a log without samples does not make the others fall back
+            format!(
+                "Message Size: 1\nStart: b 0.0\n5.000\nEnd: b 2.0\n{}\n",
+                histogram_line(&[7_000_000])
+            ),
+        ];
+        let logs = parse(&[&logs[0], &logs[1]]).unwrap();
+        let summary = Summary::new(&logs).unwrap();
+        assert_eq!(summary.transfers, 3);
+        assert_eq!(summary.min_us, 1.0);
+        assert_eq!(summary.max_us, 5.0);
+        assert_eq!(summary.clients, 2);
+        assert_eq!(summary.elapsed_avg_s, 1.5);
+    }
+
+    #[test]
+    fn clients_span_from_first_start_to_last_end() {
+        let logs = parse(&[
+            "Message Size: 1\nStart: a 2.0\n1.0\nEnd: a 3.0\n",
+            "Message Size: 1\nStart: a 1.0\nEnd: a 4.0\nStart: b 0.0\n",
+        ])
+        .unwrap();
+        assert_eq!(logs.clients().collect::<Vec<_>>(), [(1.0, 4.0)]);
+    }
+
+    #[test]
+    fn message_sizes_must_match() {
+        let e = parse(&["Message Size: 1\n", "Message Size: 2\n"])
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            e,
+            "inconsistent message sizes: 1 in client0.log, but 2 in client1.log"
+        );
+        assert!(parse(&["Message Size: 1\n", "Message Size: 1\n"]).is_ok());
+    }
+
+    #[test]
+    fn malformed_lines() {
+        for log in [
+            "1.2.3\n",
+            "Message Size: big\n",
+            "Start: a\n",
+            "Requests: many\n",
+            "Histogram: !!\n",
+        ] {
+            let e = parse(&[log]).err().unwrap().to_string();
+            assert!(e.starts_with("client0.log:1: malformed line"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn missing_lines() {
+        let e = |log| {
+            Summary::new(&parse(&[log]).unwrap())
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            e("Start: a 0\n1.0\nEnd: a 1\n"),
+            "no `Message Size:` line found"
+        );
+        assert_eq!(
+            e("Message Size: 1\nStart: a 0\nEnd: a 1\n"),
+            "no latency samples found"
+        );
+        assert_eq!(
+            e("Message Size: 1\nStart: a 0\n1.0\n"),
+            "no client with both a `Start:` and an `End:`"
+        );
+    }
+}
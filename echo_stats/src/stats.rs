@@ -0,0 +1,280 @@
+//! Latency and throughput statistics.
+
+use hdrhistogram::Histogram;
+use serde::Serialize;
+
+use crate::parse::Logs;
+
+/// Normal quantiles for the two-sided confidence intervals of the mean.
+const Z_95: f64 = 1.959964;
+const Z_99: f64 = 2.575829;
+
+/// Latency samples, in nanoseconds.
+///
+/// Percentiles come from an HDR histogram (3 significant digits), so memory does not grow
+/// with the number of samples. The count, min, max, mean and variance are tracked exactly
+/// (Welford's algorithm), up to the precision of the inputs.
+pub struct Samples {
+    histogram: Histogram<u64>,
+    count: u64,
+    min: f64,
+    max: f64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Default for Samples {
+    fn default() -> Self {
+        Samples {
+            histogram: Histogram::new(3).expect("3 significant digits are supported"),
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+}
+
+impl Samples {
+    pub fn record_micros(&mut self, micros: f64) {
+        self.record(micros * 1_000f64, 1);
+    }
+
+    pub fn record_histogram(&mut self, histogram: &Histogram<u64>) {
+        for value in histogram.iter_recorded() {
+            self.record(value.value_iterated_to() as f64, value.count_at_value());
+        }
+    }
+
+    fn record(&mut self, nanos: f64, count: u64) {
+        self.histogram
+            .record_n(nanos.round().max(0.0) as u64, count)
+            .expect("auto-resizing histograms accept any value");
+
+        // weighted Welford update
+        let total = self.count + count;
+        let delta = nanos - self.mean;
+        self.mean += delta * count as f64 / total as f64;
+        self.m2 += delta * (nanos - self.mean) * count as f64;
+        self.count = total;
+        self.min = self.min.min(nanos);
+        self.max = self.max.max(nanos);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    fn stddev(&self) -> f64 {
+        if self.count < 2 {
+            0.0
+        } else {
+            (self.m2 / (self.count - 1) as f64).sqrt()
+        }
+    }
+
+    fn percentile(&self, percentile: f64) -> f64 {
+        self.histogram.value_at_percentile(percentile) as f64
+    }
+}
+
+#[derive(Serialize)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub p999: f64,
+}
+
+/// Summary of a set of client logs. Latencies are in microseconds.
+///
+/// The throughput is that of the requests, which are not known when a log corrected for
+/// coordinated omission does not count them (see `Logs::uncounted`).
+#[derive(Serialize)]
+pub struct Summary {
+    pub message_size: usize,
+    pub transfers: u64,
+    pub min_us: f64,
+    pub mean_us: f64,
+    pub stddev_us: f64,
+    pub percentiles_us: Percentiles,
+    pub max_us: f64,
+    pub mean_ci95_us: (f64, f64),
+    pub mean_ci99_us: (f64, f64),
+    pub requests: Option<u64>,
+    pub throughput_ops: Option<f64>,
+    pub throughput_bytes: Option<f64>,
+    pub elapsed_avg_s: f64,
+    pub clients: usize,
+}
+
+impl Summary {
+    pub fn new(logs: &Logs) -> anyhow::Result<Self> {
+        let (message_size, _) = logs
+            .message_size
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("no `Message Size:` line found"))?;
+
+        let samples = &logs.samples;
+        if samples.count == 0 {
+            return Err(anyhow::anyhow!("no latency samples found"));
+        }
+
+        let (clients, elapsed_sum) = logs.clients().fold((0, 0.0), |(n, sum), (start, end)| {
+            (n + 1, sum + end - start)
+        });
+        if clients == 0 {
+            return Err(anyhow::anyhow!(
+                "no client with both a `Start:` and an `End:`"
+            ));
+        }
+        let elapsed_avg_s = elapsed_sum / clients as f64;
+
+        let micros = |nanos: f64| nanos / 1_000f64;
+        let stderr = samples.stddev() / (samples.count as f64).sqrt();
+        let interval = |z: f64| {
+            (
+                micros(samples.mean - z * stderr),
+                micros(samples.mean + z * stderr),
+            )
+        };
+        let requests = Some(logs.requests).filter(|_| logs.uncounted.is_empty());
+        let throughput_ops = requests.map(|requests| requests as f64 / elapsed_avg_s);
+
+        Ok(Summary {
+            message_size,
+            transfers: samples.count,
+            min_us: micros(samples.min),
+            mean_us: micros(samples.mean),
+            stddev_us: micros(samples.stddev()),
+            percentiles_us: Percentiles {
+                p50: micros(samples.percentile(50.0)),
+                p90: micros(samples.percentile(90.0)),
+                p95: micros(samples.percentile(95.0)),
+                p99: micros(samples.percentile(99.0)),
+                p999: micros(samples.percentile(99.9)),
+            },
+            max_us: micros(samples.max),
+            mean_ci95_us: interval(Z_95),
+            mean_ci99_us: interval(Z_99),
+            requests,
+            throughput_ops,
+            throughput_bytes: throughput_ops.map(|ops| ops * message_size as f64),
+            elapsed_avg_s,
+            clients,
+        })
+    }
+
+    /// The layout of the `.stats` files (as produced by `awk/stats.awk`), followed by the
+    /// statistics it did not compute.
+    pub fn print_stats(&self) {
+        println!("#Transfers:  {}", self.transfers);
+        println!("Min:         {:.3} us", self.min_us);
+        println!("Average:     {:.3} us", self.mean_us);
+        println!("Stddev:      {:.3} us", self.stddev_us);
+        println!("P90:         {:.3} us", self.percentiles_us.p90);
+        println!("P95:         {:.3} us", self.percentiles_us.p95);
+        println!("P99:         {:.3} us", self.percentiles_us.p99);
+        println!("Max:         {:.3} us", self.max_us);
+        match self.throughput_bytes {
+            Some(bytes) => println!("Throughput:  {:.3} B/s", bytes),
+            None => println!("Throughput:  n/a"),
+        }
+        println!("Elapsed Avg: {:.9} s", self.elapsed_avg_s);
+        println!("#Clients:    {}", self.clients);
+        println!("P50:         {:.3} us", self.percentiles_us.p50);
+        println!("P99.9:       {:.3} us", self.percentiles_us.p999);
+        match self.throughput_ops {
+            Some(ops) => println!("Ops:         {:.3} op/s", ops),
+            None => println!("Ops:         n/a"),
+        }
+        println!(
+            "Avg CI95:    [{:.3}, {:.3}] us",
+            self.mean_ci95_us.0, self.mean_ci95_us.1
+        );
+        println!(
+            "Avg CI99:    [{:.3}, {:.3}] us",
+            self.mean_ci99_us.0, self.mean_ci99_us.1
+        );
+    }
+
+    pub fn print_json(&self) -> anyhow::Result<()> {
+        println!("{}", serde_json::to_string_pretty(self)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(micros: &[f64]) -> Samples {
+        let mut samples = Samples::default();
+        for micros in micros {
+            samples.record_micros(*micros);
+        }
+        samples
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() <= expected.abs() * 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn mean_and_stddev() {
+        let samples = samples(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]);
+        assert_eq!(samples.count, 8);
+        assert_close(samples.mean, 5_000.0);
+        // sample (not population) standard deviation: sqrt(32 / 7)
+        assert_close(samples.stddev(), (32.0f64 / 7.0).sqrt() * 1_000.0);
+        assert_eq!(samples.min, 2_000.0);
+        assert_eq!(samples.max, 9_000.0);
+    }
+
+    #[test]
+    fn stddev_of_fewer_than_two_samples() {
+        assert_eq!(samples(&[]).stddev(), 0.0);
+        assert_eq!(samples(&[3.0]).stddev(), 0.0);
+    }
+
+    #[test]
+    fn weighted_records_match_repeated_ones() {
+        let mut histogram = Histogram::<u64>::new(3).unwrap();
+        // exact in the histogram, under 2048
+        histogram.record_n(100, 3).unwrap();
+        histogram.record_n(400, 1).unwrap();
+        let mut weighted = Samples::default();
+        weighted.record_histogram(&histogram);
+
+        let repeated = samples(&[0.1, 0.1, 0.1, 0.4]);
+        assert_eq!(weighted.count, repeated.count);
+        assert_close(weighted.mean, repeated.mean);
+        assert_close(weighted.stddev(), repeated.stddev());
+        assert_close(weighted.mean, 175.0);
+        assert_close(weighted.stddev(), 150.0);
+    }
+
+    #[test]
+    fn percentiles() {
+        let samples = samples(&(1..=1000).map(f64::from).collect::<Vec<_>>());
+        // within the 3 significant digits of the histogram
+        for (percentile, expected) in [(50.0, 500_000.0), (90.0, 900_000.0), (99.0, 990_000.0)] {
+            let actual = samples.percentile(percentile);
+            assert!(
+                (actual - expected).abs() <= expected * 1e-3,
+                "P{}: {} != {}",
+                percentile,
+                actual,
+                expected
+            );
+        }
+        assert_eq!(samples.percentile(100.0), 1_000_447.0);
+    }
+}
@@ -13,20 +13,20 @@ import sys
 import time
 import uuid
 
+import protocol
+
 BUCKET_SIZE = 2 ** 15
 BUCKET = b'\x42' * BUCKET_SIZE
 
 
 def run_client(host, port, message_size) -> float:
-    encoded_size = message_size.to_bytes(8, 'big')
-
     size_to_send = message_size
 
     addr = (host, port)
     server = socket.create_connection(addr)
 
+    protocol.connect(server, message_size)
     start_time = time.perf_counter()
-    assert server.send(encoded_size) == 8
 
     while size_to_send > 0:
         this_bucket = min(BUCKET_SIZE, size_to_send)
@@ -113,6 +113,14 @@ def client(
     start = start.replace(year=today.year, month=today.month, day=today.day)
     id = '{}:{}'.format(socket.gethostname(), uuid.uuid4().hex)
 
+    # fail fast against an incompatible server
+    try:
+        with socket.create_connection((host, port)) as probe:
+            protocol.connect(probe, message_size)
+    except (OSError, protocol.HandshakeError) as e:
+        print('Error: handshake with {}:{} failed: {}'.format(host, port, e), file=sys.stderr)
+        sys.exit(1)
+
     print('Message Size: {}'.format(message_size))
 
     pool = multiprocessing.Pool(
@@ -0,0 +1,79 @@
+"""Handshake of the raw-TCP echo protocol (see echo_common/src/protocol.rs)."""
+
+import struct
+
+MAGIC = b'ECHO'
+VERSION = 1
+PAYLOAD_CONSTANT = 0
+# constant, sequence, random and crc32c: the server echoes them all the same way
+PAYLOAD_MODES = (0, 1, 2, 3)
+SUPPORTED_FEATURES = 0
+
+# magic, version, payload mode, reserved, features, message size
+HELLO = struct.Struct('>4sHBBIQ')
+# magic, version, status, reserved, features, reason length
+ACK = struct.Struct('>4sHBBIH')
+
+ACCEPTED = 0
+REJECTED = 1
+
+HANDSHAKE_TIMEOUT = 5.0
+
+
+class HandshakeError(Exception):
+    pass
+
+
+def recv_exactly(sock, n):
+    data = b''
+    while len(data) < n:
+        chunk = sock.recv(n - len(data))
+        if not chunk:
+            raise HandshakeError('connection closed during the handshake')
+        data += chunk
+    return data
+
+
+def connect(sock, message_size, features=0):
+    """Client side: send the hello, and return the features granted by the server."""
+    sock.sendall(HELLO.pack(MAGIC, VERSION, PAYLOAD_CONSTANT, 0, features, message_size))
+
+    timeout = sock.gettimeout()
+    sock.settimeout(HANDSHAKE_TIMEOUT)
+    try:
+        magic, _, status, _, granted, reason_len = ACK.unpack(recv_exactly(sock, ACK.size))
+        reason = recv_exactly(sock, reason_len).decode('utf-8', 'replace')
+    finally:
+        sock.settimeout(timeout)
+
+    if magic != MAGIC:
+        raise HandshakeError('bad magic in the handshake reply: not an echo server, '
+                             'or one that predates the handshake')
+    if status != ACCEPTED:
+        raise HandshakeError('server rejected handshake: {}'.format(reason))
+    return granted
+
+
+def accept(sock):
+    """Server side: read the hello, answer it, and return the message size."""
+    magic, version, payload, _, features, message_size = HELLO.unpack(
+        recv_exactly(sock, HELLO.size))
+
+    reason = None
+    if magic != MAGIC:
+        reason = 'bad magic: not an echo client'
+    elif version != VERSION:
+        reason = 'unsupported protocol version {} (server speaks {})'.format(
+            version, VERSION)
+    elif payload not in PAYLOAD_MODES:
+        reason = 'unsupported payload mode {}'.format(payload)
+    elif message_size == 0:
+        reason = 'invalid message size: 0'
+
+    if reason is not None:
+        encoded = reason.encode('utf-8')
+        sock.sendall(ACK.pack(MAGIC, VERSION, REJECTED, 0, 0, len(encoded)) + encoded)
+        raise HandshakeError('rejected handshake: {}'.format(reason))
+
+    sock.sendall(ACK.pack(MAGIC, VERSION, ACCEPTED, 0, features & SUPPORTED_FEATURES, 0))
+    return message_size
@@ -6,6 +6,8 @@ import logging
 import socket
 import multiprocessing
 
+import protocol
+
 BUCKET_SIZE = 2 ** 16
 
 
@@ -19,8 +21,7 @@ def echo(worker_id, client, address, data):
 
 def handle_client(worker_id, client, address):
     logging.info('[{}] handling client @ {}:{}'.format(worker_id, *address))
-    encoded_size = client.recv(8)
-    message_size = int.from_bytes(encoded_size, 'big')
+    message_size = protocol.accept(client)
 
     size_to_receive = message_size
     while size_to_receive > 0:
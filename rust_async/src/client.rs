@@ -1,142 +1,334 @@
 use std::sync::Arc;
 
 use anyhow::Context;
-use chrono::{Local, NaiveDateTime, NaiveTime};
 use clap::Parser;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
-use tokio::time::Duration;
+use echo_common::protocol::{self, FRAME_HEADER_LEN};
+use echo_common::tls::{self, Credentials};
+use echo_common::{
+    Arrivals, Bursts, ClientArgs, ClientType, Endpoint, LoadArgs, Message, Recorder, Report,
+    SocketArgs,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{tcp, unix};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::{mpsc, Mutex, Semaphore};
+use tokio::time::{Duration, Instant};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
 
 const BUFFER_SIZE: usize = 1 << 16;
 
-fn size_parser(s: &str) -> anyhow::Result<usize> {
-    parse_size::Config::new()
-        .with_binary()
-        .parse_size(s)
-        .map(|x| x as usize)
-        .map_err(|e| anyhow::anyhow!("failed to parse {}: {:?}", s, e))
-}
+#[derive(Parser, Clone)]
+#[command(author, version, about, long_about=None)]
+struct Args {
+    #[command(flatten)]
+    common: ClientArgs,
 
-fn time_parser(s: &str) -> anyhow::Result<NaiveDateTime> {
-    let today = Local::now().date_naive();
-    let time =
-        NaiveTime::parse_from_str(s, "%H:%M:%S").context("failed to parse start timestamp")?;
-    Ok(NaiveDateTime::new(today, time))
+    #[command(flatten)]
+    load: LoadArgs,
 }
 
-fn duration_parser(s: &str) -> anyhow::Result<u64> {
-    humantime::parse_duration(s)
-        .map(|s| s.as_secs())
-        .context("failed to parse duration")
-}
+/// A connected stream: TCP (with or without TLS), or a Unix domain socket.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Sized + 'static {
+    type ReadHalf: AsyncRead + Unpin + Send;
+    type WriteHalf: AsyncWrite + Unpin + Send;
+    /// Shared by every connection.
+    type Connector: Sync;
 
-#[derive(Clone, Copy, clap::ValueEnum)]
-enum ClientType {
-    Bursty,
-    Closed,
-}
+    /// Connect, with the `socket` options set.
+    async fn connect(
+        endpoint: &Endpoint,
+        socket: &SocketArgs,
+        connector: &Self::Connector,
+    ) -> std::io::Result<Self>;
 
-#[derive(Parser, Clone)]
-#[command(author, version, about, long_about=None)]
-struct Args {
-    #[arg(default_value = "[::1]")]
-    host: String,
+    /// Identifies the connection of `worker` in the output.
+    fn id(&self, worker: usize) -> std::io::Result<String>;
+
+    fn into_split(self) -> (Self::ReadHalf, Self::WriteHalf);
+}
 
-    #[arg(default_value_t = 9095, value_parser = clap::value_parser!(u16).range(1..))]
-    port: u16,
+impl Stream for TcpStream {
+    type ReadHalf = tcp::OwnedReadHalf;
+    type WriteHalf = tcp::OwnedWriteHalf;
+    type Connector = ();
+
+    async fn connect(
+        endpoint: &Endpoint,
+        socket: &SocketArgs,
+        _connector: &(),
+    ) -> std::io::Result<Self> {
+        let stream = match endpoint {
+            Endpoint::Tcp(addr) => TcpStream::connect(addr).await?,
+            Endpoint::Unix(_) => return Err(std::io::ErrorKind::InvalidInput.into()),
+        };
+        socket.apply(&stream)?;
+        Ok(stream)
+    }
 
-    #[arg(short = 'j', long)]
-    n_cores: Option<usize>,
+    fn id(&self, _worker: usize) -> std::io::Result<String> {
+        Ok(self.local_addr()?.to_string())
+    }
 
-    #[arg(short, long, default_value = "60s", value_parser = duration_parser)]
-    duration: u64,
+    fn into_split(self) -> (tcp::OwnedReadHalf, tcp::OwnedWriteHalf) {
+        TcpStream::into_split(self)
+    }
+}
 
-    #[arg(short, long, default_value = "10s", value_parser = duration_parser)]
-    warmup: u64,
+impl Stream for UnixStream {
+    type ReadHalf = unix::OwnedReadHalf;
+    type WriteHalf = unix::OwnedWriteHalf;
+    type Connector = ();
+
+    async fn connect(
+        endpoint: &Endpoint,
+        socket: &SocketArgs,
+        _connector: &(),
+    ) -> std::io::Result<Self> {
+        let stream = match endpoint {
+            Endpoint::Unix(path) => UnixStream::connect(path).await?,
+            Endpoint::Tcp(_) => return Err(std::io::ErrorKind::InvalidInput.into()),
+        };
+        socket.apply(&stream)?;
+        Ok(stream)
+    }
 
-    #[arg(short, long, default_value_t = 1, value_parser = size_parser)]
-    message_size: usize,
+    /// Clients are unnamed: connections are identified by worker.
+    fn id(&self, worker: usize) -> std::io::Result<String> {
+        let path = self.peer_addr()?;
+        let path = path.as_pathname().unwrap_or(std::path::Path::new(""));
+        Ok(format!("{}#{}", path.display(), worker))
+    }
 
-    #[arg(short, long, value_parser = time_parser)]
-    start: Option<NaiveDateTime>,
+    fn into_split(self) -> (unix::OwnedReadHalf, unix::OwnedWriteHalf) {
+        UnixStream::into_split(self)
+    }
+}
 
-    #[arg(short, long)]
-    client_type: ClientType,
+/// What TLS connections need, with `--tls`.
+struct Tls {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
 }
-impl Args {
-    fn duration(&self) -> Duration {
-        return Duration::from_secs(self.duration);
+
+impl Stream for TlsStream<TcpStream> {
+    type ReadHalf = tokio::io::ReadHalf<Self>;
+    type WriteHalf = tokio::io::WriteHalf<Self>;
+    type Connector = Tls;
+
+    async fn connect(endpoint: &Endpoint, socket: &SocketArgs, tls: &Tls) -> std::io::Result<Self> {
+        let stream = <TcpStream as Stream>::connect(endpoint, socket, &()).await?;
+        tls.connector.connect(tls.server_name.clone(), stream).await
     }
-    fn warmup(&self) -> Duration {
-        return Duration::from_secs(self.warmup);
+
+    fn id(&self, worker: usize) -> std::io::Result<String> {
+        self.get_ref().0.id(worker)
+    }
+
+    fn into_split(self) -> (Self::ReadHalf, Self::WriteHalf) {
+        // the halves share the TLS session
+        tokio::io::split(self)
     }
 }
 
-async fn do_run(stream: Arc<Mutex<TcpStream>>, message_size: usize) -> anyhow::Result<Duration> {
-    let mut buffer = [42; BUFFER_SIZE];
+/// Echo a message, reading the reply while the message is written: the server only reads so
+/// much ahead (see `protocol::READ_AHEAD`).
+async fn do_run<S: Stream>(
+    halves: Arc<Mutex<(S::ReadHalf, S::WriteHalf)>>,
+    message: Message,
+    framed: bool,
+) -> anyhow::Result<Duration> {
+    // generated up front, so that only the echo is timed
+    let request = protocol::encode(message, framed);
+    let mut halves = halves.lock().await;
+    let (read_half, write_half) = &mut *halves;
     let start = tokio::time::Instant::now();
-    let mut need_to_write = message_size;
-    while need_to_write > 0 {
-        let n = stream
-            .lock()
-            .await
-            .write(&buffer[..std::cmp::min(need_to_write, BUFFER_SIZE)])
-            .await?;
-        need_to_write -= n;
+    let written = async { write_half.write_all(&request).await.map_err(Into::into) };
+    tokio::try_join!(written, read_reply(read_half, message, framed))?;
+    Ok(start.elapsed())
+}
+
+/// Read the reply to `message`, and check it.
+async fn read_reply(
+    read_half: &mut (impl AsyncRead + Unpin),
+    mut reply: Message,
+    framed: bool,
+) -> anyhow::Result<()> {
+    let mut buffer = [0; BUFFER_SIZE];
+    if framed {
+        let mut header = [0; FRAME_HEADER_LEN];
+        read_half.read_exact(&mut header).await?;
+        protocol::check_frame(header, reply.size())?;
     }
-    let mut waiting_for = message_size;
+    let mut waiting_for = reply.size();
     while waiting_for > 0 {
-        buffer.fill(0);
-        let n = stream
-            .lock()
-            .await
-            .read(&mut buffer[..std::cmp::min(waiting_for, BUFFER_SIZE)])
-            .await?;
-        if !buffer[..n].iter().all(|x| *x == 42) {
-            return Err(anyhow::anyhow!("mismatched reply"));
-        }
+        let n = std::cmp::min(waiting_for, BUFFER_SIZE);
+        read_half.read_exact(&mut buffer[..n]).await?;
+        reply.check(&buffer[..n])?;
         waiting_for -= n;
     }
-    Ok(start.elapsed())
+    Ok(())
 }
 
-async fn closed_client(id: &str, args: &Args) -> anyhow::Result<()> {
-    let mut stream = TcpStream::connect(format!("{}:{}", args.host, args.port))
+async fn connect<S: Stream>(
+    report: &Report,
+    args: &ClientArgs,
+    connector: &S::Connector,
+) -> anyhow::Result<S> {
+    let endpoint = args.endpoint();
+    let start = Instant::now();
+    let mut stream = S::connect(&endpoint, &args.socket, connector)
         .await
-        .context(format!("failed to connect to {}:{}", args.host, args.port))?;
-    tracing::info!("connected @ {}:{}", args.host, args.port);
+        .context(format!("failed to connect to {}", endpoint))?;
+    if args.tls.enabled() {
+        // TCP and TLS handshakes
+        report.handshake(start.elapsed());
+    }
+    tracing::info!("connected @ {}", endpoint);
+
+    let hello = args.hello();
+    let granted = tokio::time::timeout(
+        protocol::HANDSHAKE_TIMEOUT,
+        protocol::connect_async(&mut stream, &hello),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("no handshake reply from the server"))
+    .and_then(|granted| granted)
+    .with_context(|| format!("handshake with {} failed", endpoint))?;
+    protocol::require(&hello, granted)?;
+
+    Ok(stream)
+}
 
-    let size = args.message_size.to_be_bytes();
-    stream.write_all(&size).await?;
+async fn closed_client<S: Stream>(
+    report: &Report,
+    args: &ClientArgs,
+    connector: &S::Connector,
+    worker: usize,
+) -> anyhow::Result<()> {
+    let stream = connect::<S>(report, args, connector).await?;
+    let recorder = report.recorder(worker, stream.id(worker)?);
+    let halves = Arc::new(Mutex::new(stream.into_split()));
+    let framed = args.hello().framed_messages();
+    echo_common::closed_loop_async(recorder, |message| {
+        do_run::<S>(halves.clone(), message, framed)
+    })
+    .await
+}
 
-    let mut reporting = false;
-    let stream = Arc::new(Mutex::new(stream));
-    let start = std::time::Instant::now();
+/// Match replies to the messages announced on `sent`, in FIFO order, check them, and record
+/// their latency from the instant each was sent (or scheduled). `T` is dropped once its reply
+/// is in.
+async fn read_replies<T>(
+    read_half: &mut (impl AsyncRead + Unpin),
+    mut sent: mpsc::UnboundedReceiver<(Instant, Message, T)>,
+    recorder: &mut Recorder<'_>,
+    framed: bool,
+) -> anyhow::Result<()> {
+    while let Some((start, reply, _)) = sent.recv().await {
+        read_reply(read_half, reply, framed).await?;
+        recorder.record(start.elapsed(), reply.size());
+    }
+    Ok(())
+}
 
-    while start.elapsed() < args.duration() + args.warmup() {
-        let elapsed = do_run(stream.clone(), args.message_size).await?;
-        if !reporting && start.elapsed() > args.warmup() {
-            reporting = true;
-            println!("Start: {} {:.9}", id, start.elapsed().as_secs_f64());
+/// Open-loop worker: the writer sends messages on schedule, never waiting for replies, and
+/// the reader matches replies to their scheduled send instants in FIFO order.
+async fn open_client<S: Stream>(
+    report: &Report,
+    args: &ClientArgs,
+    connector: &S::Connector,
+    worker: usize,
+    rate: f64,
+) -> anyhow::Result<()> {
+    let stream = connect::<S>(report, args, connector).await?;
+    let mut recorder = report.recorder(worker, stream.id(worker)?);
+    let (mut read_half, mut write_half) = stream.into_split();
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    let deadline = Instant::from_std(recorder.deadline());
+    let mut sizes = recorder.sizes()?;
+    let mut payload = recorder.payload();
+    let framed = args.hello().framed_messages();
+
+    let writer = async move {
+        for scheduled in Arrivals::new(rate)?.map(Instant::from_std) {
+            if scheduled >= deadline {
+                break;
+            }
+            let message = payload.next(sizes.next().expect("sizes never run out"));
+            let request = protocol::encode(message, framed);
+            tokio::time::sleep_until(scheduled).await;
+            write_half.write_all(&request).await?;
+            // the reader has failed, and will report why
+            if tx.send((scheduled, message, ())).is_err() {
+                break;
+            }
         }
+        Ok::<(), anyhow::Error>(())
+    };
+    let reader = read_replies(&mut read_half, rx, &mut recorder, framed);
+
+    tokio::try_join!(writer, reader)?;
+    recorder.finish();
 
-        if reporting && start.elapsed() < args.duration() + args.warmup() {
-            println!("{:.3}", elapsed.as_secs_f64() * 1_000_000f64);
+    Ok(())
+}
+
+/// Pipelined worker: the writer keeps up to `depth` messages outstanding, and the reader
+/// matches replies to them in FIFO order.
+async fn pipelined_client<S: Stream>(
+    report: &Report,
+    args: &ClientArgs,
+    connector: &S::Connector,
+    worker: usize,
+    depth: usize,
+) -> anyhow::Result<()> {
+    let stream = connect::<S>(report, args, connector).await?;
+    let mut recorder = report.recorder(worker, stream.id(worker)?);
+    let (mut read_half, mut write_half) = stream.into_split();
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    let deadline = Instant::from_std(recorder.deadline());
+    let mut sizes = recorder.sizes()?;
+    let mut payload = recorder.payload();
+    let framed = args.hello().framed_messages();
+    let outstanding = Arc::new(Semaphore::new(depth));
+
+    let writer = async move {
+        while Instant::now() < deadline {
+            // released by the reader, once the reply is in
+            let slot = outstanding.clone().acquire_owned().await?;
+            let message = payload.next(sizes.next().expect("sizes never run out"));
+            let request = protocol::encode(message, framed);
+            let start = Instant::now();
+            write_half.write_all(&request).await?;
+            // the reader has failed, and will report why
+            if tx.send((start, message, slot)).is_err() {
+                break;
+            }
         }
-    }
+        Ok::<(), anyhow::Error>(())
+    };
+    let reader = read_replies(&mut read_half, rx, &mut recorder, framed);
 
-    println!("End: {} {:.9}", id, start.elapsed().as_secs_f64());
-    drop(stream);
+    tokio::try_join!(writer, reader)?;
+    recorder.finish();
 
     Ok(())
 }
 
-async fn run_closed(id: String, args: Args) -> anyhow::Result<()> {
-    let paralellism = args.n_cores.unwrap_or_else(|| num_cpus::get());
-    let runners = (0..paralellism)
-        .into_iter()
-        .map(|_| closed_client(&id, &args))
+async fn run_open<S: Stream>(
+    report: &Report,
+    args: ClientArgs,
+    connector: &S::Connector,
+    rate: f64,
+) -> anyhow::Result<()> {
+    let parallelism = args.parallelism();
+    report.rate(rate);
+    let runners = (0..parallelism)
+        .map(|worker| open_client::<S>(report, &args, connector, worker, rate / parallelism as f64))
         .collect::<Vec<_>>();
     futures::future::join_all(runners)
         .await
@@ -146,51 +338,129 @@ async fn run_closed(id: String, args: Args) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn run_bursty(id: String, args: Args) -> anyhow::Result<()> {
-    let paralellism = args.n_cores.unwrap_or_else(|| num_cpus::get());
-    let mut stream = TcpStream::connect(format!("{}:{}", args.host, args.port))
+async fn run_closed<S: Stream>(
+    report: &Report,
+    args: ClientArgs,
+    connector: &S::Connector,
+) -> anyhow::Result<()> {
+    let runners = (0..args.parallelism())
+        .map(|worker| closed_client::<S>(report, &args, connector, worker))
+        .collect::<Vec<_>>();
+    futures::future::join_all(runners)
         .await
-        .context(format!("failed to connect to {}:{}", args.host, args.port))?;
-    tracing::info!("connected @ {}:{}", args.host, args.port);
+        .into_iter()
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
-    let size = args.message_size.to_be_bytes();
-    stream.write_all(&size).await?;
+    Ok(())
+}
 
-    let mut reporting = false;
-    let stream = Arc::new(Mutex::new(stream));
+async fn run_pipelined<S: Stream>(
+    report: &Report,
+    args: ClientArgs,
+    connector: &S::Connector,
+    depth: usize,
+) -> anyhow::Result<()> {
+    report.pipeline(depth);
+    let runners = (0..args.parallelism())
+        .map(|worker| pipelined_client::<S>(report, &args, connector, worker, depth))
+        .collect::<Vec<_>>();
+    futures::future::join_all(runners)
+        .await
+        .into_iter()
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
-    let start = tokio::time::Instant::now();
-    while start.elapsed() < args.duration() + args.warmup() {
-        if !reporting && start.elapsed() > args.warmup() {
-            reporting = true;
-            println!("Start: {} {:.9}", id, start.elapsed().as_secs_f64());
+    Ok(())
+}
+
+/// Push out bursts of `burst_size` requests; with a `period`, bursts start once per period (see
+/// `Bursts` for the ones that overrun it), otherwise each burst starts as soon as the previous
+/// one is over.
+async fn run_bursty<S: Stream>(
+    report: &Report,
+    args: ClientArgs,
+    connector: &S::Connector,
+    burst_size: usize,
+    period: Option<Duration>,
+) -> anyhow::Result<()> {
+    let stream = connect::<S>(report, &args, connector).await?;
+    let mut recorder = report.recorder(0, stream.id(0)?);
+    let (mut read_half, mut write_half) = stream.into_split();
+    let mut sizes = recorder.sizes()?;
+    let mut payload = recorder.payload();
+    let framed = args.hello().framed_messages();
+
+    let mut bursts = period.map(Bursts::new);
+
+    while recorder.running() {
+        if let Some(bursts) = bursts.as_mut() {
+            bursts.tick().await;
         }
-        let futs = (0..paralellism)
-            .into_iter()
-            .map(|_| do_run(stream.clone(), args.message_size))
-            .collect::<Vec<_>>();
 
-        futures::future::join_all(futs)
-            .await
-            .into_iter()
-            .map(|x| {
-                x.map(|elapsed| {
-                    if reporting && start.elapsed() < args.duration() + args.warmup() {
-                        println!("{:.3}", elapsed.as_secs_f64() * 1_000_000f64);
-                    }
-                })
-            })
-            .collect::<anyhow::Result<Vec<()>>>()?;
-    }
-    println!("End: {} {:.9}", id, start.elapsed().as_secs_f64());
+        // the whole burst is written while the replies are read, as a pipeline
+        let (tx, rx) = mpsc::unbounded_channel();
+        let writer = async {
+            for _ in 0..burst_size {
+                let message = payload.next(sizes.next().expect("sizes never run out"));
+                let request = protocol::encode(message, framed);
+                let start = Instant::now();
+                write_half.write_all(&request).await?;
+                if tx.send((start, message, ())).is_err() {
+                    break;
+                }
+            }
+            drop(tx);
+            Ok::<(), anyhow::Error>(())
+        };
+        let reader = read_replies(&mut read_half, rx, &mut recorder, framed);
+        tokio::try_join!(writer, reader)?;
+    }
+    if let Some(skipped) = bursts.map(|bursts| bursts.skipped()).filter(|n| *n > 0) {
+        tracing::warn!(
+            "{} bursts skipped, as earlier ones overran the burst period",
+            skipped
+        );
+    }
+    recorder.finish();
 
     Ok(())
 }
 
-async fn run(id: String, args: Args) -> anyhow::Result<()> {
-    match args.client_type {
-        ClientType::Bursty => run_bursty(id, args).await,
-        ClientType::Closed => run_closed(id, args).await,
+async fn run<S: Stream>(
+    report: &Report,
+    args: Args,
+    connector: &S::Connector,
+) -> anyhow::Result<()> {
+    match args.load.client_type {
+        ClientType::Bursty => {
+            let burst_size = args.common.parallelism();
+            run_bursty::<S>(report, args.common, connector, burst_size, None).await
+        }
+        ClientType::ControlledBursty => {
+            let burst_size = args
+                .load
+                .burst_size
+                .unwrap_or_else(|| args.common.parallelism());
+            let period = args.load.burst_period.ok_or_else(|| {
+                anyhow::anyhow!("controlled bursty clients need a --burst-period")
+            })?;
+            report.burst(burst_size, period);
+            run_bursty::<S>(report, args.common, connector, burst_size, Some(period)).await
+        }
+        ClientType::Closed => run_closed::<S>(report, args.common, connector).await,
+        ClientType::Open => {
+            let rate = args
+                .load
+                .rate
+                .ok_or_else(|| anyhow::anyhow!("open-loop clients need a --rate"))?;
+            run_open::<S>(report, args.common, connector, rate).await
+        }
+        ClientType::Pipelined => {
+            let depth = args
+                .load
+                .pipeline_depth
+                .ok_or_else(|| anyhow::anyhow!("pipelined clients need a --pipeline-depth"))?;
+            run_pipelined::<S>(report, args.common, connector, depth as usize).await
+        }
     }
 }
 
@@ -199,35 +469,111 @@ fn main() -> anyhow::Result<()> {
         .with_max_level(tracing::Level::INFO)
         .with_writer(std::io::stderr)
         .init();
-    let args = Args::parse();
-    let id = format!(
-        "{}:{:x}",
-        gethostname::gethostname()
-            .into_string()
-            .map_err(|os_str| anyhow::anyhow!("failed to convert hostname: {:?}", os_str))?,
-        uuid::Uuid::new_v4().simple()
-    );
-
-    let rt = if let Some(n_cores) = args.n_cores {
-        tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .worker_threads(n_cores)
-            .build()
-            .unwrap()
-    } else {
-        tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .build()
-            .unwrap()
-    };
+    let args: Args = echo_common::cli::parse("9095");
+    args.load.check(&args.common)?;
+    let report = Report::new(&args.common)?;
+    let tls = args.common.tls.clone();
+
+    let rt = echo_common::runtime(args.common.n_cores)?;
+
+    report.header();
+    echo_common::wait_for_start(args.common.start);
+    match args.common.endpoint() {
+        Endpoint::Tcp(_) if tls.enabled() => {
+            let config = Credentials::client(&tls)?
+                .client_config(tls.mtls)
+                .context("failed to set up TLS")?;
+            let tls = Tls {
+                connector: TlsConnector::from(config),
+                server_name: tls::server_name(&args.common.host)?,
+            };
+            rt.block_on(run::<TlsStream<TcpStream>>(&report, args, &tls))?
+        }
+        Endpoint::Tcp(_) => rt.block_on(run::<TcpStream>(&report, args, &()))?,
+        Endpoint::Unix(_) => {
+            tls.reject("over Unix domain sockets")?;
+            rt.block_on(run::<UnixStream>(&report, args, &()))?
+        }
+    }
+
+    report.summary()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+    use tokio::net::UnixListener;
+
+    /// As `cli::parse`, with the host given.
+    fn args(args: &[&str]) -> Args {
+        let matches = Args::command()
+            .mut_arg("port", |arg| arg.required(false).default_value("9095"))
+            .try_get_matches_from(["client"].iter().chain(args))
+            .unwrap();
+        Args::from_arg_matches(&matches).unwrap()
+    }
 
-    println!("Message Size: {}", args.message_size);
-    if let Some(start) = args.start {
-        let now_ts = Local::now().timestamp_nanos_opt().ok_or(anyhow::anyhow!("an i64 can represent stuff until 2262. if you are still using this code in 2262 first of all, thanks i guess; second, i don't really care, probably fix this"))?;
-        let start_ts = start.timestamp_nanos_opt().ok_or(anyhow::anyhow!("an i64 can represent stuff until 2262. if you are still using this code in 2262 first of all, thanks i guess; second, i don't really care, probably fix this"))?;
-        if now_ts < start_ts {
-            std::thread::sleep(std::time::Duration::from_nanos((start_ts - now_ts) as u64));
+    /// Echo what comes in back, `batch` bytes at a time: the bytes of a batch that does not
+    /// fill up within 100ms are echoed all the same. Returns how many batches were full, and
+    /// how many were not.
+    async fn echo_in_batches(listener: UnixListener, batch: usize) -> anyhow::Result<(u64, u64)> {
+        let (mut stream, _) = listener.accept().await?;
+        protocol::accept_async(&mut stream).await?;
+        let (mut full, mut partial) = (0, 0);
+        loop {
+            let mut buffer = vec![0; batch];
+            let mut n = 0;
+            while n < batch {
+                let read = stream.read(&mut buffer[n..]);
+                match tokio::time::timeout(Duration::from_millis(100), read).await {
+                    Ok(Ok(0)) | Err(_) => break,
+                    Ok(read) => n += read?,
+                }
+            }
+            if n == 0 {
+                return Ok((full, partial));
+            }
+            stream.write_all(&buffer[..n]).await?;
+            if n == batch {
+                full += 1;
+            } else {
+                partial += 1;
+            }
         }
     }
-    rt.block_on(run(id, args))
+
+    #[tokio::test]
+    async fn pipelined_windows_are_refilled() {
+        let path = std::env::temp_dir().join(format!("rust_async-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = tokio::spawn(echo_in_batches(UnixListener::bind(&path).unwrap(), 4 * 8));
+
+        let host = format!("unix:{}", path.display());
+        let args = args(&[
+            &host,
+            "-c",
+            "pipelined",
+            "--pipeline-depth",
+            "4",
+            "-j",
+            "1",
+            "-d",
+            "1s",
+            "-w",
+            "0s",
+            "-m",
+            "8",
+        ]);
+        let report = Report::new(&args.common).unwrap();
+        run_pipelined::<UnixStream>(&report, args.common, &(), 4)
+            .await
+            .unwrap();
+        let (full, partial) = server.await.unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // four messages were outstanding whenever the replies went out, but at the end
+        assert!(full > 1, "{}", full);
+        assert!(partial <= 1, "{}", partial);
+    }
 }
@@ -1,93 +1,434 @@
 use clap::Parser;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{tcp, unix};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::watch;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use std::future::{self, Future, Ready};
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+use std::thread;
 
 use anyhow::Context;
+use echo_common::endpoint::{self, Endpoint};
+use echo_common::per_core::{self, ServerMode};
+use echo_common::protocol::{self, Hello, FRAME_HEADER_LEN};
+use echo_common::shutdown::Signal;
+use echo_common::tls::Credentials;
+use echo_common::{shutdown, ServerArgs, ServerStats, SocketArgs};
 
 const BUFFER_SIZE: usize = 1 << 16;
 
+/// Chunks queued between the reader and the writer of a connection: once the queue is full,
+/// the reader waits for the client to read the echo.
+const QUEUE_LEN: usize = protocol::READ_AHEAD / BUFFER_SIZE;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about=None)]
 struct Args {
-    #[arg(default_value = "[::1]")]
-    host: String,
+    #[command(flatten)]
+    common: ServerArgs,
+
+    /// Serve on a single work-stealing runtime, or on a pinned current-thread runtime per core
+    /// with its own listener.
+    #[arg(long, value_enum, default_value_t = ServerMode::Shared)]
+    mode: ServerMode,
+}
+
+/// A listener: TCP (with or without TLS), or a Unix domain socket.
+trait Listener {
+    type Stream: Stream;
+    /// Whatever is left to set up the connection (the TLS handshake), which happens in the
+    /// task of the connection rather than in the accept loop.
+    type Handshake: Future<Output = io::Result<Self::Stream>> + Send + 'static;
 
-    #[arg(default_value_t = 9095, value_parser = clap::value_parser!(u16).range(1..))]
-    port: u16,
+    /// Accept a connection, with the `socket` options set, along with a description of the peer.
+    async fn accept(&self, socket: &SocketArgs) -> io::Result<(Self::Handshake, String)>;
+}
 
-    #[arg(short = 'j', long)]
-    n_cores: Option<usize>,
+/// A connected stream, which splits into halves that can be used concurrently.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    type ReadHalf<'a>: AsyncRead + Unpin + Send;
+    type WriteHalf<'a>: AsyncWrite + Unpin + Send;
+
+    fn split(&mut self) -> (Self::ReadHalf<'_>, Self::WriteHalf<'_>);
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+    type Handshake = Ready<io::Result<TcpStream>>;
+
+    async fn accept(&self, socket: &SocketArgs) -> io::Result<(Self::Handshake, String)> {
+        let (stream, addr) = TcpListener::accept(self).await?;
+        socket.apply(&stream)?;
+        Ok((future::ready(Ok(stream)), addr.to_string()))
+    }
 }
 
-async fn handle_client(mut stream: TcpStream) -> anyhow::Result<()> {
-    let mut data = [0 as u8; BUFFER_SIZE];
-    let mut size_buffer = [0; 8];
-    stream.read_exact(&mut size_buffer).await?;
-    let message_size = usize::from_be_bytes(size_buffer);
+impl Stream for TcpStream {
+    type ReadHalf<'a> = tcp::ReadHalf<'a>;
+    type WriteHalf<'a> = tcp::WriteHalf<'a>;
 
+    fn split(&mut self) -> (tcp::ReadHalf<'_>, tcp::WriteHalf<'_>) {
+        TcpStream::split(self)
+    }
+}
+
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+    type Handshake = Ready<io::Result<UnixStream>>;
+
+    async fn accept(&self, socket: &SocketArgs) -> io::Result<(Self::Handshake, String)> {
+        // clients are unnamed
+        let (stream, addr) = UnixListener::accept(self).await?;
+        socket.apply(&stream)?;
+        Ok((future::ready(Ok(stream)), format!("{:?}", addr)))
+    }
+}
+
+impl Stream for UnixStream {
+    type ReadHalf<'a> = unix::ReadHalf<'a>;
+    type WriteHalf<'a> = unix::WriteHalf<'a>;
+
+    fn split(&mut self) -> (unix::ReadHalf<'_>, unix::WriteHalf<'_>) {
+        UnixStream::split(self)
+    }
+}
+
+/// A TCP listener with `--tls`.
+struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+}
+
+impl Listener for TlsListener {
+    type Stream = TlsStream<TcpStream>;
+    type Handshake = tokio_rustls::Accept<TcpStream>;
+
+    async fn accept(&self, socket: &SocketArgs) -> io::Result<(Self::Handshake, String)> {
+        let (stream, addr) = self.listener.accept().await?;
+        socket.apply(&stream)?;
+        Ok((self.acceptor.accept(stream), addr.to_string()))
+    }
+}
+
+impl Stream for TlsStream<TcpStream> {
+    type ReadHalf<'a> = tokio::io::ReadHalf<&'a mut Self>;
+    type WriteHalf<'a> = tokio::io::WriteHalf<&'a mut Self>;
+
+    fn split(&mut self) -> (Self::ReadHalf<'_>, Self::WriteHalf<'_>) {
+        // the halves share the TLS session
+        tokio::io::split(self)
+    }
+}
+
+/// Echo messages back until the client is done, or until the server stops after a message.
+///
+/// Reading and writing are decoupled: the reader hands chunks over to the writer through
+/// a small queue, so the echo of a message starts while the client still writes it, and
+/// memory stays bounded whatever the message size. A client that only reads its reply after
+/// writing the full message must keep it within `protocol::READ_AHEAD`.
+async fn handle_client<S: Stream>(
+    handshake: impl Future<Output = io::Result<S>>,
+    peer_addr: &str,
+    stats: &ServerStats,
+    stopped: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut stream = handshake.await.context("failed to set up connection")?;
+    let hello = protocol::accept_async(&mut stream).await?;
+
+    let (reader, mut writer) = stream.split();
+    let (chunks, queue) = mpsc::channel(QUEUE_LEN);
+
+    tokio::try_join!(
+        read_chunks(reader, &hello, chunks, stats, stopped),
+        write_chunks(&mut writer, queue, stats),
+    )
+    .with_context(|| {
+        format!(
+            "An error occurred, terminating connection with {}",
+            peer_addr
+        )
+    })?;
+    Ok(())
+}
+
+/// Read whole messages in chunks of at most `BUFFER_SIZE` bytes of payload, which never span
+/// messages; the last chunk of a message is flagged. The header of a frame is echoed along
+/// with the first chunk of its payload, and is not counted as payload.
+async fn read_chunks(
+    mut reader: impl AsyncRead + Unpin,
+    hello: &Hello,
+    chunks: Sender<(Vec<u8>, usize, bool)>,
+    stats: &ServerStats,
+    mut stopped: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
     loop {
+        let mut header = Vec::new();
+        let message_size = if hello.framed_messages() {
+            let mut buf = [0; FRAME_HEADER_LEN];
+            if !read_message(&mut reader, &mut buf, &mut stopped).await? {
+                return Ok(());
+            }
+            header.extend_from_slice(&buf);
+            protocol::frame_size(buf, hello.message_size)?
+        } else {
+            hello.message_size
+        };
+
         let mut to_read = message_size;
         while to_read > 0 {
-            match stream.read(&mut data).await {
-                Ok(size) => {
-                    stream
-                        .write(&data[0..size])
-                        .await
-                        .context("failed to echo")?;
-                    to_read -= size;
-                }
-                Err(e) => {
-                    let err = Err(e).context(format!(
-                        "An error occurred, terminating connection with {}",
-                        stream.peer_addr().unwrap()
-                    ));
-                    stream.shutdown().await.unwrap();
-                    return err;
-                }
+            let n = std::cmp::min(to_read, BUFFER_SIZE);
+            let mut chunk = std::mem::take(&mut header);
+            let offset = chunk.len();
+            chunk.resize(offset + n, 0);
+            if offset > 0 || to_read < message_size {
+                reader
+                    .read_exact(&mut chunk[offset..])
+                    .await
+                    .context("failed to read")?;
+            } else if !read_message(&mut reader, &mut chunk, &mut stopped).await? {
+                return Ok(());
+            }
+            stats.bytes_in(n);
+            to_read -= n;
+            if chunks.send((chunk, n, to_read == 0)).await.is_err() {
+                // the writer failed, and reports why
+                return Ok(());
             }
         }
     }
 }
 
-async fn run(args: Args) -> anyhow::Result<()> {
-    let listener = TcpListener::bind(format!("{}:{}", args.host, args.port)).await?;
-    tracing::info!("server listening on {}:{}", args.host, args.port);
+/// Wait for the next message, and fill `buf` with its start; returns whether there is one,
+/// rather than the end of the stream (or a stop while waiting, which idle connections see at
+/// once).
+async fn read_message(
+    reader: &mut (impl AsyncRead + Unpin),
+    buf: &mut [u8],
+    stopped: &mut watch::Receiver<bool>,
+) -> anyhow::Result<bool> {
+    // a single read is cancel-safe
+    let read = tokio::select! {
+        biased;
+        read = reader.read(buf) => read,
+        _ = stopped.wait_for(|stopped| *stopped) => return Ok(false),
+    };
+    let n = match read {
+        // the client is done (without a `close_notify`, over TLS)
+        Ok(0) => return Ok(false),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+        read => read.context("failed to read")?,
+    };
+    reader
+        .read_exact(&mut buf[n..])
+        .await
+        .context("failed to read")?;
+    Ok(true)
+}
+
+/// Echo every chunk back, until the reader is done.
+async fn write_chunks(
+    writer: &mut (impl AsyncWrite + Unpin),
+    mut queue: Receiver<(Vec<u8>, usize, bool)>,
+    stats: &ServerStats,
+) -> anyhow::Result<()> {
+    while let Some((chunk, payload, last)) = queue.recv().await {
+        writer.write_all(&chunk).await.context("failed to echo")?;
+        stats.bytes_out(payload);
+        if last {
+            stats.message();
+        }
+    }
+    writer.shutdown().await.context("failed to shut down")
+}
+
+/// Accept connections until `signal`, then stop every connection: at once if it waits for a
+/// message, after its current message otherwise.
+async fn serve<L: Listener>(
+    listener: L,
+    socket: &SocketArgs,
+    stats: &Arc<ServerStats>,
+    signal: impl Future<Output = io::Result<()>>,
+) -> anyhow::Result<()> {
+    tokio::pin!(signal);
+    let (stop, stopped) = watch::channel(false);
 
     loop {
-        match listener.accept().await {
-            Ok((stream, socket_addr)) => {
-                tracing::info!("accepted new connection: {}", socket_addr);
+        let accepted = tokio::select! {
+            accepted = listener.accept(socket) => accepted,
+            signal = &mut signal => {
+                signal.context("failed to handle signals")?;
+                break;
+            }
+        };
+        match accepted {
+            Ok((handshake, peer_addr)) => {
+                tracing::info!("accepted new connection: {}", peer_addr);
+                let stats = Arc::clone(stats);
+                let stopped = stopped.clone();
                 tokio::spawn(async move {
+                    let _connection = stats.connection();
                     // connection succeeded
-                    if let Err(e) = handle_client(stream).await {
-                        tracing::warn!("failed to handle connection from {}: {:?}", socket_addr, e);
+                    if let Err(e) = handle_client(handshake, &peer_addr, &stats, stopped).await {
+                        stats.error(&*e);
+                        tracing::warn!("failed to handle connection from {}: {:?}", peer_addr, e);
                     }
                 });
             }
             Err(e) => {
+                stats.error(&e);
                 tracing::warn!("failed to accept connection: {}", e);
                 /* connection failed */
             }
         }
     }
+
+    tracing::info!("shutting down");
+    stop.send_replace(true);
+    Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-    let args = Args::parse();
+async fn run(args: ServerArgs) -> anyhow::Result<()> {
+    let stats = ServerStats::new();
+    if let Some(admin_addr) = args.admin_addr() {
+        stats.serve_admin(&admin_addr)?;
+        tracing::info!("serving stats on {}", admin_addr);
+    }
+    tracing::info!("socket options: {}", args.socket.describe());
 
-    let rt = if let Some(n_cores) = args.n_cores {
-        tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .worker_threads(n_cores)
-            .build()
-            .unwrap()
+    match args.endpoint() {
+        Endpoint::Tcp(addr) => {
+            let listener = endpoint::tcp_listener_async(&addr, args.backlog, false)
+                .with_context(|| format!("failed to bind {}", addr))?;
+            tracing::info!("server listening on {}", addr);
+            stats.listening();
+            if args.tls.enabled() {
+                let config = Credentials::server(&args.tls, &args.host)?
+                    .server_config(args.tls.mtls)
+                    .context("failed to set up TLS")?;
+                let acceptor = TlsAcceptor::from(config);
+                let listener = TlsListener { listener, acceptor };
+                serve(listener, &args.socket, &stats, shutdown::signal()).await?;
+            } else {
+                serve(listener, &args.socket, &stats, shutdown::signal()).await?;
+            }
+        }
+        Endpoint::Unix(path) => {
+            args.tls.reject("over Unix domain sockets")?;
+            endpoint::remove_stale_socket(&path)?;
+            let listener = endpoint::unix_listener_async(&path, args.backlog)
+                .with_context(|| format!("failed to bind {}", path.display()))?;
+            tracing::info!("server listening on {}", path.display());
+            stats.listening();
+            serve(listener, &args.socket, &stats, shutdown::signal()).await?;
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+
+    let left = shutdown::drain_async(&stats, args.drain_timeout).await;
+    tracing::info!("drained connections, {} left", left);
+    shutdown::finish(&stats, left)
+}
+
+/// `--mode thread-per-core`: a current-thread runtime per core, on a thread pinned to it, which
+/// accepts on its own listener and runs the connections it accepted.
+fn thread_per_core(args: ServerArgs) -> anyhow::Result<()> {
+    let stats = ServerStats::new();
+    if let Some(admin_addr) = args.admin_addr() {
+        stats.serve_admin(&admin_addr)?;
+        tracing::info!("serving stats on {}", admin_addr);
+    }
+    tracing::info!("socket options: {}", args.socket.describe());
+
+    let addr = match args.endpoint() {
+        Endpoint::Tcp(addr) => addr,
+        Endpoint::Unix(_) => {
+            return Err(anyhow::anyhow!(
+                "--mode thread-per-core is not supported over Unix domain sockets"
+            ))
+        }
+    };
+    let acceptor = if args.tls.enabled() {
+        let config = Credentials::server(&args.tls, &args.host)?
+            .server_config(args.tls.mtls)
+            .context("failed to set up TLS")?;
+        Some(TlsAcceptor::from(config))
     } else {
-        tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .build()
-            .unwrap()
+        None
     };
 
-    rt.block_on(run(args))
+    let cores = per_core::cores(args.parallelism())?;
+    // set up up front, so that the threads cannot fail before the signal
+    let mut runtimes = Vec::with_capacity(cores.len());
+    for _ in &cores {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let listener = {
+            let _guard = rt.enter();
+            endpoint::tcp_listener_async(&addr, args.backlog, true)
+                .with_context(|| format!("failed to bind {}", addr))?
+        };
+        runtimes.push((rt, listener));
+    }
+    tracing::info!("server listening on {} on {} cores", addr, cores.len());
+    stats.listening();
+
+    let signal = Signal::new().context("failed to handle signals")?;
+    let (stop, stopped) = watch::channel(false);
+    thread::scope(|scope| {
+        for (core, (rt, listener)) in cores.into_iter().zip(runtimes) {
+            let acceptor = acceptor.clone();
+            let mut stopped = stopped.clone();
+            let (socket, stats) = (&args.socket, &stats);
+            let drain_timeout = args.drain_timeout;
+            scope.spawn(move || {
+                if let Err(e) = per_core::pin(core) {
+                    tracing::warn!("{}", e);
+                }
+                rt.block_on(async move {
+                    let signal = async move {
+                        let _ = stopped.wait_for(|stop| *stop).await;
+                        Ok(())
+                    };
+                    // the signal never fails
+                    let _ = match acceptor {
+                        Some(acceptor) => {
+                            let listener = TlsListener { listener, acceptor };
+                            serve(listener, socket, stats, signal).await
+                        }
+                        None => serve(listener, socket, stats, signal).await,
+                    };
+                    // the connections of this core run on this runtime: keep it up until they
+                    // are done (along with those of the other cores)
+                    shutdown::drain_async(stats, drain_timeout).await;
+                });
+            });
+        }
+
+        let signaled = signal.wait();
+        let _ = stop.send(true);
+        signaled.context("failed to handle signals")
+    })?;
+
+    let left = stats.active();
+    tracing::info!("drained connections, {} left", left);
+    shutdown::finish(&stats, left)
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args: Args = echo_common::cli::parse("9095");
+
+    match args.mode {
+        ServerMode::Shared => {
+            let rt = echo_common::runtime(args.common.n_cores)?;
+            rt.block_on(run(args.common))
+        }
+        ServerMode::ThreadPerCore => thread_per_core(args.common),
+    }
 }
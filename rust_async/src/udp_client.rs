@@ -0,0 +1,205 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use clap::Parser;
+use echo_common::datagram::{self, DatagramStats, Tracker, MAX_DATAGRAM_LEN};
+use echo_common::{
+    Arrivals, ClientArgs, ClientType, DatagramArgs, LoadArgs, Message, Recorder, Report,
+};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Duration, Instant};
+
+#[derive(Parser, Clone)]
+#[command(author, version, about, long_about=None)]
+struct Args {
+    #[command(flatten)]
+    common: ClientArgs,
+
+    #[command(flatten)]
+    load: LoadArgs,
+
+    #[command(flatten)]
+    datagram: DatagramArgs,
+}
+
+/// How a worker offers load.
+#[derive(Clone, Copy)]
+enum Load {
+    /// Keep up to this many messages outstanding (one for a closed loop).
+    Window(usize),
+    /// Send messages on an open-loop schedule, at this rate.
+    Rate(f64),
+}
+
+/// Account for the messages announced on `sent` and for the replies received on `socket`,
+/// recording the latency of the replies received in time, until every message sent has been
+/// answered or lost. A slot is dropped once its message is answered or lost.
+async fn read_replies(
+    socket: &UdpSocket,
+    mut sent: mpsc::UnboundedReceiver<(Instant, Message, Option<OwnedSemaphorePermit>)>,
+    tracker: &mut Tracker<Option<OwnedSemaphorePermit>>,
+    recorder: &mut Recorder<'_>,
+) -> anyhow::Result<()> {
+    let mut buffer = vec![0; MAX_DATAGRAM_LEN];
+    let mut writing = true;
+    while writing || !tracker.idle() {
+        let deadline = tracker.next_deadline().map(Instant::from_std);
+        let expiry = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            // messages are announced before they are sent: track them before their replies
+            biased;
+            message = sent.recv(), if writing => match message {
+                Some((start, message, slot)) => tracker.track(start.into_std(), message, slot),
+                None => writing = false,
+            },
+            received = socket.recv(&mut buffer) => {
+                let n = received.context("failed to receive")?;
+                let now = std::time::Instant::now();
+                if let Some((elapsed, size)) = tracker.receive(&buffer[..n], now)? {
+                    recorder.record(elapsed, size);
+                }
+            }
+            _ = expiry => tracker.expire(std::time::Instant::now()),
+        }
+    }
+    Ok(())
+}
+
+/// One socket: the writer sends messages as `load` allows, and the reader accounts for them.
+async fn udp_client(
+    report: &Report,
+    args: &ClientArgs,
+    loss_timeout: Duration,
+    worker: usize,
+    load: Load,
+) -> anyhow::Result<DatagramStats> {
+    let server = datagram::resolve(&args.endpoint())?;
+    let socket = UdpSocket::bind(datagram::local_addr(server)).await?;
+    args.socket.apply(&socket)?;
+    socket
+        .connect(server)
+        .await
+        .context(format!("failed to connect to {}", server))?;
+
+    let mut recorder = report.recorder(worker, socket.local_addr()?.to_string());
+    let mut tracker = Tracker::new(loss_timeout);
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    let deadline = Instant::from_std(recorder.deadline());
+    let mut sizes = recorder.sizes()?;
+    let mut payload = recorder.payload();
+    let socket = &socket;
+
+    let writer = async move {
+        let mut next = move || {
+            let message = payload.next(sizes.next().expect("sizes never run out"));
+            (message, datagram::encode(message))
+        };
+        match load {
+            Load::Window(depth) => {
+                let outstanding = Arc::new(Semaphore::new(depth));
+                while Instant::now() < deadline {
+                    // released by the reader, once the message is answered or lost
+                    let slot = outstanding.clone().acquire_owned().await?;
+                    let (message, request) = next();
+                    // the reader has failed, and will report why
+                    if tx.send((Instant::now(), message, Some(slot))).is_err() {
+                        break;
+                    }
+                    socket.send(&request).await.context("failed to send")?;
+                }
+            }
+            Load::Rate(rate) => {
+                for scheduled in Arrivals::new(rate)?.map(Instant::from_std) {
+                    if scheduled >= deadline {
+                        break;
+                    }
+                    let (message, request) = next();
+                    tokio::time::sleep_until(scheduled).await;
+                    if tx.send((scheduled, message, None)).is_err() {
+                        break;
+                    }
+                    socket.send(&request).await.context("failed to send")?;
+                }
+            }
+        }
+        Ok::<(), anyhow::Error>(())
+    };
+    let reader = read_replies(socket, rx, &mut tracker, &mut recorder);
+
+    tokio::try_join!(writer, reader)?;
+    recorder.finish();
+
+    Ok(tracker.stats().clone())
+}
+
+async fn run(report: &Report, args: Args) -> anyhow::Result<DatagramStats> {
+    let parallelism = args.common.parallelism();
+    let load = match args.load.client_type {
+        ClientType::Closed => Load::Window(1),
+        ClientType::Pipelined => {
+            let depth = args
+                .load
+                .pipeline_depth
+                .ok_or_else(|| anyhow::anyhow!("pipelined clients need a --pipeline-depth"))?;
+            report.pipeline(depth as usize);
+            Load::Window(depth as usize)
+        }
+        ClientType::Open => {
+            let rate = args
+                .load
+                .rate
+                .ok_or_else(|| anyhow::anyhow!("open-loop clients need a --rate"))?;
+            report.rate(rate);
+            Load::Rate(rate / parallelism as f64)
+        }
+        client_type => {
+            return Err(anyhow::anyhow!(
+                "{:?} clients are not supported over UDP",
+                client_type
+            ))
+        }
+    };
+
+    let loss_timeout = args.datagram.loss_timeout;
+    let runners = (0..parallelism)
+        .map(|worker| udp_client(report, &args.common, loss_timeout, worker, load))
+        .collect::<Vec<_>>();
+    let stats = futures::future::join_all(runners)
+        .await
+        .into_iter()
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut total = DatagramStats::default();
+    for worker in &stats {
+        total.merge(worker);
+    }
+    Ok(total)
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_writer(std::io::stderr)
+        .init();
+    let args: Args = echo_common::cli::parse("9095");
+    args.common.tls.reject("over UDP")?;
+    args.load.check(&args.common)?;
+    datagram::check_size(args.common.size_dist().max())?;
+    let report = Report::new(&args.common)?;
+
+    let rt = echo_common::runtime(args.common.n_cores)?;
+
+    report.header();
+    echo_common::wait_for_start(args.common.start);
+    let stats = rt.block_on(run(&report, args))?;
+
+    report.datagrams(&stats);
+    report.summary()
+}
@@ -0,0 +1,81 @@
+use clap::Parser;
+use tokio::net::UdpSocket;
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use echo_common::datagram::{self, MAX_DATAGRAM_LEN};
+use echo_common::{shutdown, ServerArgs, ServerStats};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about=None)]
+struct Args {
+    #[command(flatten)]
+    common: ServerArgs,
+}
+
+/// Echo datagrams back to their sender, forever.
+async fn serve(socket: Arc<UdpSocket>, stats: Arc<ServerStats>) {
+    let mut buffer = vec![0; MAX_DATAGRAM_LEN];
+    loop {
+        match socket.recv_from(&mut buffer).await {
+            Ok((n, peer_addr)) => {
+                stats.bytes_in(datagram::payload_len(n));
+                match socket.send_to(&buffer[..n], peer_addr).await {
+                    Ok(_) => {
+                        stats.bytes_out(datagram::payload_len(n));
+                        stats.message();
+                    }
+                    Err(e) => {
+                        stats.error(&e);
+                        tracing::warn!("failed to echo to {}: {}", peer_addr, e);
+                    }
+                }
+            }
+            Err(e) => {
+                stats.error(&e);
+                tracing::warn!("failed to receive: {}", e);
+            }
+        }
+    }
+}
+
+async fn run(args: ServerArgs) -> anyhow::Result<()> {
+    let stats = ServerStats::new();
+    if let Some(admin_addr) = args.admin_addr() {
+        stats.serve_admin(&admin_addr)?;
+        tracing::info!("serving stats on {}", admin_addr);
+    }
+
+    let addr = datagram::resolve(&args.endpoint())?;
+    let socket = UdpSocket::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind {}", addr))?;
+    args.socket.apply(&socket)?;
+    tracing::info!("server listening on {}", addr);
+    stats.listening();
+    tracing::info!("socket options: {}", args.socket.describe());
+
+    // one receive loop per worker thread
+    let socket = Arc::new(socket);
+    for _ in 0..args.parallelism() {
+        tokio::spawn(serve(Arc::clone(&socket), Arc::clone(&stats)));
+    }
+
+    shutdown::signal()
+        .await
+        .context("failed to handle signals")?;
+    tracing::info!("shutting down");
+
+    // datagrams are echoed as they come: there is nothing in flight to drain
+    shutdown::finish(&stats, 0)
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args: Args = echo_common::cli::parse("9095");
+    args.common.tls.reject("over UDP")?;
+
+    let rt = echo_common::runtime(args.common.n_cores)?;
+    rt.block_on(run(args.common))
+}
@@ -0,0 +1,217 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use clap::Parser;
+use echo_common::protocol::{self, Hello, FRAME_HEADER_LEN};
+use echo_common::{datagram, ClientArgs, Message, Report};
+use quinn::rustls::pki_types::CertificateDer;
+use quinn::rustls::RootCertStore;
+use quinn::{Connection, RecvStream, SendStream};
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+const BUFFER_SIZE: usize = 1 << 16;
+
+#[derive(Parser, Clone)]
+#[command(author, version, about, long_about=None)]
+struct Args {
+    #[command(flatten)]
+    common: ClientArgs,
+
+    /// How requests map to QUIC streams.
+    #[arg(long, value_enum, default_value_t = StreamMode::PerRequest)]
+    stream_mode: StreamMode,
+
+    /// Certificate of the server (DER), as written by `rust_quic_server --cert`.
+    #[arg(long, default_value = "/tmp/rust_quic_cert.der")]
+    cert: PathBuf,
+
+    /// Name to check the certificate of the server against (default: the hostname).
+    #[arg(long)]
+    server_name: Option<String>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum StreamMode {
+    /// A new bidirectional stream for every request, finished once the request is sent.
+    PerRequest,
+    /// A single bidirectional stream per connection, carrying every request, as over TCP.
+    LongLived,
+}
+
+fn client_config(args: &Args) -> anyhow::Result<quinn::ClientConfig> {
+    let cert = std::fs::read(&args.cert)
+        .with_context(|| format!("failed to read the certificate {}", args.cert.display()))?;
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from(cert))
+        .context("invalid server certificate")?;
+    quinn::ClientConfig::with_root_certificates(Arc::new(roots)).context("failed to configure TLS")
+}
+
+/// Send `message` on a stream and time its echo. The request is written while the reply is
+/// read, so that flow control never blocks the server's echo of a large message.
+async fn do_run(
+    send: &mut SendStream,
+    recv: &mut RecvStream,
+    message: Message,
+    framed: bool,
+    finish: bool,
+) -> anyhow::Result<Duration> {
+    // generated up front, so that only the echo is timed
+    let request = protocol::encode(message, framed);
+    let mut reply = message;
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let start = Instant::now();
+
+    let write = async {
+        send.write_all(&request).await.context("failed to send")?;
+        if finish {
+            send.finish().context("failed to finish stream")?;
+        }
+        Ok::<(), anyhow::Error>(())
+    };
+    let read = async {
+        if framed {
+            let mut header = [0; FRAME_HEADER_LEN];
+            recv.read_exact(&mut header)
+                .await
+                .context("failed to read")?;
+            protocol::check_frame(header, message.size())?;
+        }
+        let mut waiting_for = message.size();
+        while waiting_for > 0 {
+            let n = std::cmp::min(waiting_for, BUFFER_SIZE);
+            let Some(n) = recv
+                .read(&mut buffer[..n])
+                .await
+                .context("failed to read")?
+            else {
+                return Err(anyhow::anyhow!("stream finished before the reply was in"));
+            };
+            reply.check(&buffer[..n])?;
+            waiting_for -= n;
+        }
+        Ok(())
+    };
+    tokio::try_join!(write, read)?;
+
+    Ok(start.elapsed())
+}
+
+/// Connect, on a socket of its own, and handshake on the first stream of the connection.
+async fn connect(args: &Args) -> anyhow::Result<(quinn::Endpoint, Connection, Hello)> {
+    let endpoint = args.common.endpoint();
+    let server = datagram::resolve(&endpoint)?;
+    let socket = std::net::UdpSocket::bind(datagram::local_addr(server))?;
+    args.common
+        .socket
+        .apply(&socket)
+        .context("failed to set socket options")?;
+    let runtime = quinn::default_runtime().context("no async runtime found")?;
+    let mut client = quinn::Endpoint::new(quinn::EndpointConfig::default(), None, socket, runtime)?;
+    client.set_default_client_config(client_config(args)?);
+
+    let server_name = args.server_name.clone().unwrap_or_else(|| {
+        let host = args.common.host.as_str();
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string()
+    });
+    let connection = client
+        .connect(server, &server_name)?
+        .await
+        .context(format!("failed to connect to {}", endpoint))?;
+    tracing::info!("connected @ {}", endpoint);
+
+    let hello = args.common.hello();
+    let handshake = async {
+        let (send, recv) = connection.open_bi().await?;
+        let mut control = tokio::io::join(recv, send);
+        let granted = protocol::connect_async(&mut control, &hello).await?;
+        let (_, mut send) = control.into_inner();
+        let _ = send.finish();
+        Ok::<u32, anyhow::Error>(granted)
+    };
+    let granted = tokio::time::timeout(protocol::HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| anyhow::anyhow!("no handshake reply from the server"))
+        .and_then(|granted| granted)
+        .with_context(|| format!("handshake with {} failed", endpoint))?;
+    protocol::require(&hello, granted)?;
+
+    Ok((client, connection, hello))
+}
+
+async fn closed_client(report: &Report, args: &Args, worker: usize) -> anyhow::Result<()> {
+    let (client, connection, hello) = connect(args).await?;
+    let recorder = report.recorder(worker, client.local_addr()?.to_string());
+    let framed = hello.framed_messages();
+
+    match args.stream_mode {
+        StreamMode::PerRequest => {
+            echo_common::closed_loop_async(recorder, |message| {
+                let connection = connection.clone();
+                async move {
+                    let (mut send, mut recv) = connection.open_bi().await?;
+                    do_run(&mut send, &mut recv, message, framed, true).await
+                }
+            })
+            .await?
+        }
+        StreamMode::LongLived => {
+            let stream = Arc::new(Mutex::new(connection.open_bi().await?));
+            echo_common::closed_loop_async(recorder, |message| {
+                let stream = Arc::clone(&stream);
+                async move {
+                    let mut stream = stream.lock().await;
+                    let (send, recv) = &mut *stream;
+                    do_run(send, recv, message, framed, false).await
+                }
+            })
+            .await?;
+
+            // the server is done once it has seen the end of the stream
+            let (send, recv) = &mut *stream.lock().await;
+            send.finish().context("failed to finish stream")?;
+            recv.read_to_end(0).await.context("failed to read")?;
+        }
+    }
+
+    connection.close(0u32.into(), b"done");
+    client.wait_idle().await;
+    Ok(())
+}
+
+async fn run(report: &Report, args: Args) -> anyhow::Result<()> {
+    let runners = (0..args.common.parallelism())
+        .map(|worker| closed_client(report, &args, worker))
+        .collect::<Vec<_>>();
+    futures::future::join_all(runners)
+        .await
+        .into_iter()
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_writer(std::io::stderr)
+        .init();
+    let args: Args = echo_common::cli::parse("9096");
+    args.common
+        .tls
+        .reject("by QUIC, which always encrypts, with the certificate in --cert")?;
+    let report = Report::new(&args.common)?;
+
+    let rt = echo_common::runtime(args.common.n_cores)?;
+
+    report.header();
+    echo_common::wait_for_start(args.common.start);
+    rt.block_on(run(&report, args))?;
+
+    report.summary()
+}
@@ -0,0 +1,230 @@
+use clap::Parser;
+use quinn::{ConnectionError, ReadExactError, RecvStream, SendStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use echo_common::datagram;
+use echo_common::protocol::{self, Hello, FRAME_HEADER_LEN};
+use echo_common::{shutdown, ServerArgs, ServerStats};
+use quinn::rustls::pki_types::PrivatePkcs8KeyDer;
+
+const BUFFER_SIZE: usize = 1 << 16;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about=None)]
+struct Args {
+    #[command(flatten)]
+    common: ServerArgs,
+
+    /// Where to write the self-signed certificate of the server (DER), for the clients to trust.
+    #[arg(long, default_value = "/tmp/rust_quic_cert.der")]
+    cert: PathBuf,
+}
+
+/// A fresh self-signed certificate for `localhost` and `host`, written to `cert_path`.
+fn server_config(host: &str, cert_path: &Path) -> anyhow::Result<quinn::ServerConfig> {
+    let names = vec![
+        "localhost".to_string(),
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
+    ];
+    let certified =
+        rcgen::generate_simple_self_signed(names).context("failed to generate a certificate")?;
+    let cert = certified.cert.der().clone();
+    std::fs::write(cert_path, &cert)
+        .with_context(|| format!("failed to write {}", cert_path.display()))?;
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+
+    quinn::ServerConfig::with_single_cert(vec![cert], key.into()).context("failed to configure TLS")
+}
+
+/// Handshake on the first stream of the connection, then echo every stream the client opens,
+/// until it closes the connection; once the server stops, close it when every stream is done.
+async fn handle_connection(
+    connection: quinn::Connection,
+    stats: &Arc<ServerStats>,
+    stopping: &watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let (send, recv) = connection
+        .accept_bi()
+        .await
+        .context("failed to accept the handshake stream")?;
+    let mut control = tokio::io::join(recv, send);
+    let hello = protocol::accept_async(&mut control).await?;
+    let (_, mut send) = control.into_inner();
+    let _ = send.finish();
+
+    let mut streams = JoinSet::new();
+    let mut stop = stopping.clone();
+    loop {
+        let accepted = tokio::select! {
+            accepted = connection.accept_bi() => accepted,
+            _ = stop.wait_for(|stopping| *stopping) => break,
+            // reap finished streams: with a stream per request, there is one per message
+            Some(_) = streams.join_next() => continue,
+        };
+        let (send, recv) = match accepted {
+            Ok(stream) => stream,
+            // the client is done
+            Err(ConnectionError::ApplicationClosed(_)) => return Ok(()),
+            Err(e) => return Err(e).context("failed to accept stream"),
+        };
+        let stats = Arc::clone(stats);
+        let stopping = stopping.clone();
+        let hello = hello.clone();
+        let peer_addr = connection.remote_address();
+        streams.spawn(async move {
+            if let Err(e) = handle_stream(send, recv, &hello, &stats, &stopping).await {
+                stats.error(&*e);
+                tracing::warn!("failed to handle stream from {}: {:?}", peer_addr, e);
+            }
+        });
+    }
+
+    while streams.join_next().await.is_some() {}
+    connection.close(0u32.into(), b"shutting down");
+    Ok(())
+}
+
+/// Echo messages back until the client finishes the stream (after a single message, with
+/// a stream per request), or until the server stops after a message.
+async fn handle_stream(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    hello: &Hello,
+    stats: &ServerStats,
+    stopping: &watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    'messages: while !*stopping.borrow() {
+        let message_size = if hello.framed_messages() {
+            let mut header = [0; FRAME_HEADER_LEN];
+            match recv.read_exact(&mut header).await {
+                // the client is done
+                Err(ReadExactError::FinishedEarly(0)) => break,
+                result => result.context("failed to read")?,
+            }
+            let message_size = protocol::frame_size(header, hello.message_size)?;
+            send.write_all(&header).await.context("failed to echo")?;
+            message_size
+        } else {
+            hello.message_size
+        };
+
+        let mut to_read = message_size;
+        while to_read > 0 {
+            let n = std::cmp::min(to_read, BUFFER_SIZE);
+            match recv.read_exact(&mut buffer[..n]).await {
+                // the client is done
+                Err(ReadExactError::FinishedEarly(0))
+                    if !hello.framed_messages() && to_read == message_size =>
+                {
+                    break 'messages
+                }
+                result => result.context("failed to read")?,
+            }
+            stats.bytes_in(n);
+            send.write_all(&buffer[..n])
+                .await
+                .context("failed to echo")?;
+            stats.bytes_out(n);
+            to_read -= n;
+        }
+        stats.message();
+    }
+    send.finish().context("failed to finish stream")
+}
+
+async fn run(args: Args) -> anyhow::Result<()> {
+    let common = args.common;
+    let stats = ServerStats::new();
+    if let Some(admin_addr) = common.admin_addr() {
+        stats.serve_admin(&admin_addr)?;
+        tracing::info!("serving stats on {}", admin_addr);
+    }
+
+    let addr = datagram::resolve(&common.endpoint())?;
+    let config = server_config(&common.host, &args.cert)?;
+    let socket =
+        std::net::UdpSocket::bind(addr).with_context(|| format!("failed to bind {}", addr))?;
+    common
+        .socket
+        .apply(&socket)
+        .context("failed to set socket options")?;
+    let runtime = quinn::default_runtime().context("no async runtime found")?;
+    let endpoint = quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(config),
+        socket,
+        runtime,
+    )
+    .with_context(|| format!("failed to bind {}", addr))?;
+    tracing::info!(
+        "server listening on {}, certificate in {}",
+        addr,
+        args.cert.display()
+    );
+    stats.listening();
+    tracing::info!("socket options: {}", common.socket.describe());
+
+    let (stop, stopping) = watch::channel(false);
+    let signal = shutdown::signal();
+    tokio::pin!(signal);
+
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            signal = &mut signal => {
+                signal.context("failed to handle signals")?;
+                break;
+            }
+        };
+        let stats = Arc::clone(&stats);
+        let stopping = stopping.clone();
+        tokio::spawn(async move {
+            let _connection = stats.connection();
+            match incoming.await {
+                Ok(connection) => {
+                    let peer_addr = connection.remote_address();
+                    tracing::info!("accepted new connection: {}", peer_addr);
+                    if let Err(e) = handle_connection(connection, &stats, &stopping).await {
+                        stats.error(&*e);
+                        tracing::warn!("failed to handle connection from {}: {:?}", peer_addr, e);
+                    }
+                }
+                Err(e) => {
+                    stats.error(&e);
+                    tracing::warn!("failed to accept connection: {}", e);
+                }
+            }
+        });
+    }
+
+    tracing::info!("shutting down");
+    stop.send_replace(true);
+    endpoint.set_server_config(None);
+    let left = shutdown::drain_async(&stats, common.drain_timeout).await;
+    tracing::info!("drained connections, {} left", left);
+    endpoint.close(0u32.into(), b"shutting down");
+    endpoint.wait_idle().await;
+    shutdown::finish(&stats, left)
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args: Args = echo_common::cli::parse("9096");
+    args.common
+        .tls
+        .reject("by QUIC, which always encrypts, with the certificate in --cert")?;
+
+    let rt = echo_common::runtime(args.common.n_cores)?;
+    rt.block_on(run(args))
+}
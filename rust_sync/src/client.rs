@@ -1,145 +1,241 @@
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::time::Duration;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
-use chrono::{Local, NaiveDateTime, NaiveTime};
 use clap::Parser;
+use echo_common::protocol::{self, Hello, FRAME_HEADER_LEN};
+use echo_common::tls::{self, Credentials};
+use echo_common::{ClientArgs, Endpoint, Message, Report};
+use rust_sync::tls::TlsStream;
+use rustls::{ClientConfig, ClientConnection};
 
 const BUFFER_SIZE: usize = 1 << 16;
 
-fn size_parser(s: &str) -> anyhow::Result<usize> {
-    parse_size::Config::new()
-        .with_binary()
-        .parse_size(s)
-        .map(|x| x as usize)
-        .map_err(|e| anyhow::anyhow!("failed to parse {}: {:?}", s, e))
-}
-
-fn time_parser(s: &str) -> anyhow::Result<NaiveDateTime> {
-    let today = Local::now().date_naive();
-    let time =
-        NaiveTime::parse_from_str(s, "%H:%M:%S").context("failed to parse start timestamp")?;
-    Ok(NaiveDateTime::new(today, time))
-}
-
-fn duration_parser(s: &str) -> anyhow::Result<u64> {
-    humantime::parse_duration(s)
-        .map(|s| s.as_secs())
-        .context("failed to parse duration")
-}
-
 #[derive(Parser, Clone, Debug)]
 #[command(author, version, about, long_about=None)]
 struct Args {
-    #[arg(default_value = "[::1]")]
-    host: String,
+    #[command(flatten)]
+    common: ClientArgs,
+}
 
-    #[arg(default_value_t = 9094, value_parser = clap::value_parser!(u16).range(1..))]
-    port: u16,
+/// A connected stream: TCP (with or without TLS), or a Unix domain socket.
+trait Stream: Read + Write + Send + Sized {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
 
-    #[arg(short = 'j', long)]
-    n_cores: Option<usize>,
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
 
-    #[arg(short, long, default_value = "60s", value_parser = duration_parser)]
-    duration: u64,
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
 
-    #[arg(short, long, default_value = "10s", value_parser = duration_parser)]
-    warmup: u64,
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
 
-    #[arg(short, long, default_value_t = 1, value_parser = size_parser)]
-    message_size: usize,
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
 
-    #[arg(short, long, value_parser = time_parser)]
-    start: Option<NaiveDateTime>,
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
 }
 
-impl Args {
-    fn duration(&self) -> std::time::Duration {
-        return Duration::from_secs(self.duration);
+impl Stream for TlsStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TlsStream::try_clone(self)
     }
-    fn warmup(&self) -> std::time::Duration {
-        return Duration::from_secs(self.warmup);
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TlsStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TlsStream::set_read_timeout(self, timeout)
     }
 }
 
-fn do_run(stream: &mut TcpStream, message_size: usize) -> anyhow::Result<Duration> {
-    let mut buffer = [42; BUFFER_SIZE];
+/// Echo a message over `stream`; `writer` is a clone of it.
+///
+/// A message longer than the servers read ahead is written on another thread, while the reply
+/// is read, or the server would stop reading it (see `protocol::READ_AHEAD`).
+fn do_run<S: Stream>(
+    stream: &mut S,
+    writer: &mut S,
+    message: Message,
+    framed: bool,
+) -> anyhow::Result<Duration> {
+    // generated up front, so that only the echo is timed
+    let request = protocol::encode(message, framed);
     let start = std::time::Instant::now();
-    let mut need_to_write = message_size;
-    while need_to_write > 0 {
-        let n = stream.write(&buffer[..std::cmp::min(need_to_write, BUFFER_SIZE)])?;
-        need_to_write -= n;
+    if message.size() <= protocol::READ_AHEAD {
+        stream.write_all(&request)?;
+        read_reply(stream, message, framed)?;
+    } else {
+        thread::scope(|scope| {
+            let writing = scope.spawn(|| writer.write_all(&request));
+            let read = read_reply(stream, message, framed);
+            if read.is_err() {
+                // unblock the writer
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            let written = writing
+                .join()
+                .map_err(|_| anyhow::anyhow!("writer panicked"))?;
+            read.and(written.map_err(Into::into))
+        })?;
     }
-    let mut waiting_for = message_size;
+    Ok(start.elapsed())
+}
+
+/// Read the reply to `message`, and check it.
+fn read_reply<S: Read>(stream: &mut S, message: Message, framed: bool) -> anyhow::Result<()> {
+    let mut reply = message;
+    let mut buffer = [0; BUFFER_SIZE];
+    if framed {
+        let mut header = [0; FRAME_HEADER_LEN];
+        stream.read_exact(&mut header)?;
+        protocol::check_frame(header, message.size())?;
+    }
+    let mut waiting_for = message.size();
     while waiting_for > 0 {
-        buffer.fill(0);
         let n = stream.read(&mut buffer[..std::cmp::min(waiting_for, BUFFER_SIZE)])?;
-        if !buffer[..n].iter().all(|x| *x == 42) {
-            return Err(anyhow::anyhow!("mismatched reply"));
+        if n == 0 {
+            return Err(anyhow::anyhow!("connection closed before the reply was in"));
         }
+        reply.check(&buffer[..n])?;
         waiting_for -= n;
     }
-    Ok(start.elapsed())
+    Ok(())
 }
 
-fn closed_client(id: &str, args: &Args) -> anyhow::Result<()> {
-    match TcpStream::connect(format!("{}:{}", args.host, args.port)) {
-        Ok(mut stream) => {
-            let mut reporting = false;
-            let size = args.message_size.to_be_bytes();
-            stream.write_all(&size)?;
-            let start = std::time::Instant::now();
-            while start.elapsed() < args.duration() + args.warmup() {
-                let elapsed = do_run(&mut stream, args.message_size)?;
-                if !reporting && start.elapsed() > args.warmup() {
-                    reporting = true;
-                    println!("Start: {} {:.9}", id, start.elapsed().as_secs_f64());
-                }
+/// Handshake with the server, which the caller bounds with a read timeout.
+fn handshake<S: Read + Write>(stream: &mut S, args: &ClientArgs) -> anyhow::Result<Hello> {
+    let hello = args.hello();
+    let granted = protocol::connect(stream, &hello)
+        .with_context(|| format!("handshake with {} failed", args.endpoint()))?;
+    protocol::require(&hello, granted)?;
+    Ok(hello)
+}
 
-                if reporting && start.elapsed() < args.duration() + args.warmup() {
-                    println!("{:.3}", elapsed.as_secs_f64() * 1_000_000f64);
-                }
-            }
-            println!("End: {} {:.9}", id, start.elapsed().as_secs_f64());
+/// Connect over TLS, and record how long it took, TCP handshake included.
+fn connect_tls(
+    report: &Report,
+    args: &ClientArgs,
+    addr: &str,
+    config: &Arc<ClientConfig>,
+) -> anyhow::Result<TlsStream> {
+    let start = Instant::now();
+    let mut stream = TcpStream::connect(addr).context("failed to connect")?;
+    args.socket.apply(&stream)?;
+    stream.set_read_timeout(Some(protocol::HANDSHAKE_TIMEOUT))?;
+    let mut connection = ClientConnection::new(Arc::clone(config), tls::server_name(&args.host)?)?;
+    while connection.is_handshaking() {
+        connection
+            .complete_io(&mut stream)
+            .context("TLS handshake failed")?;
+    }
+    report.handshake(start.elapsed());
+    Ok(TlsStream::client(stream, connection)?)
+}
+
+fn closed_client(
+    report: &Report,
+    args: &ClientArgs,
+    tls: Option<&Arc<ClientConfig>>,
+    worker: usize,
+) -> anyhow::Result<()> {
+    match (args.endpoint(), tls) {
+        (Endpoint::Tcp(addr), Some(config)) => {
+            let stream = connect_tls(report, args, &addr, config)?;
+            let connection = stream.socket().local_addr()?.to_string();
+            run(report, args, stream, worker, connection)
         }
-        Err(e) => {
-            return Err(e).context("failed to connect");
+        (Endpoint::Tcp(addr), None) => {
+            let stream = TcpStream::connect(addr).context("failed to connect")?;
+            args.socket.apply(&stream)?;
+            let connection = stream.local_addr()?.to_string();
+            run(report, args, stream, worker, connection)
+        }
+        (Endpoint::Unix(path), _) => {
+            let stream = UnixStream::connect(&path).context("failed to connect")?;
+            args.socket.apply(&stream)?;
+            // clients are unnamed: connections are identified by worker
+            let connection = format!("{}#{}", path.display(), worker);
+            run(report, args, stream, worker, connection)
         }
     }
+}
 
-    Ok(())
+fn run<S: Stream>(
+    report: &Report,
+    args: &ClientArgs,
+    mut stream: S,
+    worker: usize,
+    connection: String,
+) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(protocol::HANDSHAKE_TIMEOUT))?;
+    let hello = handshake(&mut stream, args)?;
+    stream.set_read_timeout(None)?;
+
+    let recorder = report.recorder(worker, connection);
+    let framed = hello.framed_messages();
+    let mut writer = stream.try_clone().context("failed to clone stream")?;
+    echo_common::closed_loop(recorder, |message| {
+        do_run(&mut stream, &mut writer, message, framed)
+    })
 }
 
 fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::filter::EnvFilter::from_default_env())
         .with_writer(std::io::stderr)
-        .finish();
-    let args = Args::parse();
-
-    let paralellism = args.n_cores.unwrap_or_else(|| num_cpus::get());
-    let id = format!(
-        "{}:{:x}",
-        gethostname::gethostname()
-            .into_string()
-            .map_err(|os_str| anyhow::anyhow!("failed to convert hostname: {:?}", os_str))?,
-        uuid::Uuid::new_v4().simple()
-    );
-
-    println!("Message Size: {}", args.message_size);
-    if let Some(start) = args.start {
-        let now_ts = Local::now().timestamp_nanos_opt().ok_or(anyhow::anyhow!("an i64 can represent stuff until 2262. if you are still using this code in 2262 first of all, thanks i guess; second, i don't really care, probably fix this"))?;
-        let start_ts = start.timestamp_nanos_opt().ok_or(anyhow::anyhow!("an i64 can represent stuff until 2262. if you are still using this code in 2262 first of all, thanks i guess; second, i don't really care, probably fix this"))?;
-        if now_ts < start_ts {
-            std::thread::sleep(std::time::Duration::from_nanos((start_ts - now_ts) as u64));
+        .init();
+    let args: Args = echo_common::cli::parse("9094");
+    let args = args.common;
+
+    let parallelism = args.parallelism();
+    let report = Report::new(&args)?;
+    let tls = match args.endpoint() {
+        Endpoint::Tcp(_) if args.tls.enabled() => Some(
+            Credentials::client(&args.tls)?
+                .client_config(args.tls.mtls)
+                .context("failed to set up TLS")?,
+        ),
+        Endpoint::Tcp(_) => None,
+        Endpoint::Unix(_) => {
+            args.tls.reject("over Unix domain sockets")?;
+            None
         }
-    }
+    };
+
+    report.header();
+    echo_common::wait_for_start(args.start);
 
     std::thread::scope(|s| {
-        let runners = (0..paralellism)
-            .into_iter()
-            .map(|_| s.spawn(|| closed_client(&id, &args)))
+        let runners = (0..parallelism)
+            .map(|worker| {
+                let report = &report;
+                let args = &args;
+                let tls = tls.as_ref();
+                s.spawn(move || closed_client(report, args, tls, worker))
+            })
             .collect::<Vec<_>>();
 
         runners
@@ -151,7 +247,9 @@ fn main() -> anyhow::Result<()> {
                     None
                 }
             })
-            .collect::<anyhow::Result<_>>()?;
+            .collect::<anyhow::Result<Vec<()>>>()?;
         Ok::<(), anyhow::Error>(())
-    })
+    })?;
+
+    report.summary()
 }
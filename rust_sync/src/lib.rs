@@ -0,0 +1,3 @@
+//! Plumbing shared by the raw-TCP server and client.
+
+pub mod tls;
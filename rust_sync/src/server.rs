@@ -1,75 +1,491 @@
 use clap::Parser;
-use std::io::{Read, Write};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
 use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::fd::AsFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 use anyhow::Context;
+use echo_common::endpoint::{self, Endpoint};
+use echo_common::per_core::{self, ServerMode};
+use echo_common::protocol::{self, Hello, FRAME_HEADER_LEN};
+use echo_common::shutdown::{self, Signal};
+use echo_common::tls::Credentials;
+use echo_common::{ServerArgs, ServerStats, SocketArgs};
+
+use rust_sync::tls::TlsStream;
+use rustls::ServerConfig;
 
 const BUFFER_SIZE: usize = 1 << 16;
 
+/// Chunks queued between the reader and the writer of a connection: once the queue is full,
+/// the reader waits for the client to read the echo.
+const QUEUE_LEN: usize = protocol::READ_AHEAD / BUFFER_SIZE;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about=None)]
 struct Args {
-    #[arg(default_value = "[::1]")]
-    host: String,
+    #[command(flatten)]
+    common: ServerArgs,
+
+    /// Serve on `-j` workers that accept on a single listener, or that are pinned to a core each,
+    /// with a listener of their own.
+    #[arg(long, value_enum, default_value_t = ServerMode::Shared)]
+    mode: ServerMode,
+}
+
+/// A connected stream: TCP (with or without TLS), or a Unix domain socket.
+trait Stream: Read + Write + Send + Sync + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn peer(&self) -> String;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn peer(&self) -> String {
+        self.peer_addr().map(|a| a.to_string()).unwrap_or_default()
+    }
+}
+
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn peer(&self) -> String {
+        // clients are unnamed
+        self.peer_addr()
+            .map(|a| format!("{:?}", a))
+            .unwrap_or_default()
+    }
+}
+
+impl Stream for TlsStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TlsStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TlsStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TlsStream::set_read_timeout(self, timeout)
+    }
 
-    #[arg(default_value_t = 9094, value_parser = clap::value_parser!(u16).range(1..))]
-    port: u16,
+    fn peer(&self) -> String {
+        self.socket().peer()
+    }
 }
 
-fn handle_client(mut stream: TcpStream) -> anyhow::Result<()> {
-    let mut data = [0 as u8; BUFFER_SIZE];
-    let mut size_buffer = [0; 8];
-    stream.read_exact(&mut size_buffer)?;
-    let message_size = usize::from_be_bytes(size_buffer);
+/// Echo messages back until the client is done, or until the server stops after a message.
+///
+/// Reading and writing are decoupled: a reader thread hands chunks over to the writer through
+/// a small queue, so the echo of a message starts while the client still writes it, and
+/// memory stays bounded whatever the message size. A client that only reads its reply after
+/// writing the full message must keep it within `protocol::READ_AHEAD`.
+fn handle_client<S: Stream>(mut stream: S, stats: &ServerStats, stop: &Stop) -> anyhow::Result<()> {
+    // a client that never says hello (or never finishes its TLS handshake) must not hold on
+    // to its worker
+    stream.set_read_timeout(Some(protocol::HANDSHAKE_TIMEOUT))?;
+    let hello = protocol::accept(&mut stream)?;
+    stream.set_read_timeout(None)?;
+
+    let reader = stream.try_clone().context("failed to clone stream")?;
+    let registration = stop.register(&stream).context("failed to clone stream")?;
+    let (chunks, queue) = mpsc::sync_channel(QUEUE_LEN);
+
+    thread::scope(|scope| {
+        let reading = scope.spawn(|| read_chunks(reader, &hello, chunks, stats, &registration));
+
+        let written = write_chunks(&mut stream, queue, stats);
+        if written.is_err() {
+            // unblock the reader
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        let read = reading
+            .join()
+            .map_err(|_| anyhow::anyhow!("reader panicked"))?;
+
+        read.and(written).with_context(|| {
+            format!(
+                "An error occurred, terminating connection with {}",
+                stream.peer()
+            )
+        })
+    })
+}
 
+/// Read whole messages in chunks of at most `BUFFER_SIZE` bytes of payload, which never span
+/// messages; the last chunk of a message is flagged. The header of a frame is echoed along
+/// with the first chunk of its payload, and is not counted as payload.
+fn read_chunks<S: Stream>(
+    mut stream: S,
+    hello: &Hello,
+    chunks: SyncSender<(Vec<u8>, usize, bool)>,
+    stats: &ServerStats,
+    registration: &Registration,
+) -> anyhow::Result<()> {
     loop {
+        let mut header = Vec::new();
+        let message_size = if hello.framed_messages() {
+            let mut buf = [0; FRAME_HEADER_LEN];
+            if !read_message(&mut stream, &mut buf, registration)? {
+                return Ok(());
+            }
+            header.extend_from_slice(&buf);
+            protocol::frame_size(buf, hello.message_size)?
+        } else {
+            hello.message_size
+        };
+
         let mut to_read = message_size;
         while to_read > 0 {
-            match stream.read(&mut data) {
-                Ok(size) => {
-                    stream.write(&data[0..size]).context("failed to echo")?;
-                    to_read -= size;
-                }
-                Err(e) => {
-                    let err = Err(e).context(format!(
-                        "An error occurred, terminating connection with {}",
-                        stream.peer_addr().unwrap()
-                    ));
-                    stream.shutdown(Shutdown::Both).unwrap();
-                    return err;
-                }
+            let n = std::cmp::min(to_read, BUFFER_SIZE);
+            let mut chunk = std::mem::take(&mut header);
+            let offset = chunk.len();
+            chunk.resize(offset + n, 0);
+            if offset > 0 || to_read < message_size {
+                stream
+                    .read_exact(&mut chunk[offset..])
+                    .context("failed to read")?;
+            } else if !read_message(&mut stream, &mut chunk, registration)? {
+                return Ok(());
+            }
+            stats.bytes_in(n);
+            to_read -= n;
+            if chunks.send((chunk, n, to_read == 0)).is_err() {
+                // the writer failed, and reports why
+                return Ok(());
             }
         }
     }
 }
 
-fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-    let args = Args::parse();
+/// Wait for the next message, and fill `buf` with its start; returns whether there is one,
+/// rather than the end of the stream (or a stop while waiting).
+///
+/// The connection is idle until the first bytes come, and its read side is shut down at once
+/// if the server stops meanwhile.
+fn read_message<S: Stream>(
+    stream: &mut S,
+    buf: &mut [u8],
+    registration: &Registration,
+) -> anyhow::Result<bool> {
+    if !registration.idle() {
+        return Ok(false);
+    }
+    let read = loop {
+        match stream.read(buf) {
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            read => break read,
+        }
+    };
+    registration.busy();
+    let n = match read {
+        // the client is done (without a `close_notify`, over TLS)
+        Ok(0) => return Ok(false),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+        read => read.context("failed to read")?,
+    };
+    stream.read_exact(&mut buf[n..]).context("failed to read")?;
+    Ok(true)
+}
 
-    let listener = TcpListener::bind(format!("{}:{}", args.host, args.port))?;
-    tracing::info!("server listening on {}:{}", args.host, args.port);
+/// Echo every chunk back, until the reader is done.
+fn write_chunks<S: Stream>(
+    stream: &mut S,
+    queue: Receiver<(Vec<u8>, usize, bool)>,
+    stats: &ServerStats,
+) -> anyhow::Result<()> {
+    for (chunk, payload, last) in queue {
+        stream.write_all(&chunk).context("failed to echo")?;
+        stats.bytes_out(payload);
+        if last {
+            stats.message();
+        }
+    }
+    stream
+        .shutdown(Shutdown::Write)
+        .context("failed to shut down")
+}
+
+/// Stops the connections on shutdown: those waiting for a message at once, by shutting down
+/// their read side, and the others after their current message.
+#[derive(Default)]
+struct Stop {
+    stopping: AtomicBool,
+    readers: Mutex<HashMap<u64, Arc<Reader>>>,
+    next: AtomicU64,
+}
+
+/// The read side of a connection.
+struct Reader {
+    /// Whether it waits for a message.
+    idle: Mutex<bool>,
+    shutdown: Box<dyn Fn() + Send + Sync>,
+}
 
-    for stream in listener.incoming() {
+/// A connection known to `Stop`, until dropped.
+struct Registration<'a> {
+    stop: &'a Stop,
+    id: u64,
+    reader: Arc<Reader>,
+}
+
+impl Stop {
+    fn register<S: Stream>(&self, stream: &S) -> io::Result<Registration<'_>> {
+        let socket = stream.try_clone()?;
+        let reader = Arc::new(Reader {
+            idle: Mutex::new(false),
+            shutdown: Box::new(move || {
+                let _ = socket.shutdown(Shutdown::Read);
+            }),
+        });
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        self.readers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, Arc::clone(&reader));
+        Ok(Registration {
+            stop: self,
+            id,
+            reader,
+        })
+    }
+
+    fn stopping(&self) -> bool {
+        self.stopping.load(Ordering::Relaxed)
+    }
+
+    fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        let readers = self.readers.lock().unwrap_or_else(|e| e.into_inner());
+        for reader in readers.values() {
+            if *reader.idle.lock().unwrap_or_else(|e| e.into_inner()) {
+                (reader.shutdown)();
+            }
+        }
+    }
+}
+
+impl Registration<'_> {
+    /// Wait for a message: returns whether to, unless the server stops.
+    fn idle(&self) -> bool {
+        let mut idle = self.reader.idle.lock().unwrap_or_else(|e| e.into_inner());
+        *idle = true;
+        // under the lock: either `Stop::stop` sees the reader idle, or the reader sees it
+        // stopping
+        !self.stop.stopping()
+    }
+
+    /// A message started.
+    fn busy(&self) {
+        *self.reader.idle.lock().unwrap_or_else(|e| e.into_inner()) = false;
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.stop
+            .readers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
+}
+
+/// Serve connections one at a time, as they come from `incoming`, until the server stops (and
+/// the listener is woken up).
+fn serve<S: Stream>(
+    incoming: impl Iterator<Item = io::Result<S>>,
+    stats: &Arc<ServerStats>,
+    stop: &Stop,
+) {
+    for stream in incoming {
+        if stop.stopping() {
+            break;
+        }
         match stream {
             Ok(stream) => {
-                tracing::info!("accepted new connection: {}", stream.peer_addr().unwrap());
-                thread::spawn(move || {
-                    let peer_addr = stream.peer_addr().unwrap();
-                    // connection succeeded
-                    if let Err(e) = handle_client(stream) {
-                        tracing::warn!("failed to handle connection from {}: {:?}", peer_addr, e);
-                    }
-                });
+                let peer_addr = stream.peer();
+                tracing::info!("accepted new connection: {}", peer_addr);
+                let _connection = stats.connection();
+                // connection succeeded
+                if let Err(e) = handle_client(stream, stats, stop) {
+                    stats.error(&*e);
+                    tracing::warn!("failed to handle connection from {}: {:?}", peer_addr, e);
+                }
             }
             Err(e) => {
+                stats.error(&e);
                 tracing::warn!("failed to accept connection: {}", e);
                 /* connection failed */
             }
         }
     }
+}
+
+/// Connections from `incoming`, once the socket options are set.
+fn configured<'a, S: AsFd>(
+    incoming: impl Iterator<Item = io::Result<S>> + 'a,
+    socket: &'a SocketArgs,
+) -> impl Iterator<Item = io::Result<S>> + 'a {
+    incoming.map(|stream| stream.and_then(|stream| socket.apply(&stream).map(|()| stream)))
+}
 
-    drop(listener);
+/// Serve TCP connections, with `--tls` if there is a `config`.
+fn serve_tcp(
+    listener: TcpListener,
+    config: Option<Arc<ServerConfig>>,
+    socket: &SocketArgs,
+    stats: &Arc<ServerStats>,
+    stop: &Stop,
+) {
+    let incoming = configured(listener.incoming(), socket);
+    match config {
+        Some(config) => {
+            // the TLS handshake happens on the first read, bounded by the handshake timeout
+            let incoming = incoming.map(|stream| {
+                stream.and_then(|stream| TlsStream::server(stream, Arc::clone(&config)))
+            });
+            serve(incoming, stats, stop);
+        }
+        None => serve(incoming, stats, stop),
+    }
+}
+
+/// Block until a signal, then stop the connections (between two messages), and wake up the
+/// workers waiting for a connection on `listeners`.
+fn stop_on_signal<L: AsFd>(signal: Signal, stop: &Stop, listeners: &[L]) -> anyhow::Result<()> {
+    signal.wait().context("failed to handle signals")?;
+    tracing::info!("shutting down");
+    stop.stop();
+    listeners.iter().for_each(endpoint::wake);
     Ok(())
 }
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args: Args = echo_common::cli::parse("9094");
+    let mode = args.mode;
+    let args = args.common;
+
+    let stats = ServerStats::new();
+    if let Some(admin_addr) = args.admin_addr() {
+        stats.serve_admin(&admin_addr)?;
+        tracing::info!("serving stats on {}", admin_addr);
+    }
+    tracing::info!("socket options: {}", args.socket.describe());
+
+    let stop = Arc::new(Stop::default());
+    let signal = Signal::new().context("failed to handle signals")?;
+    // every worker serves a connection at a time: the others wait in the listen queue
+    let workers = args.parallelism();
+
+    match args.endpoint() {
+        Endpoint::Tcp(addr) => {
+            let config = if args.tls.enabled() {
+                let config = Credentials::server(&args.tls, &args.host)?
+                    .server_config(args.tls.mtls)
+                    .context("failed to set up TLS")?;
+                Some(config)
+            } else {
+                None
+            };
+            let (listeners, cores) = match mode {
+                ServerMode::Shared => {
+                    // the workers accept on clones of a single listener
+                    let listener = endpoint::tcp_listener(&addr, args.backlog, false)
+                        .with_context(|| format!("failed to bind {}", addr))?;
+                    let listeners = (0..workers)
+                        .map(|_| listener.try_clone())
+                        .collect::<io::Result<Vec<_>>>()?;
+                    (listeners, vec![None; workers])
+                }
+                ServerMode::ThreadPerCore => {
+                    let cores = per_core::cores(workers)?;
+                    let listeners = per_core::listeners(&addr, workers, args.backlog)
+                        .with_context(|| format!("failed to bind {}", addr))?;
+                    (listeners, cores.into_iter().map(Some).collect())
+                }
+            };
+            tracing::info!("server listening on {} with {} workers", addr, workers);
+            stats.listening();
+            for (listener, core) in listeners.iter().zip(cores) {
+                let listener = listener.try_clone()?;
+                let config = config.clone();
+                let socket = args.socket.clone();
+                let stats = Arc::clone(&stats);
+                let stop = Arc::clone(&stop);
+                thread::spawn(move || {
+                    // the reader threads of its connections inherit the pinning
+                    if let Some(Err(e)) = core.map(per_core::pin) {
+                        tracing::warn!("{}", e);
+                    }
+                    serve_tcp(listener, config, &socket, &stats, &stop);
+                });
+            }
+            stop_on_signal(signal, &stop, &listeners)?;
+        }
+        Endpoint::Unix(path) => {
+            args.tls.reject("over Unix domain sockets")?;
+            if mode == ServerMode::ThreadPerCore {
+                return Err(anyhow::anyhow!(
+                    "--mode thread-per-core is not supported over Unix domain sockets"
+                ));
+            }
+            endpoint::remove_stale_socket(&path)?;
+            let listener = endpoint::unix_listener(&path, args.backlog)
+                .with_context(|| format!("failed to bind {}", path.display()))?;
+            tracing::info!(
+                "server listening on {} with {} workers",
+                path.display(),
+                workers
+            );
+            stats.listening();
+            for _ in 0..workers {
+                let listener = listener.try_clone()?;
+                let socket = args.socket.clone();
+                let stats = Arc::clone(&stats);
+                let stop = Arc::clone(&stop);
+                thread::spawn(move || {
+                    let incoming = configured(listener.incoming(), &socket);
+                    serve(incoming, &stats, &stop)
+                });
+            }
+            stop_on_signal(signal, &stop, &[&listener])?;
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+
+    let left = shutdown::drain(&stats, args.drain_timeout);
+    tracing::info!("drained connections, {} left", left);
+    shutdown::finish(&stats, left)
+}
@@ -0,0 +1,258 @@
+//! `--tls` over blocking TCP streams, for the server and the client.
+//!
+//! `rustls::StreamOwned` cannot be split between the reader and writer threads of a
+//! connection, so both halves share the TLS session behind a lock, which is never held while
+//! blocking on the socket: the reader reads ciphertext into the session's `pending` buffer
+//! first, and the writer takes the ciphertext out of the session before writing it.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use rustls::{ClientConnection, Connection, ServerConfig, ServerConnection};
+
+const READ_SIZE: usize = 1 << 16;
+
+struct Session {
+    connection: Connection,
+    /// Ciphertext read from the socket, but not yet handed over to `connection`.
+    pending: Vec<u8>,
+}
+
+/// A TLS connection, of which each clone can read or write.
+pub struct TlsStream {
+    session: Arc<Mutex<Session>>,
+    socket: TcpStream,
+    /// Ciphertext read from `socket`, until it goes to the session.
+    buffer: Box<[u8]>,
+    /// Serializes the ciphertext written by every clone.
+    writing: Arc<Mutex<TcpStream>>,
+}
+
+impl TlsStream {
+    /// The server side of a connection, whose handshake happens on the first read.
+    pub fn server(socket: TcpStream, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let connection = ServerConnection::new(config).map_err(io::Error::other)?;
+        Self::new(socket, connection.into())
+    }
+
+    /// The client side of a connection, once `connection` is through its handshake.
+    pub fn client(socket: TcpStream, connection: ClientConnection) -> io::Result<Self> {
+        Self::new(socket, connection.into())
+    }
+
+    fn new(socket: TcpStream, connection: Connection) -> io::Result<Self> {
+        Ok(TlsStream {
+            session: Arc::new(Mutex::new(Session {
+                connection,
+                pending: Vec::new(),
+            })),
+            writing: Arc::new(Mutex::new(socket.try_clone()?)),
+            socket,
+            buffer: vec![0; READ_SIZE].into(),
+        })
+    }
+
+    /// The underlying TCP stream.
+    pub fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(TlsStream {
+            session: Arc::clone(&self.session),
+            socket: self.socket.try_clone()?,
+            buffer: vec![0; READ_SIZE].into(),
+            writing: Arc::clone(&self.writing),
+        })
+    }
+
+    /// Shut the connection down; the write side with a `close_notify` first.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Write {
+            return self.socket.shutdown(how);
+        }
+        self.session()?.connection.send_close_notify();
+        match self.flush_tls().and_then(|()| self.socket.shutdown(how)) {
+            // the peer closed first, and reset the connection on our close_notify
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::NotConnected | ErrorKind::BrokenPipe | ErrorKind::ConnectionReset
+                ) =>
+            {
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn session(&self) -> io::Result<MutexGuard<'_, Session>> {
+        self.session
+            .lock()
+            .map_err(|_| io::Error::other("TLS session poisoned"))
+    }
+
+    /// Write whatever the session has to send (records, handshake messages or alerts).
+    fn flush_tls(&self) -> io::Result<()> {
+        let mut socket = self
+            .writing
+            .lock()
+            .map_err(|_| io::Error::other("TLS socket poisoned"))?;
+        let mut ciphertext = Vec::new();
+        {
+            let mut session = self.session()?;
+            while session.connection.wants_write() {
+                session.connection.write_tls(&mut ciphertext)?;
+            }
+        }
+        socket.write_all(&ciphertext)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut session = self.session()?;
+                match session.connection.reader().read(buf) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+                // only fed once the plaintext is consumed, so that it never overflows
+                if !session.pending.is_empty() {
+                    let Session {
+                        connection,
+                        pending,
+                    } = &mut *session;
+                    let n = connection.read_tls(&mut &pending[..])?;
+                    pending.drain(..n);
+                    let processed = connection.process_new_packets();
+                    let wants_write = connection.wants_write();
+                    drop(session);
+                    if wants_write {
+                        // the handshake, or an alert on error
+                        self.flush_tls()?;
+                    }
+                    processed.map_err(io::Error::other)?;
+                    continue;
+                }
+            }
+
+            let n = self.socket.read(&mut self.buffer)?;
+            let mut session = self.session()?;
+            if n == 0 {
+                // the session tells a clean close from a truncation
+                session.connection.read_tls(&mut io::empty())?;
+                session
+                    .connection
+                    .process_new_packets()
+                    .map_err(io::Error::other)?;
+            } else {
+                session.pending.extend_from_slice(&self.buffer[..n]);
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.session()?.connection.writer().write(buf)?;
+        self.flush_tls()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_tls()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use echo_common::tls::{self, Credentials};
+    use echo_common::TlsArgs;
+
+    /// A connected pair of streams, with the client through its handshake.
+    fn pair(name: &str) -> (TlsStream, TlsStream) {
+        let args = TlsArgs {
+            tls: true,
+            mtls: false,
+            tls_dir: std::env::temp_dir().join(format!("echo_tls-{}-{}", name, std::process::id())),
+        };
+        let server_config = Credentials::server(&args, "localhost")
+            .unwrap()
+            .server_config(false)
+            .unwrap();
+        let client_config = Credentials::client(&args)
+            .unwrap()
+            .client_config(false)
+            .unwrap();
+        std::fs::remove_dir_all(&args.tls_dir).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let server = TlsStream::server(accepted, server_config).unwrap();
+
+        // the server's handshake happens as it reads
+        let mut reading = server.try_clone().unwrap();
+        let first_read = thread::spawn(move || {
+            let mut buf = [0; 5];
+            reading.read_exact(&mut buf).map(|()| buf)
+        });
+        let mut connection =
+            ClientConnection::new(client_config, tls::server_name("localhost").unwrap()).unwrap();
+        while connection.is_handshaking() {
+            connection.complete_io(&mut socket).unwrap();
+        }
+        let mut client = TlsStream::client(socket, connection).unwrap();
+        client.write_all(b"hello").unwrap();
+        assert_eq!(&first_read.join().unwrap().unwrap(), b"hello");
+        (client, server)
+    }
+
+    #[test]
+    fn clones_read_while_others_write() {
+        let (client, server) = pair("clones");
+        // more than the socket buffers hold, so that neither side gets away with writing first
+        let message = (0..4 << 20).map(|i| i as u8).collect::<Vec<_>>();
+
+        let echo = thread::spawn(move || {
+            let mut writer = server.try_clone().unwrap();
+            let mut reader = server;
+            io::copy(&mut reader, &mut writer).unwrap();
+            writer.shutdown(Shutdown::Write).unwrap();
+        });
+
+        let mut writer = client.try_clone().unwrap();
+        let mut reader = client;
+        let sent = message.clone();
+        let writing = thread::spawn(move || {
+            writer.write_all(&sent).unwrap();
+            writer.shutdown(Shutdown::Write).unwrap();
+        });
+        let mut reply = Vec::new();
+        reader.read_to_end(&mut reply).unwrap();
+        writing.join().unwrap();
+        echo.join().unwrap();
+        assert!(reply == message, "the reply differs");
+    }
+
+    #[test]
+    fn truncation_is_not_a_clean_close() {
+        let (client, mut server) = pair("truncation");
+        // no close_notify
+        client.socket().shutdown(Shutdown::Write).unwrap();
+        let e = server.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+}
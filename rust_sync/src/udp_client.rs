@@ -0,0 +1,112 @@
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use clap::Parser;
+use echo_common::datagram::{self, DatagramStats, Tracker, MAX_DATAGRAM_LEN};
+use echo_common::{ClientArgs, DatagramArgs, Report};
+
+#[derive(Parser, Clone, Debug)]
+#[command(author, version, about, long_about=None)]
+struct Args {
+    #[command(flatten)]
+    common: ClientArgs,
+
+    #[command(flatten)]
+    datagram: DatagramArgs,
+}
+
+/// Closed-loop worker: send one message at a time, and wait for its reply until it is lost.
+fn closed_client(
+    report: &Report,
+    args: &ClientArgs,
+    loss_timeout: Duration,
+    worker: usize,
+) -> anyhow::Result<DatagramStats> {
+    let server = datagram::resolve(&args.endpoint())?;
+    let socket = UdpSocket::bind(datagram::local_addr(server))?;
+    args.socket.apply(&socket)?;
+    socket.connect(server).context("failed to connect")?;
+
+    let mut recorder = report.recorder(worker, socket.local_addr()?.to_string());
+    let mut sizes = recorder.sizes()?;
+    let mut payload = recorder.payload();
+    let mut tracker = Tracker::new(loss_timeout);
+    let mut buffer = vec![0; MAX_DATAGRAM_LEN];
+
+    while recorder.running() {
+        let message = payload.next(sizes.next().expect("sizes never run out"));
+        // generated up front, so that only the echo is timed
+        let request = datagram::encode(message);
+        tracker.track(Instant::now(), message, ());
+        socket.send(&request).context("failed to send")?;
+
+        while let Some(deadline) = tracker.next_deadline() {
+            let now = Instant::now();
+            if now >= deadline {
+                tracker.expire(now);
+                continue;
+            }
+            socket.set_read_timeout(Some(deadline - now))?;
+            match socket.recv(&mut buffer) {
+                Ok(n) => {
+                    if let Some((elapsed, size)) = tracker.receive(&buffer[..n], Instant::now())? {
+                        recorder.record(elapsed, size);
+                    }
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e).context("failed to receive"),
+            }
+        }
+    }
+    recorder.finish();
+
+    Ok(tracker.stats().clone())
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::filter::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+    let args: Args = echo_common::cli::parse("9094");
+    let loss_timeout = args.datagram.loss_timeout;
+    let args = args.common;
+    args.tls.reject("over UDP")?;
+    datagram::check_size(args.size_dist().max())?;
+
+    let parallelism = args.parallelism();
+    let report = Report::new(&args)?;
+
+    report.header();
+    echo_common::wait_for_start(args.start);
+
+    let stats = std::thread::scope(|s| {
+        let runners = (0..parallelism)
+            .map(|worker| {
+                let report = &report;
+                let args = &args;
+                s.spawn(move || closed_client(report, args, loss_timeout, worker))
+            })
+            .collect::<Vec<_>>();
+
+        runners
+            .into_iter()
+            .filter_map(|x| match x.join() {
+                Ok(ok) => Some(ok),
+                Err(e) => {
+                    tracing::warn!("join error: {:?}", e);
+                    None
+                }
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    })?;
+
+    let mut total = DatagramStats::default();
+    for worker in &stats {
+        total.merge(worker);
+    }
+    report.datagrams(&total);
+    report.summary()
+}
@@ -0,0 +1,102 @@
+use clap::Parser;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use anyhow::Context;
+use echo_common::datagram::{self, MAX_DATAGRAM_LEN};
+use echo_common::shutdown::{self, Signal};
+use echo_common::{ServerArgs, ServerStats};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about=None)]
+struct Args {
+    #[command(flatten)]
+    common: ServerArgs,
+}
+
+/// Echo datagrams back to their sender until `stopping` is set (and the socket woken up).
+fn serve(socket: &UdpSocket, stats: &ServerStats, stopping: &AtomicBool) {
+    let mut buffer = vec![0; MAX_DATAGRAM_LEN];
+    loop {
+        let received = socket.recv_from(&mut buffer);
+        if stopping.load(Ordering::Relaxed) {
+            break;
+        }
+        match received {
+            Ok((n, peer_addr)) => {
+                stats.bytes_in(datagram::payload_len(n));
+                match socket.send_to(&buffer[..n], peer_addr) {
+                    Ok(_) => {
+                        stats.bytes_out(datagram::payload_len(n));
+                        stats.message();
+                    }
+                    Err(e) => {
+                        stats.error(&e);
+                        tracing::warn!("failed to echo to {}: {}", peer_addr, e);
+                    }
+                }
+            }
+            Err(e) => {
+                stats.error(&e);
+                tracing::warn!("failed to receive: {}", e);
+            }
+        }
+    }
+}
+
+/// Address to send to in order to wake up a blocking `recv_from` on `addr`.
+fn wake_addr(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    addr
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args: Args = echo_common::cli::parse("9094");
+    let args = args.common;
+    args.tls.reject("over UDP")?;
+
+    let stats = ServerStats::new();
+    if let Some(admin_addr) = args.admin_addr() {
+        stats.serve_admin(&admin_addr)?;
+        tracing::info!("serving stats on {}", admin_addr);
+    }
+
+    let addr = datagram::resolve(&args.endpoint())?;
+    let socket = UdpSocket::bind(addr).with_context(|| format!("failed to bind {}", addr))?;
+    args.socket.apply(&socket)?;
+    tracing::info!("server listening on {}", addr);
+    stats.listening();
+    tracing::info!("socket options: {}", args.socket.describe());
+
+    let workers = args.parallelism();
+    let stopping = AtomicBool::new(false);
+    let signal = Signal::new().context("failed to handle signals")?;
+    let wake = wake_addr(socket.local_addr()?);
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| serve(&socket, &stats, &stopping));
+        }
+
+        if signal.wait().is_ok() {
+            tracing::info!("shutting down");
+        }
+        stopping.store(true, Ordering::Relaxed);
+        // one empty datagram per worker, which notices `stopping` once it gets one
+        let waker = UdpSocket::bind(datagram::local_addr(wake))?;
+        for _ in 0..workers {
+            waker.send_to(&[], wake)?;
+        }
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    // datagrams are echoed as they come: there is nothing in flight to drain
+    shutdown::finish(&stats, 0)
+}
@@ -1,118 +1,286 @@
 use anyhow::Context;
-use chrono::{Local, NaiveDateTime, NaiveTime};
 use clap::Parser;
 use echo::echoer_client::EchoerClient;
 use echo::EchoRequest;
-use tokio::time::Duration;
-use tonic::transport::Channel;
+use echo_common::protocol;
+use echo_common::tls::{self, Credentials};
+use echo_common::{
+    Arrivals, Bursts, ClientArgs, ClientType, LoadArgs, Message, Report, SocketArgs,
+};
+use futures::stream::{FuturesUnordered, StreamExt};
+use hyper::client::HttpConnector;
+use streaming::Load;
+use tokio::time::{Duration, Instant};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+
+mod streaming;
 
 pub mod echo {
     tonic::include_proto!("echo");
 }
 
-fn size_parser(s: &str) -> anyhow::Result<usize> {
-    parse_size::Config::new()
-        .with_binary()
-        .parse_size(s)
-        .map(|x| x as usize)
-        .map_err(|e| anyhow::anyhow!("failed to parse {}: {:?}", s, e))
-}
+#[derive(Parser, Clone)]
+#[command(author, version, about, long_about=None)]
+struct Args {
+    #[command(flatten)]
+    common: ClientArgs,
+
+    #[command(flatten)]
+    load: LoadArgs,
 
-fn time_parser(s: &str) -> anyhow::Result<NaiveDateTime> {
-    let today = Local::now().date_naive();
-    let time =
-        NaiveTime::parse_from_str(s, "%H:%M:%S").context("failed to parse start timestamp")?;
-    Ok(NaiveDateTime::new(today, time))
+    /// The RPC that carries the messages.
+    #[arg(long, value_enum, default_value_t = Rpc::Unary)]
+    rpc: Rpc,
+
+    /// Messages per call of the client- and server-streaming RPCs.
+    #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u32).range(1..))]
+    batch_size: u32,
 }
 
-fn duration_parser(s: &str) -> anyhow::Result<u64> {
-    humantime::parse_duration(s)
-        .map(|s| s.as_secs())
-        .context("failed to parse duration")
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum Rpc {
+    /// `Echo`: a call per message.
+    Unary,
+    /// `StreamEcho`: a single stream per worker, on which every message is echoed.
+    Bidi,
+    /// `ClientStreamEcho`: every call streams `--batch-size` messages, echoed in one reply.
+    ClientStreaming,
+    /// `ServerStreamEcho`: every call sends `--batch-size` messages at once, echoed as a
+    /// stream of replies.
+    ServerStreaming,
 }
 
-#[derive(Clone, Copy, clap::ValueEnum)]
-enum ClientType {
-    Bursty,
-    Closed,
+/// The request of a message, generated before it is timed.
+fn request(message: Message) -> EchoRequest {
+    let mut sent = message;
+    let mut msg = vec![0; message.size()];
+    sent.fill(&mut msg);
+    EchoRequest { msg }
 }
 
-#[derive(Parser, Clone)]
-#[command(author, version, about, long_about=None)]
-struct Args {
-    #[arg(default_value = "[::1]")]
-    host: String,
+/// Check that `reply` echoes `message`.
+fn check_reply(message: Message, reply: &[u8]) -> anyhow::Result<()> {
+    if reply.len() != message.size() {
+        return Err(anyhow::anyhow!(
+            "mismatched reply: {} bytes, sent {}",
+            reply.len(),
+            message.size()
+        ));
+    }
+    let mut expected = message;
+    expected.check(reply)
+}
 
-    #[arg(default_value_t = 9091, value_parser = clap::value_parser!(u16).range(1..))]
-    port: u16,
+/// Echo one message and check the reply; returns its latency along with its size, for the
+/// workers that keep several in flight.
+async fn do_run(
+    mut client: EchoerClient<Channel>,
+    message: Message,
+) -> anyhow::Result<(Duration, usize)> {
+    let request = tonic::Request::new(request(message));
+    let start = tokio::time::Instant::now();
+    let reply = client.echo(request).await?.into_inner();
+    check_reply(message, &reply.msg)?;
+    Ok((start.elapsed(), message.size()))
+}
 
-    #[arg(short = 'j', long)]
-    n_cores: Option<usize>,
+/// Where every worker connects, over TLS with `--tls`.
+fn endpoint(args: &ClientArgs) -> anyhow::Result<Endpoint> {
+    if !args.tls.enabled() {
+        return Ok(Channel::from_shared(format!("http://{}", args.addr()))?);
+    }
+    let credentials = Credentials::client(&args.tls)?;
+    let mut tls = ClientTlsConfig::new()
+        .ca_certificate(Certificate::from_pem(credentials.ca_pem()))
+        .domain_name(tls::unbracketed(&args.host));
+    if args.tls.mtls {
+        tls = tls.identity(Identity::from_pem(
+            credentials.cert_pem(),
+            credentials.key_pem(),
+        ));
+    }
+    Ok(Channel::from_shared(format!("https://{}", args.addr()))?.tls_config(tls)?)
+}
 
-    #[arg(short, long, default_value = "60s", value_parser = duration_parser)]
-    duration: u64,
+/// Makes the TCP connections of a channel, with the socket options it can take.
+fn connector(socket: &SocketArgs) -> HttpConnector {
+    // as `Endpoint::connect` does, with the `Endpoint` defaults (TCP_NODELAY on, unless
+    // `--nodelay=false`)
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_nodelay(socket.nodelay.unwrap_or(true));
+    http.set_send_buffer_size(socket.sndbuf);
+    http.set_recv_buffer_size(socket.rcvbuf);
+    http
+}
 
-    #[arg(short, long, default_value = "10s", value_parser = duration_parser)]
-    warmup: u64,
+async fn connect(
+    report: &Report,
+    args: &ClientArgs,
+    endpoint: &Endpoint,
+) -> anyhow::Result<EchoerClient<Channel>> {
+    let start = Instant::now();
+    let channel = endpoint
+        .connect_with_connector(connector(&args.socket))
+        .await?;
+    let mut client = EchoerClient::new(channel);
+    if args.tls.enabled() {
+        // TCP and TLS handshakes
+        report.handshake(start.elapsed());
+        // with TLS 1.3, servers check client certificates once the client is done with the
+        // handshake, and the channel would keep reconnecting on a rejection: probe it
+        let probe = client.echo(EchoRequest { msg: Vec::new() });
+        tokio::time::timeout(protocol::HANDSHAKE_TIMEOUT, probe)
+            .await
+            .map_err(|_| anyhow::anyhow!("no reply from the server"))
+            .and_then(|reply| Ok(reply?))
+            .with_context(|| format!("TLS with {} failed", args.addr()))?;
+    }
+    tracing::info!("connected @ {}", args.addr());
+    Ok(client)
+}
 
-    #[arg(short, long, default_value_t = 1, value_parser = size_parser)]
-    message_size: usize,
+/// Channels do not expose their local address: connections are identified by worker.
+fn connection_id(worker: usize) -> String {
+    format!("channel-{}", worker)
+}
 
-    #[arg(short, long, value_parser = time_parser)]
-    start: Option<NaiveDateTime>,
+async fn closed_client(
+    report: &Report,
+    args: &ClientArgs,
+    endpoint: &Endpoint,
+    worker: usize,
+) -> anyhow::Result<()> {
+    let client = connect(report, args, endpoint).await?;
 
-    #[arg(short, long)]
-    client_type: ClientType,
+    let recorder = report.recorder(worker, connection_id(worker));
+    echo_common::closed_loop_async(recorder, |message| {
+        let reply = do_run(client.clone(), message);
+        async move { reply.await.map(|(elapsed, _)| elapsed) }
+    })
+    .await
 }
-impl Args {
-    fn duration(&self) -> Duration {
-        return Duration::from_secs(self.duration);
-    }
-    fn warmup(&self) -> Duration {
-        return Duration::from_secs(self.warmup);
+
+/// Open-loop worker: requests are issued on schedule, each as its own RPC, and their latency
+/// is measured from the scheduled send instant.
+async fn open_client(
+    report: &Report,
+    args: &ClientArgs,
+    endpoint: &Endpoint,
+    worker: usize,
+    rate: f64,
+) -> anyhow::Result<()> {
+    let client = connect(report, args, endpoint).await?;
+
+    let mut recorder = report.recorder(worker, connection_id(worker));
+    let mut sizes = recorder.sizes()?;
+    let mut payload = recorder.payload();
+    let deadline = Instant::from_std(recorder.deadline());
+    let mut arrivals = Arrivals::new(rate)?.map(Instant::from_std);
+    let mut next = arrivals.next();
+    let mut in_flight = FuturesUnordered::new();
+
+    loop {
+        let scheduled = next.filter(|scheduled| *scheduled < deadline);
+        if scheduled.is_none() && in_flight.is_empty() {
+            break;
+        }
+
+        tokio::select! {
+            Some(scheduled) = async move {
+                let scheduled = scheduled?;
+                tokio::time::sleep_until(scheduled).await;
+                Some(scheduled)
+            } => {
+                let message = payload.next(sizes.next().expect("sizes never run out"));
+                let reply = do_run(client.clone(), message);
+                in_flight.push(async move {
+                    reply.await.map(|(_, size)| (scheduled.elapsed(), size))
+                });
+                next = arrivals.next();
+            }
+            Some(reply) = in_flight.next() => {
+                let (elapsed, size) = reply?;
+                recorder.record(elapsed, size);
+            }
+        }
     }
-}
+    recorder.finish();
 
-async fn do_run(
-    mut client: EchoerClient<Channel>,
-    request: tonic::Request<EchoRequest>,
-) -> anyhow::Result<Duration> {
-    let start = tokio::time::Instant::now();
-    let _reply = client.echo(request).await?;
-    Ok(start.elapsed())
+    Ok(())
 }
 
-async fn closed_client(id: &str, args: &Args) -> anyhow::Result<()> {
-    let client = EchoerClient::connect(format!("http://{}:{}", args.host, args.port)).await?;
-    tracing::info!("connected @ {}:{}", args.host, args.port);
-    let request = EchoRequest {
-        msg: vec![42u8; args.message_size].into(),
-    };
+/// Pipelined worker: up to `depth` RPCs are outstanding on the channel at any time, each
+/// replaced as soon as it completes. HTTP/2 multiplexes them, so replies may come back in any
+/// order; each RPC is timed on its own.
+async fn pipelined_client(
+    report: &Report,
+    args: &ClientArgs,
+    endpoint: &Endpoint,
+    worker: usize,
+    depth: usize,
+) -> anyhow::Result<()> {
+    let client = connect(report, args, endpoint).await?;
 
-    let mut reporting = false;
-    let start = std::time::Instant::now();
-    while start.elapsed() < args.duration() + args.warmup() {
-        let elapsed = do_run(client.clone(), tonic::Request::new(request.clone())).await?;
-        if !reporting && start.elapsed() > args.warmup() {
-            reporting = true;
-            println!("Start: {} {:.9}", id, start.elapsed().as_secs_f64());
-        }
+    let mut recorder = report.recorder(worker, connection_id(worker));
+    let mut sizes = recorder.sizes()?;
+    let mut payload = recorder.payload();
+    let mut in_flight = (&mut sizes)
+        .take(depth)
+        .map(|size| do_run(client.clone(), payload.next(size)))
+        .collect::<FuturesUnordered<_>>();
 
-        if reporting && start.elapsed() < args.duration() + args.warmup() {
-            println!("{:.3}", elapsed.as_secs_f64() * 1_000_000f64);
+    while let Some(reply) = in_flight.next().await {
+        let (elapsed, size) = reply?;
+        recorder.record(elapsed, size);
+        if recorder.running() {
+            let message = payload.next(sizes.next().expect("sizes never run out"));
+            in_flight.push(do_run(client.clone(), message));
         }
     }
+    recorder.finish();
 
-    println!("End: {} {:.9}", id, start.elapsed().as_secs_f64());
+    Ok(())
+}
 
+async fn run_open(
+    report: &Report,
+    args: ClientArgs,
+    endpoint: &Endpoint,
+    rate: f64,
+) -> anyhow::Result<()> {
+    let parallelism = args.parallelism();
+    report.rate(rate);
+    let runners = (0..parallelism)
+        .map(|worker| open_client(report, &args, endpoint, worker, rate / parallelism as f64))
+        .collect::<Vec<_>>();
+    futures::future::join_all(runners)
+        .await
+        .into_iter()
+        .collect::<anyhow::Result<Vec<_>>>()?;
     Ok(())
 }
 
-async fn run_closed(id: String, args: Args) -> anyhow::Result<()> {
-    let paralellism = args.n_cores.unwrap_or_else(|| num_cpus::get());
-    let runners = (0..paralellism)
+async fn run_closed(report: &Report, args: ClientArgs, endpoint: &Endpoint) -> anyhow::Result<()> {
+    let runners = (0..args.parallelism())
+        .map(|worker| closed_client(report, &args, endpoint, worker))
+        .collect::<Vec<_>>();
+    futures::future::join_all(runners)
+        .await
         .into_iter()
-        .map(|_| closed_client(&id, &args))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(())
+}
+
+async fn run_pipelined(
+    report: &Report,
+    args: ClientArgs,
+    endpoint: &Endpoint,
+    depth: usize,
+) -> anyhow::Result<()> {
+    report.pipeline(depth);
+    let runners = (0..args.parallelism())
+        .map(|worker| pipelined_client(report, &args, endpoint, worker, depth))
         .collect::<Vec<_>>();
     futures::future::join_all(runners)
         .await
@@ -121,84 +289,157 @@ async fn run_closed(id: String, args: Args) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn run_bursty(id: String, args: Args) -> anyhow::Result<()> {
-    let paralellism = args.n_cores.unwrap_or_else(|| num_cpus::get());
-    let client = EchoerClient::connect(format!("http://{}:{}", args.host, args.port)).await?;
-    tracing::info!("connected @ {}:{}", args.host, args.port);
-    let request = EchoRequest {
-        msg: vec![42u8; args.message_size].into(),
-    };
+/// Push out bursts of `burst_size` requests; with a `period`, bursts start once per period (see
+/// `Bursts` for the ones that overrun it), otherwise each burst starts as soon as the previous
+/// one is over.
+async fn run_bursty(
+    report: &Report,
+    args: ClientArgs,
+    endpoint: &Endpoint,
+    burst_size: usize,
+    period: Option<Duration>,
+) -> anyhow::Result<()> {
+    let client = connect(report, &args, endpoint).await?;
 
-    let mut reporting = false;
-    let start = tokio::time::Instant::now();
-    while start.elapsed() < args.duration() + args.warmup() {
-        if !reporting && start.elapsed() > args.warmup() {
-            reporting = true;
-            println!("Start: {} {:.9}", id, start.elapsed().as_secs_f64());
+    let mut bursts = period.map(Bursts::new);
+
+    let mut recorder = report.recorder(0, connection_id(0));
+    let mut sizes = recorder.sizes()?;
+    let mut payload = recorder.payload();
+    while recorder.running() {
+        if let Some(bursts) = bursts.as_mut() {
+            bursts.tick().await;
         }
-        let futs = (0..paralellism)
-            .into_iter()
-            .map(|_| do_run(client.clone(), tonic::Request::new(request.clone())))
+        let futs = (&mut sizes)
+            .take(burst_size)
+            .map(|size| do_run(client.clone(), payload.next(size)))
             .collect::<Vec<_>>();
 
-        futures::future::join_all(futs)
-            .await
-            .into_iter()
-            .map(|x| {
-                x.map(|elapsed| {
-                    if reporting && start.elapsed() < args.duration() + args.warmup() {
-                        println!("{:.3}", elapsed.as_secs_f64() * 1_000_000f64);
-                    }
-                })
-            })
-            .collect::<anyhow::Result<Vec<()>>>()?;
+        for reply in futures::future::join_all(futs).await {
+            let (elapsed, size) = reply?;
+            recorder.record(elapsed, size);
+        }
+    }
+    if let Some(skipped) = bursts.map(|bursts| bursts.skipped()).filter(|n| *n > 0) {
+        tracing::warn!(
+            "{} bursts skipped, as earlier ones overran the burst period",
+            skipped
+        );
     }
-    println!("End: {} {:.9}", id, start.elapsed().as_secs_f64());
+    recorder.finish();
 
     Ok(())
 }
 
-async fn run(id: String, args: Args) -> anyhow::Result<()> {
-    match args.client_type {
-        ClientType::Bursty => run_bursty(id, args).await,
-        ClientType::Closed => run_closed(id, args).await,
+async fn run(report: &Report, args: Args) -> anyhow::Result<()> {
+    let endpoint = &endpoint(&args.common)?;
+    if args.rpc != Rpc::Unary {
+        return run_streaming(report, args, endpoint).await;
+    }
+    match args.load.client_type {
+        ClientType::Bursty => {
+            let burst_size = args.common.parallelism();
+            run_bursty(report, args.common, endpoint, burst_size, None).await
+        }
+        ClientType::ControlledBursty => {
+            let burst_size = args
+                .load
+                .burst_size
+                .unwrap_or_else(|| args.common.parallelism());
+            let period = args.load.burst_period.ok_or_else(|| {
+                anyhow::anyhow!("controlled bursty clients need a --burst-period")
+            })?;
+            report.burst(burst_size, period);
+            run_bursty(report, args.common, endpoint, burst_size, Some(period)).await
+        }
+        ClientType::Closed => run_closed(report, args.common, endpoint).await,
+        ClientType::Open => {
+            let rate = args
+                .load
+                .rate
+                .ok_or_else(|| anyhow::anyhow!("open-loop clients need a --rate"))?;
+            run_open(report, args.common, endpoint, rate).await
+        }
+        ClientType::Pipelined => {
+            let depth = args
+                .load
+                .pipeline_depth
+                .ok_or_else(|| anyhow::anyhow!("pipelined clients need a --pipeline-depth"))?;
+            run_pipelined(report, args.common, endpoint, depth as usize).await
+        }
     }
 }
 
+/// Run one of the streaming RPCs, which support fewer client types than `Echo`.
+async fn run_streaming(report: &Report, args: Args, endpoint: &Endpoint) -> anyhow::Result<()> {
+    let parallelism = args.common.parallelism();
+    let load = match (args.rpc, args.load.client_type) {
+        (_, ClientType::Closed) => Load::Window(1),
+        (Rpc::Bidi, ClientType::Pipelined) => {
+            let depth = args
+                .load
+                .pipeline_depth
+                .ok_or_else(|| anyhow::anyhow!("pipelined clients need a --pipeline-depth"))?;
+            report.pipeline(depth as usize);
+            Load::Window(depth as usize)
+        }
+        (Rpc::Bidi, ClientType::Open) => {
+            let rate = args
+                .load
+                .rate
+                .ok_or_else(|| anyhow::anyhow!("open-loop clients need a --rate"))?;
+            report.rate(rate);
+            Load::Rate(rate / parallelism as f64)
+        }
+        (rpc, client_type) => {
+            return Err(anyhow::anyhow!(
+                "{:?} clients are not supported with {:?} RPCs",
+                client_type,
+                rpc
+            ))
+        }
+    };
+
+    let args = &args;
+    let runners = (0..parallelism)
+        .map(|worker| async move {
+            let client = connect(report, &args.common, endpoint).await?;
+            let recorder = report.recorder(worker, connection_id(worker));
+            let batch_size = args.batch_size as usize;
+            match args.rpc {
+                Rpc::Unary => unreachable!("unary calls are not streamed"),
+                Rpc::Bidi => streaming::bidi(client, recorder, load).await,
+                Rpc::ClientStreaming => {
+                    streaming::client_streaming(client, recorder, batch_size).await
+                }
+                Rpc::ServerStreaming => {
+                    streaming::server_streaming(client, recorder, batch_size).await
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    futures::future::join_all(runners)
+        .await
+        .into_iter()
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::INFO)
         .with_writer(std::io::stderr)
         .init();
-    let args = Args::parse();
-    let id = format!(
-        "{}:{:x}",
-        gethostname::gethostname()
-            .into_string()
-            .map_err(|os_str| anyhow::anyhow!("failed to convert hostname: {:?}", os_str))?,
-        uuid::Uuid::new_v4().simple()
-    );
-
-    let rt = if let Some(n_cores) = args.n_cores {
-        tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .worker_threads(n_cores)
-            .build()
-            .unwrap()
-    } else {
-        tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .build()
-            .unwrap()
-    };
+    let args: Args = echo_common::cli::parse("9091");
+    args.common.socket.reject_tuning("by tonic channels")?;
+    args.load.check(&args.common)?;
+    let report = Report::new(&args.common)?;
 
-    println!("Message Size: {}", args.message_size);
-    if let Some(start) = args.start {
-        let now_ts = Local::now().timestamp_nanos_opt().ok_or(anyhow::anyhow!("an i64 can represent stuff until 2262. if you are still using this code in 2262 first of all, thanks i guess; second, i don't really care, probably fix this"))?;
-        let start_ts = start.timestamp_nanos_opt().ok_or(anyhow::anyhow!("an i64 can represent stuff until 2262. if you are still using this code in 2262 first of all, thanks i guess; second, i don't really care, probably fix this"))?;
-        if now_ts < start_ts {
-            std::thread::sleep(std::time::Duration::from_nanos((start_ts - now_ts) as u64));
-        }
-    }
-    rt.block_on(run(id, args))
+    let rt = echo_common::runtime(args.common.n_cores)?;
+
+    report.header();
+    echo_common::wait_for_start(args.common.start);
+    rt.block_on(run(&report, args))?;
+
+    report.summary()
 }
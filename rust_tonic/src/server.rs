@@ -1,12 +1,28 @@
+use std::future;
+use std::io;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
 
 use echo::echoer_server::{Echoer, EchoerServer};
-use echo::{EchoReply, EchoRequest};
+use echo::{EchoBatch, EchoReply, EchoRequest};
 
 use clap::Parser;
-use tonic::{transport::Server, Request, Response, Status};
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::watch;
+use tonic::transport::server::{Connected, TcpIncoming};
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+use tonic::{transport::Server, Request, Response, Status, Streaming};
 
 use anyhow::Context;
+use echo_common::endpoint;
+use echo_common::metrics::Connection;
+use echo_common::tls::Credentials;
+use echo_common::{shutdown, ServerArgs, ServerStats};
 
 pub mod echo {
     tonic::include_proto!("echo");
@@ -15,70 +31,234 @@ pub mod echo {
 #[derive(Parser)]
 #[command(author, version, about, long_about=None)]
 struct Args {
-    #[arg(default_value = "[::1]")]
-    host: String,
-
-    #[arg(default_value_t = 9091, value_parser = clap::value_parser!(u16).range(1..))]
-    port: u16,
+    #[command(flatten)]
+    common: ServerArgs,
+}
 
-    #[arg(short = 'j', long)]
-    n_cores: Option<usize>,
+pub struct MyEchoer {
+    stats: Arc<ServerStats>,
+    /// Set on shutdown, which ends the reply streams of `StreamEcho` after their current message.
+    stopping: watch::Receiver<bool>,
 }
 
-#[derive(Debug, Default)]
-pub struct MyEchoer {}
+/// Count a message, and echo it.
+fn echoed(stats: &ServerStats, msg: Vec<u8>) -> EchoReply {
+    stats.bytes_in(msg.len());
+    stats.bytes_out(msg.len());
+    stats.message();
+    EchoReply { msg }
+}
 
+// replies are `Result<_, Status>` in tonic's API
+#[allow(clippy::result_large_err)]
 #[tonic::async_trait]
 impl Echoer for MyEchoer {
+    type StreamEchoStream = BoxStream<'static, Result<EchoReply, Status>>;
+    type ServerStreamEchoStream = BoxStream<'static, Result<EchoReply, Status>>;
+
     async fn echo(&self, request: Request<EchoRequest>) -> Result<Response<EchoReply>, Status> {
-        tracing::info!(
-            "handling request from {}",
-            request
-                .remote_addr()
-                .map(|x| format!("{}", x))
-                .unwrap_or("unknown".to_string())
-        );
-        let reply = echo::EchoReply {
-            msg: request.into_inner().msg,
-        };
-
-        Ok(Response::new(reply))
+        tracing::info!("handling request from {}", peer(&request));
+        let msg = request.into_inner().msg;
+        Ok(Response::new(echoed(&self.stats, msg)))
+    }
+
+    async fn stream_echo(
+        &self,
+        request: Request<Streaming<EchoRequest>>,
+    ) -> Result<Response<Self::StreamEchoStream>, Status> {
+        tracing::info!("handling stream from {}", peer(&request));
+        let stats = Arc::clone(&self.stats);
+        let mut stopping = self.stopping.clone();
+        let replies = request
+            .into_inner()
+            .map(move |request| {
+                request
+                    .map(|request| echoed(&stats, request.msg))
+                    .inspect_err(|status| stats.error(status))
+            })
+            .take_until(async move {
+                let _ = stopping.wait_for(|stopping| *stopping).await;
+            });
+        Ok(Response::new(replies.boxed()))
+    }
+
+    async fn client_stream_echo(
+        &self,
+        request: Request<Streaming<EchoRequest>>,
+    ) -> Result<Response<EchoBatch>, Status> {
+        tracing::info!("handling stream from {}", peer(&request));
+        let mut requests = request.into_inner();
+        let mut msgs = Vec::new();
+        while let Some(request) = requests.message().await.inspect_err(|status| {
+            self.stats.error(status);
+        })? {
+            msgs.push(echoed(&self.stats, request.msg).msg);
+        }
+        Ok(Response::new(EchoBatch { msgs }))
+    }
+
+    async fn server_stream_echo(
+        &self,
+        request: Request<EchoBatch>,
+    ) -> Result<Response<Self::ServerStreamEchoStream>, Status> {
+        tracing::info!("handling batch from {}", peer(&request));
+        let stats = Arc::clone(&self.stats);
+        let replies =
+            stream::iter(request.into_inner().msgs).map(move |msg| Ok(echoed(&stats, msg)));
+        Ok(Response::new(replies.boxed()))
+    }
+}
+
+fn peer<T>(request: &Request<T>) -> String {
+    request
+        .remote_addr()
+        .map(|x| format!("{}", x))
+        .unwrap_or("unknown".to_string())
+}
+
+/// An accepted connection, counted as active until hyper drops it.
+struct Counted<IO> {
+    io: IO,
+    _connection: Connection,
+}
+
+impl<IO: Connected> Connected for Counted<IO> {
+    type ConnectInfo = IO::ConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.io.connect_info()
     }
 }
 
-async fn run(args: Args) -> anyhow::Result<()> {
+impl<IO: AsyncRead + Unpin> AsyncRead for Counted<IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for Counted<IO> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+async fn run(args: ServerArgs) -> anyhow::Result<()> {
     let addr: SocketAddr = (args.host.as_str(), args.port)
         .to_socket_addrs()
         .context("failed to parse")?
         .next()
         .ok_or_else(|| anyhow::anyhow!("no socket addrs"))?;
-    let echoer = MyEchoer::default();
+    let stats = ServerStats::new();
+    if let Some(admin_addr) = args.admin_addr() {
+        stats.serve_admin(&admin_addr)?;
+        tracing::info!("serving stats on {}", admin_addr);
+    }
+    tracing::info!("socket options: {}", args.socket.describe());
+    let (stop, stopping) = watch::channel(false);
+    let echoer = MyEchoer {
+        stats: Arc::clone(&stats),
+        stopping: stopping.clone(),
+    };
 
-    tracing::info!("preparing to serve @ {}:{}", args.host, args.port);
-    Server::builder()
+    let listener = endpoint::tcp_listener_async(addr, args.backlog, false)
+        .with_context(|| format!("failed to bind {}", addr))?;
+    stats.listening();
+    // as `Server::serve` does, with the `Server::builder` defaults (TCP_NODELAY on, unless
+    // `--nodelay=false`)
+    let nodelay = args.socket.nodelay.unwrap_or(true);
+    let incoming = TcpIncoming::from_listener(listener, nodelay, None)
+        .map_err(|e| anyhow::anyhow!("failed to bind {}: {}", addr, e))?
+        .filter_map({
+            let stats = Arc::clone(&stats);
+            let socket = args.socket.clone();
+            move |io| {
+                let io = match io {
+                    // SAFETY: the descriptor is open as long as `io` lives
+                    Ok(io) => match socket.apply(unsafe { BorrowedFd::borrow_raw(io.as_raw_fd()) })
+                    {
+                        Ok(()) => Some(Ok(Counted {
+                            io,
+                            _connection: stats.connection(),
+                        })),
+                        // an error would stop the server: drop the connection instead
+                        Err(e) => {
+                            stats.error(&e);
+                            tracing::warn!("failed to set socket options: {}", e);
+                            None
+                        }
+                    },
+                    Err(e) => {
+                        stats.error(&e);
+                        Some(Err(e))
+                    }
+                };
+                future::ready(io)
+            }
+        });
+
+    let mut builder = Server::builder();
+    if args.tls.enabled() {
+        let credentials = Credentials::server(&args.tls, &args.host)?;
+        let mut tls = ServerTlsConfig::new().identity(Identity::from_pem(
+            credentials.cert_pem(),
+            credentials.key_pem(),
+        ));
+        if args.tls.mtls {
+            tls = tls.client_ca_root(Certificate::from_pem(credentials.ca_pem()));
+        }
+        builder = builder.tls_config(tls).context("failed to set up TLS")?;
+    }
+
+    tracing::info!("preparing to serve @ {}", args.addr());
+    let serve = builder
         .add_service(EchoerServer::new(echoer))
-        .serve(addr)
-        .await?;
+        .serve_with_incoming_shutdown(incoming, {
+            let mut stopping = stopping;
+            async move {
+                let _ = stopping.wait_for(|stopping| *stopping).await;
+            }
+        });
+    tokio::pin!(serve);
+
+    tokio::select! {
+        served = &mut serve => served?,
+        signal = shutdown::signal() => {
+            signal.context("failed to handle signals")?;
+            tracing::info!("shutting down");
+            // stops accepting, and lets hyper close every connection once its requests are done
+            // (streams are done after their current message)
+            let _ = stop.send(true);
+            if tokio::time::timeout(args.drain_timeout, &mut serve).await.is_err() {
+                tracing::warn!("drain timeout expired");
+            }
+        }
+    }
 
-    Ok(())
+    let left = stats.active();
+    tracing::info!("drained connections, {} left", left);
+    shutdown::finish(&stats, left)
 }
 
 fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
-    let args = Args::parse();
-
-    let rt = if let Some(n_cores) = args.n_cores {
-        tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .worker_threads(n_cores)
-            .build()
-            .unwrap()
-    } else {
-        tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .build()
-            .unwrap()
-    };
+    let args: Args = echo_common::cli::parse("9091");
 
-    rt.block_on(run(args))
+    let rt = echo_common::runtime(args.common.n_cores)?;
+    rt.block_on(run(args.common))
 }
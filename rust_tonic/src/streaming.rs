@@ -0,0 +1,365 @@
+//! Workers of the streaming RPCs (`--rpc`), which measure the latency of every message rather
+//! than of every call.
+
+use std::sync::Arc;
+
+use echo_common::{Arrivals, Message, Recorder};
+use futures::channel::mpsc::UnboundedSender;
+use futures::StreamExt;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use tonic::transport::Channel;
+use tonic::Streaming;
+
+use crate::echo::echoer_client::EchoerClient;
+use crate::echo::{EchoBatch, EchoReply, EchoRequest};
+use crate::{check_reply, request};
+
+/// How a worker offers load on its stream.
+#[derive(Clone, Copy)]
+pub enum Load {
+    /// Keep up to this many messages outstanding (one for a closed loop).
+    Window(usize),
+    /// Send messages on an open-loop schedule, at this rate.
+    Rate(f64),
+}
+
+/// `StreamEcho`: the writer sends messages on a single stream as `load` allows, and the reader
+/// matches the replies to them in FIFO order.
+pub async fn bidi(
+    mut client: EchoerClient<Channel>,
+    mut recorder: Recorder<'_>,
+    load: Load,
+) -> anyhow::Result<()> {
+    let (requests, outgoing) = futures::channel::mpsc::unbounded();
+    let mut replies = client.stream_echo(outgoing).await?.into_inner();
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    let deadline = Instant::from_std(recorder.deadline());
+    let mut sizes = recorder.sizes()?;
+    let mut payload = recorder.payload();
+    let mut next = move || payload.next(sizes.next().expect("sizes never run out"));
+
+    let writer = async move {
+        match load {
+            Load::Window(depth) => {
+                let outstanding = Arc::new(Semaphore::new(depth));
+                while Instant::now() < deadline {
+                    // released by the reader, once the reply is in
+                    let slot = outstanding.clone().acquire_owned().await?;
+                    let message = next();
+                    if !send(&requests, &tx, Instant::now(), message, Some(slot)) {
+                        break;
+                    }
+                }
+            }
+            Load::Rate(rate) => {
+                for scheduled in Arrivals::new(rate)?.map(Instant::from_std) {
+                    if scheduled >= deadline {
+                        break;
+                    }
+                    let message = next();
+                    tokio::time::sleep_until(scheduled).await;
+                    if !send(&requests, &tx, scheduled, message, None) {
+                        break;
+                    }
+                }
+            }
+        }
+        // ends the stream, once the replies are in
+        requests.close_channel();
+        Ok::<(), anyhow::Error>(())
+    };
+    let reader = read_replies(&mut replies, rx, &mut recorder);
+
+    tokio::try_join!(writer, reader)?;
+    recorder.finish();
+
+    Ok(())
+}
+
+/// Send `message` on the stream, and announce it to the reader; false once either is gone.
+fn send(
+    requests: &UnboundedSender<EchoRequest>,
+    sent: &mpsc::UnboundedSender<(Instant, Message, Option<OwnedSemaphorePermit>)>,
+    start: Instant,
+    message: Message,
+    slot: Option<OwnedSemaphorePermit>,
+) -> bool {
+    // the reader has failed, and will report why
+    requests.unbounded_send(request(message)).is_ok() && sent.send((start, message, slot)).is_ok()
+}
+
+/// Match replies to the messages announced on `sent`, in FIFO order, check them, and record
+/// their latency from the instant each was sent (or scheduled). `T` is dropped once its reply
+/// is in.
+async fn read_replies<T>(
+    replies: &mut Streaming<EchoReply>,
+    mut sent: mpsc::UnboundedReceiver<(Instant, Message, T)>,
+    recorder: &mut Recorder<'_>,
+) -> anyhow::Result<()> {
+    while let Some((start, message, _)) = sent.recv().await {
+        let reply = replies
+            .message()
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("stream closed before the reply was in"))?;
+        check_reply(message, &reply.msg)?;
+        recorder.record(start.elapsed(), message.size());
+    }
+    Ok(())
+}
+
+/// `ClientStreamEcho`: every call streams `batch_size` messages, and each is timed from when
+/// it went out to the reply with the whole batch.
+pub async fn client_streaming(
+    mut client: EchoerClient<Channel>,
+    mut recorder: Recorder<'_>,
+    batch_size: usize,
+) -> anyhow::Result<()> {
+    let mut sizes = recorder.sizes()?;
+    let mut payload = recorder.payload();
+    while recorder.running() {
+        let messages = (&mut sizes)
+            .take(batch_size)
+            .map(|size| payload.next(size))
+            .collect::<Vec<_>>();
+        // generated up front, so that only the echo is timed
+        let requests = messages.iter().map(|message| request(*message));
+        let (stamps, sent) = std::sync::mpsc::channel();
+        let outgoing = futures::stream::iter(requests.collect::<Vec<_>>()).inspect(move |_| {
+            let _ = stamps.send(Instant::now());
+        });
+
+        let reply = client.client_stream_echo(outgoing).await?.into_inner();
+        let now = Instant::now();
+        if reply.msgs.len() != messages.len() {
+            return Err(anyhow::anyhow!(
+                "mismatched batch: {} replies, sent {}",
+                reply.msgs.len(),
+                messages.len()
+            ));
+        }
+        for ((message, reply), start) in messages.iter().zip(&reply.msgs).zip(sent.try_iter()) {
+            check_reply(*message, reply)?;
+            recorder.record(now - start, message.size());
+        }
+    }
+    recorder.finish();
+
+    Ok(())
+}
+
+/// `ServerStreamEcho`: every call sends `batch_size` messages at once, and each is timed from
+/// the call to its reply on the stream.
+pub async fn server_streaming(
+    mut client: EchoerClient<Channel>,
+    mut recorder: Recorder<'_>,
+    batch_size: usize,
+) -> anyhow::Result<()> {
+    let mut sizes = recorder.sizes()?;
+    let mut payload = recorder.payload();
+    while recorder.running() {
+        let messages = (&mut sizes)
+            .take(batch_size)
+            .map(|size| payload.next(size))
+            .collect::<Vec<_>>();
+        // generated up front, so that only the echo is timed
+        let batch = EchoBatch {
+            msgs: messages
+                .iter()
+                .map(|message| request(*message).msg)
+                .collect(),
+        };
+
+        let start = Instant::now();
+        let mut replies = client.server_stream_echo(batch).await?.into_inner();
+        for message in &messages {
+            let reply = replies
+                .message()
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("stream closed before the reply was in"))?;
+            check_reply(*message, &reply.msg)?;
+            recorder.record(start.elapsed(), message.size());
+        }
+        if replies.message().await?.is_some() {
+            return Err(anyhow::anyhow!("more replies than messages in the batch"));
+        }
+    }
+    recorder.finish();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use clap::{CommandFactory, FromArgMatches};
+    use echo_common::Report;
+    use futures::stream::BoxStream;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::Server;
+    use tonic::{Request, Response, Status};
+
+    use crate::echo::echoer_server::{Echoer, EchoerServer};
+    use crate::{endpoint, run_streaming, Args};
+
+    use super::*;
+
+    /// Echoes every message, counting them, and drops the last byte of each with `truncate`.
+    #[derive(Clone)]
+    struct TestEchoer {
+        messages: Arc<AtomicU64>,
+        truncate: bool,
+    }
+
+    impl TestEchoer {
+        fn echo(&self, mut msg: Vec<u8>) -> EchoReply {
+            self.messages.fetch_add(1, Ordering::Relaxed);
+            if self.truncate {
+                msg.pop();
+            }
+            EchoReply { msg }
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    #[tonic::async_trait]
+    impl Echoer for TestEchoer {
+        type StreamEchoStream = BoxStream<'static, Result<EchoReply, Status>>;
+        type ServerStreamEchoStream = BoxStream<'static, Result<EchoReply, Status>>;
+
+        async fn echo(&self, request: Request<EchoRequest>) -> Result<Response<EchoReply>, Status> {
+            Ok(Response::new(self.echo(request.into_inner().msg)))
+        }
+
+        async fn stream_echo(
+            &self,
+            request: Request<Streaming<EchoRequest>>,
+        ) -> Result<Response<Self::StreamEchoStream>, Status> {
+            let echoer = self.clone();
+            let replies = request
+                .into_inner()
+                .map(move |request| request.map(|request| echoer.echo(request.msg)));
+            Ok(Response::new(replies.boxed()))
+        }
+
+        async fn client_stream_echo(
+            &self,
+            request: Request<Streaming<EchoRequest>>,
+        ) -> Result<Response<EchoBatch>, Status> {
+            let mut requests = request.into_inner();
+            let mut msgs = Vec::new();
+            while let Some(request) = requests.message().await? {
+                msgs.push(self.echo(request.msg).msg);
+            }
+            Ok(Response::new(EchoBatch { msgs }))
+        }
+
+        async fn server_stream_echo(
+            &self,
+            request: Request<EchoBatch>,
+        ) -> Result<Response<Self::ServerStreamEchoStream>, Status> {
+            let echoer = self.clone();
+            let replies = futures::stream::iter(request.into_inner().msgs)
+                .map(move |msg| Ok(echoer.echo(msg)));
+            Ok(Response::new(replies.boxed()))
+        }
+    }
+
+    /// Run the client with `args` for a second against a server of its own, and return how many
+    /// messages the server echoed.
+    async fn run(args: &[&str], truncate: bool) -> anyhow::Result<u64> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port().to_string();
+        let echoer = TestEchoer {
+            messages: Arc::new(AtomicU64::new(0)),
+            truncate,
+        };
+        let messages = Arc::clone(&echoer.messages);
+        let incoming = TcpIncoming::from_listener(listener, true, None)
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        let server = tokio::spawn(
+            Server::builder()
+                .add_service(EchoerServer::new(echoer))
+                .serve_with_incoming(incoming),
+        );
+
+        let base = [
+            "client",
+            "127.0.0.1",
+            &port,
+            "-d",
+            "1s",
+            "-w",
+            "0s",
+            "-j",
+            "2",
+        ];
+        // as `cli::parse`, with the port given
+        let matches = Args::command()
+            .mut_arg("port", |arg| arg.required(false))
+            .try_get_matches_from(base.iter().chain(args))?;
+        let args = Args::from_arg_matches(&matches)?;
+        let report = Report::new(&args.common)?;
+        let endpoint = endpoint(&args.common)?;
+        let ran = run_streaming(&report, args, &endpoint).await;
+        server.abort();
+        ran.map(|()| messages.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test]
+    async fn round_trips() {
+        for rpc in ["bidi", "client-streaming", "server-streaming"] {
+            let echoed = run(&["-c", "closed", "--rpc", rpc, "--batch-size", "4"], false)
+                .await
+                .unwrap();
+            assert!(echoed > 0, "{}", rpc);
+            if rpc != "bidi" {
+                // whole batches only
+                assert_eq!(echoed % 4, 0, "{}", rpc);
+            }
+        }
+
+        let echoed = run(
+            &["-c", "pipelined", "--pipeline-depth", "4", "--rpc", "bidi"],
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(echoed > 0);
+    }
+
+    #[tokio::test]
+    async fn open_loop_streams_keep_the_rate() {
+        let echoed = run(&["-c", "open", "--rate", "100", "--rpc", "bidi"], false)
+            .await
+            .unwrap();
+        // 100 messages are scheduled within the second
+        assert!((70..=130).contains(&echoed), "{}", echoed);
+    }
+
+    #[tokio::test]
+    async fn replies_are_checked() {
+        for rpc in ["bidi", "client-streaming", "server-streaming"] {
+            let e = run(&["-c", "closed", "--rpc", rpc], true)
+                .await
+                .unwrap_err();
+            assert!(e.to_string().contains("mismatched reply"), "{}: {}", rpc, e);
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_client_types() {
+        let e = run(
+            &["-c", "open", "--rate", "10", "--rpc", "server-streaming"],
+            false,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Open clients are not supported with ServerStreaming RPCs"
+        );
+    }
+}
@@ -0,0 +1,587 @@
+use std::collections::VecDeque;
+use std::net::Shutdown;
+use std::time::Instant;
+
+use anyhow::Context;
+use clap::Parser;
+use echo_common::protocol::{self, FRAME_HEADER_LEN};
+use echo_common::{Arrivals, ClientArgs, ClientType, LoadArgs, Message, Recorder, Report};
+use io_uring::{cqueue, opcode, types, IoUring};
+use rust_uring::{Pool, Socket, UringArgs, BUFFER_GROUP, BUFFER_SIZE, RING_ENTRIES};
+
+/// Buffers of every worker: one to send from with `--registered-buffers`, and the rest to
+/// receive into.
+const POOL_SIZE: u16 = 16;
+
+/// `user_data` of the operations of a worker.
+const SEND: u64 = 0;
+const RECV: u64 = 1;
+const TIMER: u64 = 2;
+
+#[derive(Parser, Clone)]
+#[command(author, version, about, long_about=None)]
+struct Args {
+    #[command(flatten)]
+    common: ClientArgs,
+
+    #[command(flatten)]
+    load: LoadArgs,
+
+    #[command(flatten)]
+    uring: UringArgs,
+}
+
+/// How a worker offers load on its connection.
+#[derive(Clone, Copy)]
+enum Load {
+    /// Keep up to this many messages outstanding (one for a closed loop).
+    Window(usize),
+    /// Send messages on an open-loop schedule, at this rate.
+    Rate(f64),
+}
+
+/// A receive in flight.
+enum Receive {
+    Single(u16),
+    Multi,
+}
+
+/// The replies a worker waits for, in FIFO order, with the instant each message was scheduled,
+/// or first sent (once it is).
+struct Replies {
+    framed: bool,
+    expected: VecDeque<(Option<Instant>, Message)>,
+    header: [u8; FRAME_HEADER_LEN],
+    header_len: usize,
+    /// Bytes of the payload of the first reply received so far.
+    received: usize,
+}
+
+impl Replies {
+    /// Check what was received, and record the latency of every reply it completes.
+    fn feed(&mut self, mut data: &[u8], recorder: &mut Recorder<'_>) -> anyhow::Result<()> {
+        while !data.is_empty() {
+            let Some((start, message)) = self.expected.front_mut() else {
+                return Err(anyhow::anyhow!("received more than the replies"));
+            };
+            if self.framed && self.header_len < FRAME_HEADER_LEN {
+                let n = (FRAME_HEADER_LEN - self.header_len).min(data.len());
+                self.header[self.header_len..self.header_len + n].copy_from_slice(&data[..n]);
+                self.header_len += n;
+                data = &data[n..];
+                if self.header_len == FRAME_HEADER_LEN {
+                    protocol::check_frame(self.header, message.size())?;
+                }
+                continue;
+            }
+            let n = (message.size() - self.received).min(data.len());
+            message.check(&data[..n])?;
+            self.received += n;
+            data = &data[n..];
+            if self.received == message.size() {
+                let start = start.expect("replies come after their request is sent");
+                recorder.record(start.elapsed(), message.size());
+                self.expected.pop_front();
+                self.header_len = 0;
+                self.received = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A request, being sent or waiting to be.
+struct Request {
+    bytes: Vec<u8>,
+    sent: usize,
+}
+
+/// A worker, with its own connection and ring, on which a send and a receive are in flight at
+/// once.
+struct Worker<'a> {
+    // dropped before the buffers the kernel uses
+    ring: IoUring,
+    pool: Pool,
+    socket: Socket,
+    recorder: Recorder<'a>,
+    uring: UringArgs,
+    /// The registered buffer requests are copied into, with `--registered-buffers`.
+    send_buffer: Option<u16>,
+    requests: VecDeque<Request>,
+    sending: bool,
+    replies: Replies,
+    receiving: Option<Receive>,
+    /// The timeout until the next scheduled message, while armed.
+    timer: Option<Box<types::Timespec>>,
+    /// The server closed the connection.
+    closed: bool,
+}
+
+impl<'a> Worker<'a> {
+    /// A worker echoing over `socket`, which is through its handshake.
+    fn new(
+        report: &'a Report,
+        worker: usize,
+        socket: Socket,
+        uring: &UringArgs,
+        framed: bool,
+    ) -> anyhow::Result<Self> {
+        let ring = IoUring::new(RING_ENTRIES).context("failed to set up io_uring")?;
+        let mut pool = Pool::new(POOL_SIZE);
+        let send_buffer = uring
+            .registered_buffers
+            .then(|| pool.take().expect("a new pool has free buffers"));
+        // SAFETY: the worker drops its ring before its pool
+        unsafe {
+            if uring.registered_buffers {
+                pool.register(&ring).context("failed to register buffers")?;
+            }
+            if uring.multishot {
+                pool.provide(&ring).context("failed to provide buffers")?;
+            }
+        }
+
+        Ok(Worker {
+            ring,
+            pool,
+            recorder: report.recorder(worker, socket.id(worker)?),
+            socket,
+            uring: uring.clone(),
+            send_buffer,
+            requests: VecDeque::new(),
+            sending: false,
+            replies: Replies {
+                framed,
+                expected: VecDeque::new(),
+                header: [0; FRAME_HEADER_LEN],
+                header_len: 0,
+                received: 0,
+            },
+            receiving: None,
+            timer: None,
+            closed: false,
+        })
+    }
+
+    fn run(mut self, load: Load) -> anyhow::Result<()> {
+        let mut sizes = self.recorder.sizes()?;
+        let mut payload = self.recorder.payload();
+        let framed = self.replies.framed;
+        let deadline = self.recorder.deadline();
+        let mut arrivals = match load {
+            Load::Rate(rate) => Some(Arrivals::new(rate)?.peekable()),
+            Load::Window(_) => None,
+        };
+        let mut next = move || payload.next(sizes.next().expect("sizes never run out"));
+
+        self.arm_recv()?;
+        let mut finished = false;
+        loop {
+            if !finished {
+                let more = match (load, &mut arrivals) {
+                    (Load::Window(depth), _) => {
+                        // refill the whole window, queued behind the send in flight
+                        while self.replies.expected.len() < depth && self.recorder.running() {
+                            let message = next();
+                            // generated up front, so that only the echo is timed
+                            let bytes = protocol::encode(message, framed);
+                            self.request(None, message, bytes);
+                        }
+                        self.recorder.running()
+                    }
+                    (Load::Rate(_), Some(arrivals)) => {
+                        let now = Instant::now();
+                        while let Some(scheduled) = arrivals.next_if(|s| *s <= now && *s < deadline)
+                        {
+                            let message = next();
+                            let bytes = protocol::encode(message, framed);
+                            self.request(Some(scheduled), message, bytes);
+                        }
+                        let scheduled = *arrivals.peek().expect("arrivals never run out");
+                        if scheduled < deadline && self.timer.is_none() {
+                            self.arm_timer(scheduled - now)?;
+                        }
+                        scheduled < deadline
+                    }
+                    (Load::Rate(_), None) => unreachable!("open loops have arrivals"),
+                };
+                self.send_next()?;
+
+                if !more && self.requests.is_empty() && self.replies.expected.is_empty() {
+                    finished = true;
+                    // the server closes its side in turn, which ends the receive
+                    self.socket
+                        .shutdown(Shutdown::Write)
+                        .context("failed to shut down")?;
+                }
+            }
+            if finished && self.receiving.is_none() && self.timer.is_none() && !self.sending {
+                break;
+            }
+
+            rust_uring::submit_and_wait(&mut self.ring)?;
+            let completions = self
+                .ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result(), cqe.flags()))
+                .collect::<Vec<_>>();
+            for (user_data, result, flags) in completions {
+                match user_data {
+                    SEND => self.on_send(result)?,
+                    RECV => self.on_recv(result, flags)?,
+                    TIMER => self.timer = None,
+                    _ => unreachable!("no other operations"),
+                }
+            }
+        }
+        self.recorder.finish();
+
+        Ok(())
+    }
+
+    /// Queue a request for `message`, and expect its reply, timed from when it was
+    /// `scheduled`, or else from its first send.
+    fn request(&mut self, scheduled: Option<Instant>, message: Message, bytes: Vec<u8>) {
+        self.replies.expected.push_back((scheduled, message));
+        self.requests.push_back(Request { bytes, sent: 0 });
+    }
+
+    fn arm_timer(&mut self, timeout: std::time::Duration) -> anyhow::Result<()> {
+        let timer = self.timer.insert(Box::new(timeout.into()));
+        let entry = opcode::Timeout::new(&**timer).build().user_data(TIMER);
+        // SAFETY: the timespec lives until the timeout completes
+        unsafe { rust_uring::push(&mut self.ring, &entry)? };
+        Ok(())
+    }
+
+    fn send_next(&mut self) -> anyhow::Result<()> {
+        if self.sending {
+            return Ok(());
+        }
+        // the requests not sent in full are the last replies expected
+        let index = self.replies.expected.len() - self.requests.len();
+        let Some(request) = self.requests.front() else {
+            return Ok(());
+        };
+        if request.sent == 0 {
+            // as the other clients, which take it just before writing
+            let (start, _) = &mut self.replies.expected[index];
+            start.get_or_insert_with(Instant::now);
+        }
+        let fd = types::Fd(self.socket.fd());
+        let rest = &request.bytes[request.sent..];
+        let entry = match self.send_buffer {
+            // copied into the registered buffer, a buffer at a time
+            Some(id) => {
+                let len = rest.len().min(BUFFER_SIZE);
+                // SAFETY: only sends use the buffer, one at a time
+                unsafe { self.pool.slice_mut(id)[..len].copy_from_slice(&rest[..len]) };
+                opcode::WriteFixed::new(fd, self.pool.ptr(id), len as u32, id).build()
+            }
+            None => opcode::Send::new(fd, rest.as_ptr(), rest.len() as u32).build(),
+        };
+        self.sending = true;
+        // SAFETY: the request stays in the queue until the send completes
+        unsafe { rust_uring::push(&mut self.ring, &entry.user_data(SEND))? };
+        Ok(())
+    }
+
+    fn on_send(&mut self, result: i32) -> anyhow::Result<()> {
+        self.sending = false;
+        let n = rust_uring::check(result).context("failed to send")?;
+        let request = self
+            .requests
+            .front_mut()
+            .expect("sends are of the first request");
+        request.sent += n;
+        if request.sent == request.bytes.len() {
+            self.requests.pop_front();
+        }
+        Ok(())
+    }
+
+    fn arm_recv(&mut self) -> anyhow::Result<()> {
+        let fd = types::Fd(self.socket.fd());
+        let (entry, receive) = if self.uring.multishot {
+            (
+                opcode::RecvMulti::new(fd, BUFFER_GROUP).build(),
+                Receive::Multi,
+            )
+        } else {
+            let id = self.pool.take().expect("a buffer for every receive");
+            let ptr = self.pool.ptr(id);
+            let len = BUFFER_SIZE as u32;
+            let entry = if self.pool.registered() {
+                opcode::ReadFixed::new(fd, ptr, len, id).build()
+            } else {
+                opcode::Recv::new(fd, ptr, len).build()
+            };
+            (entry, Receive::Single(id))
+        };
+        self.receiving = Some(receive);
+        // SAFETY: the buffer is not given back until the receive completes
+        unsafe { rust_uring::push(&mut self.ring, &entry.user_data(RECV))? };
+        Ok(())
+    }
+
+    fn on_recv(&mut self, result: i32, flags: u32) -> anyhow::Result<()> {
+        let id = match self.receiving.take() {
+            Some(Receive::Single(id)) => Some(id),
+            Some(Receive::Multi) => {
+                if cqueue::more(flags) {
+                    self.receiving = Some(Receive::Multi);
+                }
+                cqueue::buffer_select(flags)
+            }
+            None => None,
+        };
+        let outcome = match rust_uring::check(result) {
+            // the buffers that ran out are given back by now, for the receive armed below
+            Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => Ok(()),
+            Err(e) => Err(anyhow::Error::new(e).context("failed to read")),
+            Ok(0) => {
+                self.closed = true;
+                if self.replies.expected.is_empty() {
+                    Ok(())
+                } else {
+                    Err(anyhow::anyhow!("connection closed before the reply was in"))
+                }
+            }
+            Ok(n) => {
+                let id = id.expect("receives fill a buffer");
+                // SAFETY: the receive is over
+                let data = unsafe { self.pool.slice(id, n) };
+                self.replies.feed(data, &mut self.recorder)
+            }
+        };
+        if let Some(id) = id {
+            self.pool.give_back(id);
+        }
+        outcome?;
+        if self.receiving.is_none() && !self.closed {
+            self.arm_recv()?;
+        }
+        Ok(())
+    }
+}
+
+fn uring_client(
+    report: &Report,
+    args: &ClientArgs,
+    uring: &UringArgs,
+    worker: usize,
+    load: Load,
+) -> anyhow::Result<()> {
+    let endpoint = args.endpoint();
+    let mut socket =
+        Socket::connect(&endpoint).with_context(|| format!("failed to connect to {}", endpoint))?;
+    args.socket.apply(&socket)?;
+    tracing::info!("connected @ {}", endpoint);
+
+    // blocking, bounded by a read timeout
+    socket.set_read_timeout(Some(protocol::HANDSHAKE_TIMEOUT))?;
+    let hello = args.hello();
+    let granted = protocol::connect(&mut socket, &hello)
+        .with_context(|| format!("handshake with {} failed", endpoint))?;
+    protocol::require(&hello, granted)?;
+    socket.set_read_timeout(None)?;
+
+    Worker::new(report, worker, socket, uring, hello.framed_messages())?.run(load)
+}
+
+fn run(report: &Report, args: &Args) -> anyhow::Result<()> {
+    let parallelism = args.common.parallelism();
+    let load = match args.load.client_type {
+        ClientType::Closed => Load::Window(1),
+        ClientType::Pipelined => {
+            let depth = args
+                .load
+                .pipeline_depth
+                .ok_or_else(|| anyhow::anyhow!("pipelined clients need a --pipeline-depth"))?;
+            report.pipeline(depth as usize);
+            Load::Window(depth as usize)
+        }
+        ClientType::Open => {
+            let rate = args
+                .load
+                .rate
+                .ok_or_else(|| anyhow::anyhow!("open-loop clients need a --rate"))?;
+            report.rate(rate);
+            Load::Rate(rate / parallelism as f64)
+        }
+        client_type => {
+            return Err(anyhow::anyhow!(
+                "{:?} clients are not supported over io_uring",
+                client_type
+            ))
+        }
+    };
+
+    std::thread::scope(|s| {
+        let runners = (0..parallelism)
+            .map(|worker| {
+                s.spawn(move || uring_client(report, &args.common, &args.uring, worker, load))
+            })
+            .collect::<Vec<_>>();
+
+        runners
+            .into_iter()
+            .filter_map(|x| match x.join() {
+                Ok(ok) => Some(ok),
+                Err(e) => {
+                    tracing::warn!("join error: {:?}", e);
+                    None
+                }
+            })
+            .collect::<anyhow::Result<Vec<()>>>()?;
+        Ok(())
+    })
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_writer(std::io::stderr)
+        .init();
+    let args: Args = echo_common::cli::parse("9097");
+    args.common.tls.reject("over io_uring")?;
+    args.load.check(&args.common)?;
+    let report = Report::new(&args.common)?;
+    tracing::info!("io_uring with {}", args.uring.describe());
+
+    report.header();
+    echo_common::wait_for_start(args.common.start);
+    run(&report, &args)?;
+
+    report.summary()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    use clap::{CommandFactory, FromArgMatches};
+
+    /// A report without warmup, with the client options `args`.
+    fn report(args: &[&str]) -> Report {
+        let args = [
+            "client",
+            "127.0.0.1",
+            "9097",
+            "-c",
+            "closed",
+            "--warmup",
+            "0s",
+        ]
+        .iter()
+        .chain(args);
+        // as `cli::parse`
+        let matches = Args::command()
+            .mut_arg("port", |arg| arg.required(false))
+            .try_get_matches_from(args)
+            .unwrap();
+        Report::new(&Args::from_arg_matches(&matches).unwrap().common).unwrap()
+    }
+
+    fn uring(both: bool) -> UringArgs {
+        UringArgs {
+            registered_buffers: both,
+            multishot: both,
+        }
+    }
+
+    #[test]
+    fn requests_are_timed_from_their_first_send() {
+        let report = report(&[]);
+        let (client, _server) = UnixStream::pair().unwrap();
+        let mut worker =
+            Worker::new(&report, 0, Socket::Unix(client), &uring(false), false).unwrap();
+        let mut payload = worker.recorder.payload();
+        let scheduled = Instant::now();
+        for start in [None, None, Some(scheduled)] {
+            let message = payload.next(1);
+            worker.request(start, message, protocol::encode(message, false));
+        }
+
+        let before = Instant::now();
+        worker.send_next().unwrap();
+        let starts = worker
+            .replies
+            .expected
+            .iter()
+            .map(|(start, _)| *start)
+            .collect::<Vec<_>>();
+        assert!(starts[0].is_some_and(|start| start >= before));
+        // queued behind the send in flight
+        assert_eq!(starts[1], None);
+        assert_eq!(starts[2], Some(scheduled));
+    }
+
+    #[test]
+    fn replies_are_checked_across_receives() {
+        let report = report(&[]);
+        let mut recorder = report.recorder(0, "test");
+        let mut replies = Replies {
+            framed: true,
+            expected: VecDeque::new(),
+            header: [0; FRAME_HEADER_LEN],
+            header_len: 0,
+            received: 0,
+        };
+        let mut payload = recorder.payload();
+        let messages = [payload.next(3), payload.next(2)];
+        let mut bytes = Vec::new();
+        for message in messages {
+            replies.expected.push_back((Some(Instant::now()), message));
+            bytes.extend(protocol::encode(message, true));
+        }
+
+        // split in the middle of the first header, and of the second payload
+        replies.feed(&bytes[..2], &mut recorder).unwrap();
+        replies.feed(&bytes[2..10], &mut recorder).unwrap();
+        assert_eq!(replies.expected.len(), 1);
+        replies.feed(&bytes[10..], &mut recorder).unwrap();
+        assert!(replies.expected.is_empty());
+
+        let e = replies.feed(b"x", &mut recorder).unwrap_err().to_string();
+        assert_eq!(e, "received more than the replies");
+    }
+
+    /// Echo what comes in on `stream` back, but only once `batch` bytes are in (or the client
+    /// is done): it never answers a client that keeps fewer bytes outstanding.
+    fn echo_in_batches(mut stream: UnixStream, batch: usize) -> std::io::Result<usize> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut echoed = 0;
+        loop {
+            let mut buffer = vec![0; batch];
+            let mut n = 0;
+            while n < batch {
+                match stream.read(&mut buffer[n..])? {
+                    0 => break,
+                    read => n += read,
+                }
+            }
+            if n == 0 {
+                return Ok(echoed);
+            }
+            stream.write_all(&buffer[..n])?;
+            echoed += n;
+        }
+    }
+
+    #[test]
+    fn pipelined_windows_are_refilled() {
+        for both in [false, true] {
+            let report = report(&["--duration", "1s", "-m", "8"]);
+            let (client, server) = UnixStream::pair().unwrap();
+            let server = std::thread::spawn(move || echo_in_batches(server, 4 * 8));
+            let worker = Worker::new(&report, 0, Socket::Unix(client), &uring(both), false);
+            worker.unwrap().run(Load::Window(4)).unwrap();
+            let echoed = server.join().unwrap().unwrap();
+            assert!(echoed > 4 * 8, "{}", echoed);
+        }
+    }
+}
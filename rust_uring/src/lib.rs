@@ -0,0 +1,334 @@
+//! io_uring plumbing shared by the server and the client: the flags that pick how the ring is
+//! used, and the pool of buffers the kernel receives into and sends from.
+
+use std::alloc::{self, Layout};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+
+use echo_common::Endpoint;
+
+use io_uring::types::BufRingEntry;
+use io_uring::{squeue, IoUring};
+
+/// Size of every buffer of a pool: the most a single receive returns.
+pub const BUFFER_SIZE: usize = 1 << 16;
+
+/// Entries of the submission queue of every ring.
+pub const RING_ENTRIES: u32 = 1024;
+
+/// Buffer group of the provided buffers, for multishot receives.
+pub const BUFFER_GROUP: u16 = 0;
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct UringArgs {
+    /// Receive into and send from buffers registered with the ring (`READ_FIXED` and
+    /// `WRITE_FIXED`), which the kernel maps once rather than on every operation.
+    #[arg(long)]
+    pub registered_buffers: bool,
+
+    /// Arm accepts and receives once for many completions (multishot), receiving into buffers
+    /// provided to the kernel up front.
+    #[arg(long)]
+    pub multishot: bool,
+}
+
+impl UringArgs {
+    /// What the flags turn on, for the logs.
+    pub fn describe(&self) -> String {
+        format!(
+            "registered buffers: {}, multishot: {}",
+            self.registered_buffers, self.multishot
+        )
+    }
+}
+
+/// A connected socket: TCP, or a Unix domain socket.
+///
+/// Blocking reads and writes are for the handshake; the echoes go through the ring.
+pub enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Socket {
+    pub fn connect(endpoint: &Endpoint) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::Tcp(addr) => TcpStream::connect(addr).map(Socket::Tcp),
+            Endpoint::Unix(path) => UnixStream::connect(path).map(Socket::Unix),
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        match self {
+            Socket::Tcp(stream) => stream.as_raw_fd(),
+            Socket::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.shutdown(how),
+            Socket::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.set_read_timeout(timeout),
+            Socket::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    /// The client, as seen by a server.
+    pub fn peer(&self) -> String {
+        match self {
+            Socket::Tcp(stream) => stream.peer_addr().map(|a| a.to_string()),
+            // clients are unnamed
+            Socket::Unix(stream) => stream.peer_addr().map(|a| format!("{:?}", a)),
+        }
+        .unwrap_or_default()
+    }
+
+    /// Identifies the connection of `worker` in the output of a client.
+    pub fn id(&self, worker: usize) -> io::Result<String> {
+        match self {
+            Socket::Tcp(stream) => Ok(stream.local_addr()?.to_string()),
+            // clients are unnamed: connections are identified by worker
+            Socket::Unix(stream) => {
+                let path = stream.peer_addr()?;
+                let path = path.as_pathname().unwrap_or(std::path::Path::new(""));
+                Ok(format!("{}#{}", path.display(), worker))
+            }
+        }
+    }
+}
+
+impl AsFd for Socket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Socket::Tcp(stream) => stream.as_fd(),
+            Socket::Unix(stream) => stream.as_fd(),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.read(buf),
+            Socket::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.write(buf),
+            Socket::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Queue `entry`, first submitting what is queued if the submission queue is full.
+///
+/// # Safety
+///
+/// Whatever the entry points to must stay valid until its completion.
+pub unsafe fn push(ring: &mut IoUring, entry: &squeue::Entry) -> io::Result<()> {
+    while ring.submission().push(entry).is_err() {
+        ring.submit()?;
+    }
+    Ok(())
+}
+
+/// Submit what is queued, and wait for at least one completion.
+///
+/// A signal, or completions that overflowed the completion queue, end the wait early.
+pub fn submit_and_wait(ring: &mut IoUring) -> io::Result<()> {
+    match ring.submit_and_wait(1) {
+        Err(e) if e.kind() == ErrorKind::Interrupted || e.raw_os_error() == Some(libc::EBUSY) => {
+            Ok(())
+        }
+        result => result.map(drop),
+    }
+}
+
+/// The error of a failed operation, from the result of its completion.
+pub fn check(result: i32) -> io::Result<usize> {
+    if result < 0 {
+        return Err(io::Error::from_raw_os_error(-result));
+    }
+    Ok(result as usize)
+}
+
+/// Buffers of `BUFFER_SIZE` bytes, in a single allocation, identified by their index.
+///
+/// A buffer is either free, in use by its owner, or, once the pool is provided to a ring, in
+/// the hands of the kernel until a receive returns it.
+pub struct Pool {
+    memory: NonNull<u8>,
+    count: u16,
+    free: Vec<u16>,
+    provided: Option<BufRing>,
+    registered: bool,
+}
+
+/// A ring of provided buffers, which the kernel picks from and we return buffers to.
+struct BufRing {
+    entries: NonNull<BufRingEntry>,
+    layout: Layout,
+    mask: u16,
+    tail: u16,
+}
+
+impl Pool {
+    pub fn new(count: u16) -> Self {
+        let memory = Box::<[u8]>::into_raw(vec![0; count as usize * BUFFER_SIZE].into());
+        Pool {
+            memory: NonNull::new(memory.cast()).expect("boxes are never null"),
+            count,
+            free: (0..count).rev().collect(),
+            provided: None,
+            registered: false,
+        }
+    }
+
+    /// Register every buffer with `ring`, as the fixed buffer of the same index.
+    ///
+    /// # Safety
+    ///
+    /// The pool must outlive the ring.
+    pub unsafe fn register(&mut self, ring: &IoUring) -> io::Result<()> {
+        let iovecs = (0..self.count)
+            .map(|id| libc::iovec {
+                iov_base: self.ptr(id).cast(),
+                iov_len: BUFFER_SIZE,
+            })
+            .collect::<Vec<_>>();
+        ring.submitter().register_buffers(&iovecs)?;
+        self.registered = true;
+        Ok(())
+    }
+
+    /// Provide the free buffers to `ring`, as `BUFFER_GROUP`; from then on, buffers given back
+    /// go back to the kernel.
+    ///
+    /// # Safety
+    ///
+    /// The pool must outlive the ring.
+    pub unsafe fn provide(&mut self, ring: &IoUring) -> io::Result<()> {
+        let entries = self.count.next_power_of_two();
+        let layout = Layout::from_size_align(
+            entries as usize * std::mem::size_of::<BufRingEntry>(),
+            // the kernel maps the ring by pages
+            4096,
+        )
+        .map_err(io::Error::other)?;
+        let entries_ptr = NonNull::new(alloc::alloc_zeroed(layout).cast::<BufRingEntry>())
+            .ok_or(ErrorKind::OutOfMemory)?;
+        if let Err(e) = ring.submitter().register_buf_ring_with_flags(
+            entries_ptr.as_ptr() as u64,
+            entries,
+            BUFFER_GROUP,
+            0,
+        ) {
+            alloc::dealloc(entries_ptr.as_ptr().cast(), layout);
+            return Err(e);
+        }
+        self.provided = Some(BufRing {
+            entries: entries_ptr,
+            layout,
+            mask: entries - 1,
+            tail: 0,
+        });
+        for id in std::mem::take(&mut self.free) {
+            self.give_back(id);
+        }
+        Ok(())
+    }
+
+    pub fn registered(&self) -> bool {
+        self.registered
+    }
+
+    /// A free buffer, unless they are all in use (or provided).
+    pub fn take(&mut self) -> Option<u16> {
+        self.free.pop()
+    }
+
+    /// Return a buffer, once done with it: to the kernel, if the pool is provided.
+    pub fn give_back(&mut self, id: u16) {
+        let ptr = self.ptr(id);
+        let Some(ring) = &mut self.provided else {
+            self.free.push(id);
+            return;
+        };
+        // SAFETY: the index is masked to the ring, which outlives the pool's use of it, and
+        // the kernel only reads entries up to the tail, which is published last.
+        unsafe {
+            let entry = &mut *ring.entries.as_ptr().add((ring.tail & ring.mask) as usize);
+            entry.set_addr(ptr as u64);
+            entry.set_len(BUFFER_SIZE as u32);
+            entry.set_bid(id);
+            ring.tail = ring.tail.wrapping_add(1);
+            let tail = BufRingEntry::tail(ring.entries.as_ptr()).cast::<AtomicU16>();
+            (*tail).store(ring.tail, Ordering::Release);
+        }
+    }
+
+    /// Start of buffer `id`, for the kernel to receive into or send from.
+    pub fn ptr(&self, id: u16) -> *mut u8 {
+        assert!(
+            id < self.count,
+            "no buffer {} in a pool of {}",
+            id,
+            self.count
+        );
+        // SAFETY: within the allocation
+        unsafe { self.memory.as_ptr().add(id as usize * BUFFER_SIZE) }
+    }
+
+    /// The first `len` bytes of buffer `id`.
+    ///
+    /// # Safety
+    ///
+    /// The kernel must not be writing to the buffer.
+    pub unsafe fn slice(&self, id: u16, len: usize) -> &[u8] {
+        std::slice::from_raw_parts(self.ptr(id), len.min(BUFFER_SIZE))
+    }
+
+    /// Buffer `id`, to fill before sending it.
+    ///
+    /// # Safety
+    ///
+    /// The kernel must not be using the buffer.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn slice_mut(&self, id: u16) -> &mut [u8] {
+        std::slice::from_raw_parts_mut(self.ptr(id), BUFFER_SIZE)
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `new` and `provide`, with the same sizes
+        unsafe {
+            if let Some(ring) = &self.provided {
+                alloc::dealloc(ring.entries.as_ptr().cast(), ring.layout);
+            }
+            drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+                self.memory.as_ptr(),
+                self.count as usize * BUFFER_SIZE,
+            )));
+        }
+    }
+}
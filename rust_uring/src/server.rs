@@ -0,0 +1,738 @@
+use clap::Parser;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use anyhow::Context;
+use echo_common::endpoint::{self, Endpoint};
+use echo_common::metrics::Connection;
+use echo_common::protocol::{self, Hello, FRAME_HEADER_LEN, HELLO_LEN};
+use echo_common::shutdown::{self, Signal};
+use echo_common::{ServerArgs, ServerStats, SocketArgs};
+use io_uring::{cqueue, opcode, types, IoUring};
+use rust_uring::{Pool, Socket, UringArgs, BUFFER_GROUP, BUFFER_SIZE, RING_ENTRIES};
+use slab::Slab;
+
+/// Buffers of every ring.
+const POOL_SIZE: u16 = 256;
+
+/// `user_data` of the operations that are not on a connection.
+const ACCEPT: u64 = u64::MAX;
+const WAKE: u64 = u64::MAX - 1;
+const CANCEL: u64 = u64::MAX - 2;
+
+/// Operations on a connection, whose `user_data` is its key and the operation.
+const RECV: u64 = 0;
+const SEND: u64 = 1;
+
+fn token(key: usize, op: u64) -> u64 {
+    (key as u64) << 1 | op
+}
+
+#[derive(Parser)]
+#[command(author, version, about, long_about=None)]
+struct Args {
+    #[command(flatten)]
+    common: ServerArgs,
+
+    #[command(flatten)]
+    uring: UringArgs,
+}
+
+/// Where received bytes are: in a buffer of the pool, or on the heap once the pool runs out.
+enum Buffer {
+    Pooled(u16),
+    Heap(Box<[u8]>),
+}
+
+/// A receive in flight.
+enum Receive {
+    Single(Buffer),
+    Multi,
+}
+
+/// Bytes to echo (or the reply to the hello), in the buffer they were received in.
+struct Chunk {
+    buffer: Buffer,
+    start: usize,
+    end: usize,
+    /// Messages that end in the chunk.
+    messages: usize,
+    /// Payload bytes in the chunk, counted as echoed once it is sent (none in the reply to the
+    /// hello, or in frame headers).
+    payload: usize,
+}
+
+enum Phase {
+    /// Reading the hello, whose bytes so far are kept.
+    Hello(Vec<u8>),
+    Echo(Messages),
+    Rejected,
+}
+
+impl Phase {
+    /// Whether the client is between two messages (or has not started its hello).
+    fn idle(&self) -> bool {
+        match self {
+            Phase::Hello(hello) => hello.is_empty(),
+            Phase::Echo(messages) => messages.between(),
+            Phase::Rejected => true,
+        }
+    }
+}
+
+/// Splits what a client sends into messages, to count them and to stop between two.
+struct Messages {
+    framed: bool,
+    max_size: usize,
+    header: [u8; FRAME_HEADER_LEN],
+    header_len: usize,
+    /// Bytes of payload left in the current message.
+    left: usize,
+    started: bool,
+}
+
+impl Messages {
+    fn new(hello: &Hello) -> Self {
+        Messages {
+            framed: hello.framed_messages(),
+            max_size: hello.message_size,
+            header: [0; FRAME_HEADER_LEN],
+            header_len: 0,
+            left: 0,
+            started: false,
+        }
+    }
+
+    fn between(&self) -> bool {
+        !self.started
+    }
+
+    /// Go through `data`: returns how much of it to echo (all of it, unless `stopping` ends
+    /// it after a message), how much of that is payload, and how many messages that ends.
+    fn feed(&mut self, data: &[u8], stopping: bool) -> anyhow::Result<(usize, usize, usize)> {
+        let mut pos = 0;
+        let mut payload = 0;
+        let mut ended = 0;
+        while pos < data.len() {
+            if !self.started {
+                if stopping {
+                    break;
+                }
+                self.started = true;
+                self.header_len = 0;
+                self.left = if self.framed { 0 } else { self.max_size };
+            }
+            if self.framed && self.header_len < FRAME_HEADER_LEN {
+                let n = (FRAME_HEADER_LEN - self.header_len).min(data.len() - pos);
+                self.header[self.header_len..self.header_len + n]
+                    .copy_from_slice(&data[pos..pos + n]);
+                self.header_len += n;
+                pos += n;
+                if self.header_len == FRAME_HEADER_LEN {
+                    self.left = protocol::frame_size(self.header, self.max_size)?;
+                }
+                continue;
+            }
+            let n = self.left.min(data.len() - pos);
+            pos += n;
+            payload += n;
+            self.left -= n;
+            if self.left == 0 {
+                self.started = false;
+                ended += 1;
+            }
+        }
+        Ok((pos, payload, ended))
+    }
+}
+
+/// A connection, echoing what it receives while it receives more.
+///
+/// As in the other servers, a connection holds up to a message (and a buffer) of received
+/// bytes that are not echoed yet, so a client that only reads its reply after writing the full
+/// message never deadlocks against a full send buffer.
+struct Conn {
+    socket: Socket,
+    peer: String,
+    phase: Phase,
+    receiving: Option<Receive>,
+    /// The provided buffers ran out: the next receive goes to the heap.
+    starved: bool,
+    sending: bool,
+    queue: VecDeque<Chunk>,
+    /// Bytes in `queue`.
+    queued: usize,
+    /// Most bytes to hold in `queue` before receiving more.
+    limit: usize,
+    /// Nothing more to receive: the client is done, or the server stopped after a message.
+    done: bool,
+    failed: bool,
+    _connection: Connection,
+}
+
+impl Conn {
+    fn new(socket: Socket, peer: String, connection: Connection) -> Self {
+        Conn {
+            socket,
+            peer,
+            phase: Phase::Hello(Vec::with_capacity(HELLO_LEN)),
+            receiving: None,
+            starved: false,
+            sending: false,
+            queue: VecDeque::new(),
+            queued: 0,
+            limit: BUFFER_SIZE,
+            done: false,
+            failed: false,
+            _connection: connection,
+        }
+    }
+
+    fn push(&mut self, chunk: Chunk) {
+        self.queued += chunk.end - chunk.start;
+        self.queue.push_back(chunk);
+    }
+
+    /// Receive no more, ending the receive in flight.
+    fn stop_reading(&mut self) {
+        self.done = true;
+        if self.receiving.is_some() {
+            let _ = self.socket.shutdown(Shutdown::Read);
+        }
+    }
+}
+
+/// Count and log the error that terminates a connection.
+fn report(stats: &ServerStats, peer: &str, error: anyhow::Error) {
+    let error = error.context(format!(
+        "An error occurred, terminating connection with {}",
+        peer
+    ));
+    stats.error(&*error);
+    tracing::warn!("failed to handle connection from {}: {:?}", peer, error);
+}
+
+/// A thread with its own ring, accepting connections from the shared listener and echoing on
+/// them, until woken up to stop.
+struct Worker {
+    // dropped before the buffers the kernel uses
+    ring: IoUring,
+    pool: Pool,
+    conns: Slab<Conn>,
+    listener: RawFd,
+    unix: bool,
+    accepting: bool,
+    wake: File,
+    wake_buf: Box<[u8; 8]>,
+    stopped: bool,
+    stats: Arc<ServerStats>,
+    socket: SocketArgs,
+    uring: UringArgs,
+}
+
+impl Worker {
+    fn new(
+        ring: IoUring,
+        listener: RawFd,
+        unix: bool,
+        wake: File,
+        stats: Arc<ServerStats>,
+        socket: SocketArgs,
+        uring: UringArgs,
+    ) -> io::Result<Self> {
+        let mut pool = Pool::new(POOL_SIZE);
+        // SAFETY: the worker drops its ring before its pool
+        unsafe {
+            if uring.registered_buffers {
+                pool.register(&ring)?;
+            }
+            if uring.multishot {
+                pool.provide(&ring)?;
+            }
+        }
+        Ok(Worker {
+            ring,
+            pool,
+            conns: Slab::new(),
+            listener,
+            unix,
+            accepting: false,
+            wake,
+            wake_buf: Box::new([0; 8]),
+            stopped: false,
+            stats,
+            socket,
+            uring,
+        })
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        self.arm_accept()?;
+        let wake = opcode::Read::new(
+            types::Fd(self.wake.as_raw_fd()),
+            self.wake_buf.as_mut_ptr(),
+            self.wake_buf.len() as u32,
+        )
+        .build()
+        .user_data(WAKE);
+        // SAFETY: the buffer lives as long as the worker
+        unsafe { rust_uring::push(&mut self.ring, &wake)? };
+
+        while !(self.stopped && self.conns.is_empty() && !self.accepting) {
+            rust_uring::submit_and_wait(&mut self.ring)?;
+            let completions = self
+                .ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result(), cqe.flags()))
+                .collect::<Vec<_>>();
+            for (user_data, result, flags) in completions {
+                match user_data {
+                    ACCEPT => self.on_accept(result, flags)?,
+                    WAKE => self.on_wake()?,
+                    CANCEL => {}
+                    token => {
+                        let key = (token >> 1) as usize;
+                        if token & 1 == SEND {
+                            self.on_send(key, result)?;
+                        } else {
+                            self.on_recv(key, result, flags)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn arm_accept(&mut self) -> io::Result<()> {
+        let fd = types::Fd(self.listener);
+        let entry = if self.uring.multishot {
+            opcode::AcceptMulti::new(fd).build()
+        } else {
+            opcode::Accept::new(fd, std::ptr::null_mut(), std::ptr::null_mut()).build()
+        };
+        self.accepting = true;
+        // SAFETY: no buffers involved
+        unsafe { rust_uring::push(&mut self.ring, &entry.user_data(ACCEPT)) }
+    }
+
+    fn on_accept(&mut self, result: i32, flags: u32) -> io::Result<()> {
+        if !cqueue::more(flags) {
+            self.accepting = false;
+        }
+        match rust_uring::check(result) {
+            // accepted before the accept was cancelled: closed right away
+            Ok(_) if self.stopped => {}
+            Ok(fd) => {
+                // SAFETY: a new descriptor, which nothing else owns
+                let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+                match self.socket.apply(&fd) {
+                    Ok(()) => self.accepted(fd)?,
+                    Err(e) => {
+                        self.stats.error(&e);
+                        tracing::warn!("failed to set socket options: {}", e);
+                    }
+                }
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ECANCELED) => {}
+            Err(e) => {
+                self.stats.error(&e);
+                tracing::warn!("failed to accept connection: {}", e);
+            }
+        }
+        if !self.accepting && !self.stopped {
+            self.arm_accept()?;
+        }
+        Ok(())
+    }
+
+    /// Start receiving from a new connection.
+    fn accepted(&mut self, fd: OwnedFd) -> io::Result<()> {
+        let socket = if self.unix {
+            Socket::Unix(fd.into())
+        } else {
+            Socket::Tcp(fd.into())
+        };
+        let peer = socket.peer();
+        tracing::info!("accepted new connection: {}", peer);
+        let key = self
+            .conns
+            .insert(Conn::new(socket, peer, self.stats.connection()));
+        self.arm_recv(key)
+    }
+
+    /// Stop accepting, and stop every connection after its current message.
+    fn on_wake(&mut self) -> io::Result<()> {
+        self.stopped = true;
+        if self.accepting {
+            let cancel = opcode::AsyncCancel::new(ACCEPT).build().user_data(CANCEL);
+            // SAFETY: no buffers involved
+            unsafe { rust_uring::push(&mut self.ring, &cancel)? };
+        }
+        let keys = self.conns.iter().map(|(key, _)| key).collect::<Vec<_>>();
+        for key in keys {
+            let conn = &mut self.conns[key];
+            if conn.phase.idle() && !conn.done {
+                conn.stop_reading();
+            }
+            self.close_if_done(key);
+        }
+        Ok(())
+    }
+
+    fn arm_recv(&mut self, key: usize) -> io::Result<()> {
+        let conn = &mut self.conns[key];
+        let fd = types::Fd(conn.socket.fd());
+        let len = BUFFER_SIZE as u32;
+        let (entry, receive) = if self.uring.multishot && !conn.starved {
+            (
+                opcode::RecvMulti::new(fd, BUFFER_GROUP).build(),
+                Receive::Multi,
+            )
+        } else {
+            conn.starved = false;
+            match self.pool.take() {
+                Some(id) if self.pool.registered() => (
+                    opcode::ReadFixed::new(fd, self.pool.ptr(id), len, id).build(),
+                    Receive::Single(Buffer::Pooled(id)),
+                ),
+                Some(id) => (
+                    opcode::Recv::new(fd, self.pool.ptr(id), len).build(),
+                    Receive::Single(Buffer::Pooled(id)),
+                ),
+                // every buffer of the pool is in use
+                None => {
+                    let mut bytes = vec![0; BUFFER_SIZE].into_boxed_slice();
+                    (
+                        opcode::Recv::new(fd, bytes.as_mut_ptr(), len).build(),
+                        Receive::Single(Buffer::Heap(bytes)),
+                    )
+                }
+            }
+        };
+        conn.receiving = Some(receive);
+        // SAFETY: the buffer stays with the connection until the receive completes
+        unsafe { rust_uring::push(&mut self.ring, &entry.user_data(token(key, RECV))) }
+    }
+
+    fn on_recv(&mut self, key: usize, result: i32, flags: u32) -> io::Result<()> {
+        let conn = &mut self.conns[key];
+        let mut buffer = match conn.receiving.take() {
+            Some(Receive::Single(buffer)) => Some(buffer),
+            Some(Receive::Multi) => {
+                if cqueue::more(flags) {
+                    conn.receiving = Some(Receive::Multi);
+                }
+                cqueue::buffer_select(flags).map(Buffer::Pooled)
+            }
+            None => None,
+        };
+
+        let outcome = match rust_uring::check(result) {
+            // the provided buffers ran out, until echoes give some back
+            Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                conn.starved = true;
+                Ok(())
+            }
+            Err(_) if conn.failed => Ok(()),
+            Err(e) => Err(anyhow::Error::new(e).context("failed to read")),
+            // after the end (or a failure), whatever comes is dropped
+            Ok(_) if conn.done => Ok(()),
+            Ok(0) => {
+                conn.done = true;
+                if conn.phase.idle() {
+                    Ok(())
+                } else {
+                    Err(
+                        anyhow::Error::new(io::Error::from(ErrorKind::UnexpectedEof))
+                            .context("failed to read"),
+                    )
+                }
+            }
+            Ok(n) => match buffer.take() {
+                Some(buffer) => return self.received(key, buffer, n),
+                None => Err(anyhow::anyhow!("received {} bytes without a buffer", n)),
+            },
+        };
+        if let Some(Buffer::Pooled(id)) = buffer {
+            self.pool.give_back(id);
+        }
+        if let Err(e) = outcome {
+            self.fail(key, e);
+        }
+        self.progress(key)
+    }
+
+    /// Handle `n` bytes received in `buffer`: the hello, then messages to echo.
+    fn received(&mut self, key: usize, buffer: Buffer, n: usize) -> io::Result<()> {
+        let conn = &mut self.conns[key];
+        let data = match &buffer {
+            // SAFETY: the receive is over
+            Buffer::Pooled(id) => unsafe { self.pool.slice(*id, n) },
+            Buffer::Heap(bytes) => &bytes[..n],
+        };
+
+        let mut offset = 0;
+        if let Phase::Hello(hello) = &mut conn.phase {
+            offset = (HELLO_LEN - hello.len()).min(n);
+            hello.extend_from_slice(&data[..offset]);
+            if hello.len() == HELLO_LEN {
+                let hello = Hello::decode(hello[..].try_into().expect("a whole hello"));
+                let ack = protocol::negotiate(&hello).encode();
+                conn.push(Chunk {
+                    end: ack.len(),
+                    buffer: Buffer::Heap(ack.into()),
+                    start: 0,
+                    messages: 0,
+                    payload: 0,
+                });
+                match hello {
+                    Ok(hello) => {
+                        conn.limit = hello.message_size.saturating_add(BUFFER_SIZE);
+                        conn.phase = Phase::Echo(Messages::new(&hello));
+                    }
+                    Err(reason) => {
+                        // closed once the client is told why
+                        conn.phase = Phase::Rejected;
+                        conn.stop_reading();
+                        let error = anyhow::anyhow!("rejected handshake: {}", reason);
+                        report(&self.stats, &conn.peer, error);
+                    }
+                }
+            }
+        }
+
+        let fed = match &mut conn.phase {
+            Phase::Echo(messages) => {
+                messages
+                    .feed(&data[offset..], self.stopped)
+                    .map(|(len, payload, ended)| {
+                        (len, payload, ended, self.stopped && messages.between())
+                    })
+            }
+            _ => Ok((0, 0, 0, false)),
+        };
+        match fed {
+            Ok((len, payload, ended, stop)) => {
+                if len > 0 {
+                    self.stats.bytes_in(payload);
+                    conn.push(Chunk {
+                        buffer,
+                        start: offset,
+                        end: offset + len,
+                        messages: ended,
+                        payload,
+                    });
+                } else if let Buffer::Pooled(id) = buffer {
+                    self.pool.give_back(id);
+                }
+                if stop {
+                    conn.stop_reading();
+                }
+            }
+            Err(e) => {
+                if let Buffer::Pooled(id) = buffer {
+                    self.pool.give_back(id);
+                }
+                self.fail(key, e);
+            }
+        }
+        self.progress(key)
+    }
+
+    fn send_next(&mut self, key: usize) -> io::Result<()> {
+        let conn = &mut self.conns[key];
+        if conn.sending || conn.failed {
+            return Ok(());
+        }
+        let Some(chunk) = conn.queue.front() else {
+            return Ok(());
+        };
+        let fd = types::Fd(conn.socket.fd());
+        let len = (chunk.end - chunk.start) as u32;
+        let entry = match &chunk.buffer {
+            Buffer::Pooled(id) if self.pool.registered() => {
+                let ptr = self.pool.ptr(*id).wrapping_add(chunk.start);
+                opcode::WriteFixed::new(fd, ptr, len, *id).build()
+            }
+            Buffer::Pooled(id) => {
+                let ptr = self.pool.ptr(*id).wrapping_add(chunk.start);
+                opcode::Send::new(fd, ptr, len).build()
+            }
+            Buffer::Heap(bytes) => {
+                opcode::Send::new(fd, bytes[chunk.start..].as_ptr(), len).build()
+            }
+        };
+        conn.sending = true;
+        // SAFETY: the chunk stays in the queue until the send completes
+        unsafe { rust_uring::push(&mut self.ring, &entry.user_data(token(key, SEND))) }
+    }
+
+    fn on_send(&mut self, key: usize, result: i32) -> io::Result<()> {
+        let conn = &mut self.conns[key];
+        conn.sending = false;
+        let chunk = conn
+            .queue
+            .front_mut()
+            .expect("sends are of the first chunk");
+        match rust_uring::check(result) {
+            Ok(n) if !conn.failed => {
+                chunk.start += n;
+                conn.queued -= n;
+                if chunk.start == chunk.end {
+                    self.stats.bytes_out(chunk.payload);
+                    for _ in 0..chunk.messages {
+                        self.stats.message();
+                    }
+                    if let Some(Buffer::Pooled(id)) = conn.queue.pop_front().map(|c| c.buffer) {
+                        self.pool.give_back(id);
+                    }
+                }
+            }
+            result => {
+                if let Some(Buffer::Pooled(id)) = conn.queue.pop_front().map(|c| c.buffer) {
+                    self.pool.give_back(id);
+                }
+                if let Err(e) = result {
+                    self.fail(key, anyhow::Error::new(e).context("failed to echo"));
+                }
+            }
+        }
+        self.progress(key)
+    }
+
+    /// After a completion on a connection: send what is queued, receive more if there is room
+    /// for it, and close the connection once it is done.
+    fn progress(&mut self, key: usize) -> io::Result<()> {
+        self.send_next(key)?;
+        let conn = &self.conns[key];
+        if conn.receiving.is_none() && !conn.done && conn.queued < conn.limit {
+            self.arm_recv(key)?;
+        }
+        self.close_if_done(key);
+        Ok(())
+    }
+
+    /// Abort a connection: what it has in flight completes with an error.
+    fn fail(&mut self, key: usize, error: anyhow::Error) {
+        let conn = &mut self.conns[key];
+        if conn.failed {
+            return;
+        }
+        conn.failed = true;
+        conn.done = true;
+        report(&self.stats, &conn.peer, error);
+        let _ = conn.socket.shutdown(Shutdown::Both);
+        // the chunk being sent goes once its send completes
+        let keep = usize::from(conn.sending);
+        for chunk in conn.queue.drain(keep..) {
+            if let Buffer::Pooled(id) = chunk.buffer {
+                self.pool.give_back(id);
+            }
+        }
+    }
+
+    /// Close a connection once it has nothing more to receive, nothing left to echo, and
+    /// nothing in flight.
+    fn close_if_done(&mut self, key: usize) {
+        let conn = &self.conns[key];
+        if !conn.done || conn.sending || conn.receiving.is_some() || !conn.queue.is_empty() {
+            return;
+        }
+        let conn = self.conns.remove(key);
+        if !conn.failed {
+            if let Err(e) = conn.socket.shutdown(Shutdown::Write) {
+                let error = anyhow::Error::new(e).context("failed to shut down");
+                report(&self.stats, &conn.peer, error);
+            }
+        }
+    }
+}
+
+fn eventfd() -> io::Result<File> {
+    // SAFETY: no pointers involved
+    let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: a new descriptor, which nothing else owns
+    Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args: Args = echo_common::cli::parse("9097");
+    let uring = args.uring;
+    let args = args.common;
+    args.tls.reject("over io_uring")?;
+
+    let stats = ServerStats::new();
+    if let Some(admin_addr) = args.admin_addr() {
+        stats.serve_admin(&admin_addr)?;
+        tracing::info!("serving stats on {}", admin_addr);
+    }
+    let signal = Signal::new().context("failed to handle signals")?;
+
+    let (listener, unix_path) = match args.endpoint() {
+        Endpoint::Tcp(addr) => {
+            let listener = endpoint::tcp_listener(&addr, args.backlog, false)
+                .with_context(|| format!("failed to bind {}", addr))?;
+            tracing::info!("server listening on {}", addr);
+            (OwnedFd::from(listener), None)
+        }
+        Endpoint::Unix(path) => {
+            endpoint::remove_stale_socket(&path)?;
+            let listener = endpoint::unix_listener(&path, args.backlog)
+                .with_context(|| format!("failed to bind {}", path.display()))?;
+            tracing::info!("server listening on {}", path.display());
+            (OwnedFd::from(listener), Some(path))
+        }
+    };
+    stats.listening();
+    tracing::info!("io_uring with {}", uring.describe());
+    tracing::info!("socket options: {}", args.socket.describe());
+
+    // the first worker to fail, or the signal, stops the server
+    let (stop, stopped) = mpsc::channel();
+    let mut wakers = Vec::new();
+    for _ in 0..args.parallelism() {
+        let ring = IoUring::new(RING_ENTRIES).context("failed to set up io_uring")?;
+        let wake = eventfd().context("failed to set up io_uring")?;
+        wakers.push(wake.try_clone()?);
+        let listener = listener.as_raw_fd();
+        let unix = unix_path.is_some();
+        let stats = Arc::clone(&stats);
+        let socket = args.socket.clone();
+        let uring = uring.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let result = Worker::new(ring, listener, unix, wake, stats, socket, uring)
+                .and_then(|mut worker| worker.run());
+            if let Err(e) = result {
+                let _ = stop.send(Err(anyhow::Error::new(e).context("io_uring worker failed")));
+            }
+        });
+    }
+    thread::spawn(move || {
+        let _ = stop.send(signal.wait().context("failed to handle signals"));
+    });
+
+    let result = stopped.recv()?;
+    tracing::info!("shutting down");
+    for mut wake in wakers {
+        let _ = wake.write_all(&1u64.to_ne_bytes());
+    }
+
+    let left = shutdown::drain(&stats, args.drain_timeout);
+    tracing::info!("drained connections, {} left", left);
+    if let Some(path) = unix_path {
+        let _ = std::fs::remove_file(&path);
+    }
+    result?;
+    shutdown::finish(&stats, left)
+}